@@ -3,19 +3,62 @@
 -- Layout:
 --   MessageHeader: kind: u8, payload_len: u64 (big endian)
 --   PayloadHeader: req_id: u64
+--   The first message on a connection is a Hello, answered by HelloAck or HelloReject
+--   Any request may be answered by an Error response
+--   Streamed results are sent as Chunk responses, ended by the request's own response
+--   A type byte with its high bit (0x80) set marks a body LZ4 compressed after the req_id, starting
+--   with its inflated length: u64. Compressed bodies are shown but not decoded
+--   A request type byte with bit 0x40 set carries sent_micros: u64 + deadline_micros: u64 (0 for none)
+--   after its req_id. Connections that negotiated DEADLINES and DROP_RESPONSES may get a Drop response,
+--   body = queued_micros: u64, for a request whose deadline passed before the server executed it
+--   A request type byte with bit 0x20 set carries priority: u8 (0 Low, 1 Normal, 2 High) + tenant: u64
+--   after its req_id and timing
+--   Connections that negotiated CANCEL may send Cancel, with an empty body and the req_id of the request
+--   to abort. It gets no answer of its own: the request it names is answered with Cancelled, also with
+--   an empty body, unless the server answered it already
+--   Connections that negotiated STATS (features bit 0x800) may send Stats, with an empty body, answered
+--   with count: u64 + count * (name len: u64 + name bytes + value: u64) of the server's counters
+--   Connections that negotiated CHECKSUMS (features bit 0x40) end every frame after the handshake
+--   with a CRC32C trailer: u32 that payload_len does not count, so they cannot be dissected either
+--   Connections that negotiated SERVER_TIMING (features bit 0x400) end every response after the handshake
+--   with received_micros: u64 + queued_micros: u64 + exec_micros: u64 + yields: u64, before any checksum
+--   and likewise not counted by payload_len
+--   Only the fixed layout is dissected: connections that negotiated COMPACT (features bit 0x20)
+--   write payload_len, req_id and single keys as LEB128 varints after the handshake
 ------------------------------------------------------------
 
 local SERVER_PORT = 12345  -- set this to your server's TCP port
 
+local HELLO_BYTE        = 1
+local HELLO_REJECT_BYTE = 2
+local ERROR_BYTE        = 3
+local CHUNK_BYTE        = 4
+local DROP_BYTE         = 5
 local BE_BYTE       = 6
 local LC_READ_BYTE  = 7
 local LC_WRITE_BYTE = 8
+local LC_DELETE_BYTE           = 9
+local LC_INSERT_IF_ABSENT_BYTE = 10
+local LC_CAS_BYTE              = 11
+local MULTI_GET_BYTE           = 12
+local MULTI_PUT_BYTE           = 13
+local RANGE_SCAN_BYTE          = 14
+local PREFIX_COUNT_BYTE        = 15
+local REGEX_COUNT_BYTE         = 16
+local GLOB_COUNT_BYTE          = 17
+local CANCEL_BYTE              = 18
+local STATS_BYTE               = 19
 local NONE_BYTE     = 0
 local SOME_BYTE     = 1
+local COMPRESSED_FLAG = 0x80
+local TIMED_FLAG      = 0x40
+local TAGGED_FLAG     = 0x20
 
 local LEN_LENGTH      = 8               -- u64
 local MSG_HDR_LEN     = 1 + LEN_LENGTH  -- kind:1 + payload_len:8
 local PAYLOAD_HDR_LEN = LEN_LENGTH      -- req_id:8
+local TIMING_LEN      = 2 * LEN_LENGTH  -- sent_micros:8 + deadline_micros:8
+local TAGS_LEN        = 1 + LEN_LENGTH  -- priority:1 + tenant:8
 
 ------------------------------------------------------------
 -- Protocol and fields
@@ -24,19 +67,54 @@ local PAYLOAD_HDR_LEN = LEN_LENGTH      -- req_id:8
 local aspenrs = Proto("aspenrs", "AspenRS Protocol")
 
 local type_vals = {
+    [HELLO_BYTE]        = "Hello",
+    [HELLO_REJECT_BYTE] = "HelloReject",
+    [ERROR_BYTE]        = "Error",
+    [CHUNK_BYTE]        = "Chunk",
+    [DROP_BYTE]         = "Drop",
     [BE_BYTE]       = "BeRead",
     [LC_READ_BYTE]  = "LcRead",
     [LC_WRITE_BYTE] = "LcWrite",
+    [LC_DELETE_BYTE]           = "LcDelete",
+    [LC_INSERT_IF_ABSENT_BYTE] = "LcInsertIfAbsent",
+    [LC_CAS_BYTE]              = "LcCompareAndSwap",
+    [MULTI_GET_BYTE]           = "MultiGet",
+    [MULTI_PUT_BYTE]           = "MultiPut",
+    [RANGE_SCAN_BYTE]          = "RangeScan",
+    [PREFIX_COUNT_BYTE]        = "PrefixCount",
+    [REGEX_COUNT_BYTE]         = "RegexCount",
+    [GLOB_COUNT_BYTE]          = "GlobCount",
+    [CANCEL_BYTE]              = "Cancel",
+    [STATS_BYTE]               = "Stats",
 }
 
 local f_type    = ProtoField.uint8("aspenrs.type", "Type", base.DEC, type_vals)
 local f_len     = ProtoField.uint64("aspenrs.payload_len", "Payload Length", base.DEC)
 local f_req_id  = ProtoField.uint64("aspenrs.req_id", "Request ID", base.DEC)
+local f_sent     = ProtoField.uint64("aspenrs.sent_micros", "Sent (µs since epoch)", base.DEC)
+local f_deadline = ProtoField.uint64("aspenrs.deadline_micros", "Deadline (µs since epoch)", base.DEC)
+local f_priority = ProtoField.uint8("aspenrs.priority", "Priority", base.DEC, { [0] = "Low", [1] = "Normal", [2] = "High" })
+local f_tenant   = ProtoField.uint64("aspenrs.tenant", "Tenant", base.DEC)
 
 -- Request fields
 local f_req_key       = ProtoField.uint64("aspenrs.request.id", "Key", base.DEC)
 local f_req_substring = ProtoField.string("aspenrs.request.substring", "Substring")
 local f_req_username  = ProtoField.string("aspenrs.request.username", "Username")
+local f_req_expected_len = ProtoField.uint64("aspenrs.request.expected_len", "Expected Length", base.DEC)
+local f_req_expected     = ProtoField.string("aspenrs.request.expected", "Expected Username")
+local be_results_vals = {
+    [0] = "Count",
+    [1] = "Keys",
+    [2] = "Usernames",
+    [3] = "KeysAndUsernames",
+}
+local f_req_be_results = ProtoField.uint8("aspenrs.request.be_results", "BE Results", base.DEC, be_results_vals)
+local f_req_be_limit   = ProtoField.uint64("aspenrs.request.be_limit", "BE Result Limit", base.DEC)
+
+-- Handshake fields
+local f_version  = ProtoField.uint16("aspenrs.hello.version", "Protocol Version", base.DEC)
+local f_features = ProtoField.uint32("aspenrs.hello.features", "Features", base.HEX)
+local f_reason   = ProtoField.string("aspenrs.hello.reason", "Reject Reason")
 
 -- Response fields
 local f_resp_freq         = ProtoField.uint64("aspenrs.response.freq", "Frequency", base.DEC)
@@ -47,11 +125,43 @@ local f_resp_has_username = ProtoField.uint8(
     { [NONE_BYTE] = "None", [SOME_BYTE] = "Some" }
 )
 local f_resp_username     = ProtoField.string("aspenrs.response.username", "Username")
+local f_count = ProtoField.uint64("aspenrs.batch.count", "Batch Count", base.DEC)
+
+-- Scan fields
+local f_scan_start  = ProtoField.uint64("aspenrs.scan.start", "Range Start", base.DEC)
+local f_scan_end    = ProtoField.uint64("aspenrs.scan.end", "Range End", base.DEC)
+local f_scan_limit  = ProtoField.uint64("aspenrs.scan.limit", "Limit", base.DEC)
+local f_scan_cursor = ProtoField.uint64("aspenrs.scan.cursor", "Cursor", base.DEC)
+local f_pattern     = ProtoField.string("aspenrs.request.pattern", "Pattern")
+local f_resp_swapped      = ProtoField.uint8("aspenrs.response.swapped", "Swapped", base.DEC, { [0] = "false", [1] = "true" })
+
+-- Error fields
+local error_code_vals = {
+    [1] = "Malformed",
+    [2] = "UnknownType",
+    [3] = "KeyOutOfRange",
+    [4] = "Overloaded",
+    [5] = "Internal",
+    [6] = "TooLarge",
+}
+local f_error_code    = ProtoField.uint8("aspenrs.error.code", "Error Code", base.DEC, error_code_vals)
+local f_error_message = ProtoField.string("aspenrs.error.message", "Error Message")
+local f_queued        = ProtoField.uint64("aspenrs.drop.queued_micros", "Queued (µs)", base.DEC)
+
+-- Stats fields
+local f_stat_name  = ProtoField.string("aspenrs.stats.name", "Counter")
+local f_stat_value = ProtoField.uint64("aspenrs.stats.value", "Value", base.DEC)
 
 aspenrs.fields = {
-    f_type, f_len, f_req_id,
-    f_req_key, f_req_substring, f_req_username,
-    f_resp_freq, f_resp_has_username, f_resp_username,
+    f_type, f_len, f_req_id, f_sent, f_deadline, f_priority, f_tenant,
+    f_req_key, f_req_substring, f_req_username, f_req_expected_len, f_req_expected,
+    f_req_be_results, f_req_be_limit,
+    f_version, f_features, f_reason,
+    f_resp_freq, f_resp_has_username, f_resp_username, f_resp_swapped,
+    f_error_code, f_error_message, f_queued,
+    f_stat_name, f_stat_value,
+    f_count,
+    f_scan_start, f_scan_end, f_scan_limit, f_scan_cursor, f_pattern,
 }
 
 ------------------------------------------------------------
@@ -105,8 +215,41 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
     local req_id       = req_id_range:uint64():tonumber()
     subtree:add(f_req_id, req_id_range)
 
-    local body_len = payload_len - PAYLOAD_HDR_LEN
-    local body     = payload(PAYLOAD_HDR_LEN, body_len)
+    local compressed = kind >= COMPRESSED_FLAG
+    if compressed then
+        kind = kind - COMPRESSED_FLAG
+    end
+    local timed = kind >= TIMED_FLAG
+    if timed then
+        kind = kind - TIMED_FLAG
+    end
+    local tagged = kind >= TAGGED_FLAG
+    if tagged then
+        kind = kind - TAGGED_FLAG
+    end
+
+    local hdr_len = PAYLOAD_HDR_LEN
+    if timed then
+        if payload_len < PAYLOAD_HDR_LEN + TIMING_LEN then
+            subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Payload too short for send time and deadline")
+            return
+        end
+        subtree:add(f_sent, payload(PAYLOAD_HDR_LEN, LEN_LENGTH))
+        subtree:add(f_deadline, payload(PAYLOAD_HDR_LEN + LEN_LENGTH, LEN_LENGTH))
+        hdr_len = hdr_len + TIMING_LEN
+    end
+    if tagged then
+        if payload_len < hdr_len + TAGS_LEN then
+            subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "Payload too short for priority and tenant")
+            return
+        end
+        subtree:add(f_priority, payload(hdr_len, 1))
+        subtree:add(f_tenant, payload(hdr_len + 1, LEN_LENGTH))
+        hdr_len = hdr_len + TAGS_LEN
+    end
+
+    local body_len = payload_len - hdr_len
+    local body     = payload(hdr_len, body_len)
 
     local dir_is_req = is_request(pinfo)
     local dir_str    = dir_is_req and "Request" or "Response"
@@ -118,18 +261,45 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
         dir_str, type_str, req_id
     )
 
+    if compressed then
+        if body_len >= LEN_LENGTH then
+            pinfo.cols.info:append(string.format(" compressed %d -> %d bytes",
+                body(0, LEN_LENGTH):uint64():tonumber(), body_len - LEN_LENGTH))
+        end
+        subtree:add(aspenrs, body, "Compressed Body")
+        return
+    end
+
     --------------------------------------------------------
     -- Requests
     --------------------------------------------------------
     if dir_is_req then
         local req_tree = subtree:add(aspenrs, payload, "Request Payload")
 
-        if kind == BE_BYTE then
-            -- BeRead Request: body = substring bytes (>=1)
-            if body_len >= 1 then
-                local substring = body:string()
-                req_tree:add(f_req_substring, body, substring)
-                pinfo.cols.info:append(' substring="' .. substring .. '"')
+        if kind == HELLO_BYTE then
+            -- Hello Request: body = version: u16 + features: u32 (exactly 6)
+            if body_len ~= 6 then
+                req_tree:add_expert_info(
+                    PI_MALFORMED, PI_ERROR,
+                    "Hello request: body must be exactly 6 bytes (u16 version + u32 features)"
+                )
+            else
+                req_tree:add(f_version, body(0,2))
+                req_tree:add(f_features, body(2,4))
+                pinfo.cols.info:append(string.format(
+                    " version=%d features=0x%x", body(0,2):uint(), body(2,4):uint()))
+            end
+
+        elseif kind == BE_BYTE then
+            -- BeRead Request: body = results mode: u8 + limit: u64 + substring bytes (>=1)
+            if body_len >= 10 then
+                local mode = body(0,1):uint()
+                local substring = body(9, body_len - 9):string()
+                req_tree:add(f_req_be_results, body(0,1))
+                req_tree:add(f_req_be_limit, body(1,8))
+                req_tree:add(f_req_substring, body(9, body_len - 9), substring)
+                pinfo.cols.info:append(string.format(' substring="%s" results=%s limit=%d',
+                    substring, be_results_vals[mode] or tostring(mode), body(1,8):uint64():tonumber()))
             else
                 req_tree:add_expert_info(
                     PI_MALFORMED, PI_ERROR,
@@ -137,12 +307,12 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
                 )
             end
 
-        elseif kind == LC_READ_BYTE then
-            -- LcRead Request: body = id: u64 (exactly 8)
+        elseif kind == LC_READ_BYTE or kind == LC_DELETE_BYTE then
+            -- LcRead/LcDelete Request: body = id: u64 (exactly 8)
             if body_len ~= 8 then
                 req_tree:add_expert_info(
                     PI_MALFORMED, PI_ERROR,
-                    "LcRead request: body must be exactly 8 bytes (u64 id)"
+                    type_str .. " request: body must be exactly 8 bytes (u64 id)"
                 )
             else
                 local id_val = body:uint64():tonumber()
@@ -150,12 +320,97 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
                 pinfo.cols.info:append(" id=" .. tostring(id_val))
             end
 
-        elseif kind == LC_WRITE_BYTE then
-            -- LcWrite Request: body = id: u64 + username bytes (>= 8)
+        elseif kind == PREFIX_COUNT_BYTE or kind == REGEX_COUNT_BYTE or kind == GLOB_COUNT_BYTE then
+            -- PrefixCount/RegexCount/GlobCount Request: body = pattern bytes (>=1)
+            if body_len >= 1 then
+                local pattern = body:string()
+                req_tree:add(f_pattern, body, pattern)
+                pinfo.cols.info:append(' pattern="' .. pattern .. '"')
+            else
+                req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " request: pattern missing")
+            end
+
+        elseif kind == CANCEL_BYTE then
+            -- Cancel Request: empty body, the req_id names the request to abort
+            if body_len ~= 0 then
+                req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Cancel request: body must be empty")
+            end
+
+        elseif kind == STATS_BYTE then
+            -- Stats Request: empty body
+            if body_len ~= 0 then
+                req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Stats request: body must be empty")
+            end
+
+        elseif kind == RANGE_SCAN_BYTE then
+            -- RangeScan Request: body = start: u64 + end: u64 + limit: u64 (exactly 24)
+            if body_len ~= 24 then
+                req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "RangeScan request: body must be exactly 24 bytes (start + end + limit)")
+            else
+                req_tree:add(f_scan_start, body(0,8))
+                req_tree:add(f_scan_end, body(8,8))
+                req_tree:add(f_scan_limit, body(16,8))
+                pinfo.cols.info:append(string.format(" range=%d..%d limit=%d",
+                    body(0,8):uint64():tonumber(), body(8,8):uint64():tonumber(), body(16,8):uint64():tonumber()))
+            end
+
+        elseif kind == MULTI_GET_BYTE or kind == MULTI_PUT_BYTE then
+            -- MultiGet Request: body = count: u64 + count * id: u64
+            -- MultiPut Request: body = count: u64 + count * (id: u64 + len: u64 + username bytes)
+            if body_len < 8 then
+                req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " request: body too short for count")
+            else
+                local count = body(0,8):uint64():tonumber()
+                req_tree:add(f_count, body(0,8))
+                pinfo.cols.info:append(" count=" .. tostring(count))
+                local off = 8
+                for _ = 1, count do
+                    if off + 8 > body_len then
+                        req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " request: truncated entry")
+                        break
+                    end
+                    req_tree:add(f_req_key, body(off,8))
+                    off = off + 8
+                    if kind == MULTI_PUT_BYTE then
+                        local uname_len = off + 8 <= body_len and body(off,8):uint64():tonumber() or nil
+                        if uname_len == nil or off + 8 + uname_len > body_len then
+                            req_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " request: truncated username")
+                            break
+                        end
+                        req_tree:add(f_req_username, body(off + 8, uname_len), uname_len > 0 and body(off + 8, uname_len):string() or "")
+                        off = off + 8 + uname_len
+                    end
+                end
+            end
+
+        elseif kind == LC_CAS_BYTE then
+            -- LcCompareAndSwap Request: body = id: u64 + expected_len: u64 + expected bytes + username bytes
+            local expected_len = body_len >= 16 and body(8,8):uint64():tonumber() or nil
+            if expected_len == nil or body_len < 16 + expected_len then
+                req_tree:add_expert_info(
+                    PI_MALFORMED, PI_ERROR,
+                    "LcCompareAndSwap request: body too short for id, expected length and expected username"
+                )
+            else
+                local id_val = body(0,8):uint64():tonumber()
+                req_tree:add(f_req_key, body(0,8))
+                req_tree:add(f_req_expected_len, body(8,8))
+                local expected = expected_len > 0 and body(16, expected_len):string() or ""
+                req_tree:add(f_req_expected, body(16, expected_len), expected)
+                local uname_len = body_len - 16 - expected_len
+                local uname = uname_len > 0 and body(16 + expected_len, uname_len):string() or ""
+                req_tree:add(f_req_username, body(16 + expected_len, uname_len), uname)
+                pinfo.cols.info:append(
+                    string.format(" id=%d expected=\"%s\" username=\"%s\"", id_val, expected, uname)
+                )
+            end
+
+        elseif kind == LC_WRITE_BYTE or kind == LC_INSERT_IF_ABSENT_BYTE then
+            -- LcWrite/LcInsertIfAbsent Request: body = id: u64 + username bytes (>= 8)
             if body_len < 8 then
                 req_tree:add_expert_info(
                     PI_MALFORMED, PI_ERROR,
-                    "LcWrite request: body must be at least 8 bytes (id + username)"
+                    type_str .. " request: body must be at least 8 bytes (id + username)"
                 )
             else
                 local id_range = body(0,8)
@@ -191,12 +446,125 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
     else
         local resp_tree = subtree:add(aspenrs, payload, "Response Payload")
 
-        if kind == BE_BYTE then
-            -- BeRead Response: body = freq: u64 (exactly 8)
+        if kind == HELLO_BYTE then
+            -- HelloAck Response: body = version: u16 + negotiated features: u32 (exactly 6)
+            if body_len ~= 6 then
+                resp_tree:add_expert_info(
+                    PI_MALFORMED, PI_ERROR,
+                    "HelloAck response: body must be exactly 6 bytes (u16 version + u32 features)"
+                )
+            else
+                resp_tree:add(f_version, body(0,2))
+                resp_tree:add(f_features, body(2,4))
+                pinfo.cols.info:append(string.format(
+                    " (ack) version=%d features=0x%x", body(0,2):uint(), body(2,4):uint()))
+            end
+
+        elseif kind == HELLO_REJECT_BYTE then
+            -- HelloReject Response: body = server version: u16 + reason bytes
+            if body_len < 2 then
+                resp_tree:add_expert_info(
+                    PI_MALFORMED, PI_ERROR,
+                    "HelloReject response: body too short for version"
+                )
+            else
+                resp_tree:add(f_version, body(0,2))
+                if body_len > 2 then
+                    local reason = body(2, body_len - 2):string()
+                    resp_tree:add(f_reason, body(2, body_len - 2), reason)
+                    pinfo.cols.info:append(' reason="' .. reason .. '"')
+                end
+            end
+
+        elseif kind == ERROR_BYTE then
+            -- Error Response: body = code: u8 + message bytes
+            if body_len < 1 then
+                resp_tree:add_expert_info(
+                    PI_MALFORMED, PI_ERROR,
+                    "Error response: body too short for code"
+                )
+            else
+                local code = body(0,1):uint()
+                resp_tree:add(f_error_code, body(0,1))
+                pinfo.cols.info:append(" code=" .. (error_code_vals[code] or tostring(code)))
+                if body_len > 1 then
+                    local message = body(1, body_len - 1):string()
+                    resp_tree:add(f_error_message, body(1, body_len - 1), message)
+                    pinfo.cols.info:append(' message="' .. message .. '"')
+                end
+            end
+
+        elseif kind == RANGE_SCAN_BYTE or kind == CHUNK_BYTE then
+            -- RangeScan Response: body = cursor tag: u8 [+ cursor: u64 if SOME] + count: u64 + count * (id: u64 + len: u64 + username bytes)
+            -- Chunk Response: body = count: u64 + count * (id: u64 + len: u64 + username bytes)
+            local off = 0
+            if kind == CHUNK_BYTE then
+                off = 0
+            elseif body_len >= 1 and body(0,1):uint() == SOME_BYTE and body_len >= 9 then
+                resp_tree:add(f_scan_cursor, body(1,8))
+                pinfo.cols.info:append(" cursor=" .. tostring(body(1,8):uint64():tonumber()))
+                off = 9
+            else
+                off = 1
+            end
+            if off + 8 > body_len then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " response: body too short for count")
+            else
+                local count = body(off,8):uint64():tonumber()
+                resp_tree:add(f_count, body(off,8))
+                pinfo.cols.info:append(" count=" .. tostring(count))
+                off = off + 8
+                for _ = 1, count do
+                    local uname_len = off + 16 <= body_len and body(off + 8,8):uint64():tonumber() or nil
+                    if uname_len == nil or off + 16 + uname_len > body_len then
+                        resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " response: truncated entry")
+                        break
+                    end
+                    resp_tree:add(f_req_key, body(off,8))
+                    resp_tree:add(f_resp_username, body(off + 16, uname_len), uname_len > 0 and body(off + 16, uname_len):string() or "")
+                    off = off + 16 + uname_len
+                end
+            end
+
+        elseif kind == BE_BYTE then
+            -- BeRead Response: body = freq: u64 + key count: u64 + keys * u64 + username count: u64 + usernames * (len: u64 + bytes)
+            if body_len < 16 then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "BeRead response: body too short for freq and key count")
+            else
+                resp_tree:add(f_resp_freq, body(0,8))
+                pinfo.cols.info:append(" freq=" .. tostring(body(0,8):uint64():tonumber()))
+                local keys = body(8,8):uint64():tonumber()
+                resp_tree:add(f_count, body(8,8))
+                local off = 16
+                if off + keys * 8 + 8 > body_len then
+                    resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "BeRead response: truncated keys")
+                else
+                    for _ = 1, keys do
+                        resp_tree:add(f_req_key, body(off,8))
+                        off = off + 8
+                    end
+                    local count = body(off,8):uint64():tonumber()
+                    resp_tree:add(f_count, body(off,8))
+                    off = off + 8
+                    for _ = 1, count do
+                        local uname_len = off + 8 <= body_len and body(off,8):uint64():tonumber() or nil
+                        if uname_len == nil or off + 8 + uname_len > body_len then
+                            resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "BeRead response: truncated username")
+                            break
+                        end
+                        resp_tree:add(f_resp_username, body(off + 8, uname_len), uname_len > 0 and body(off + 8, uname_len):string() or "")
+                        off = off + 8 + uname_len
+                    end
+                    pinfo.cols.info:append(string.format(" keys=%d usernames=%d", keys, count))
+                end
+            end
+
+        elseif kind == PREFIX_COUNT_BYTE or kind == REGEX_COUNT_BYTE or kind == GLOB_COUNT_BYTE then
+            -- PrefixCount/RegexCount/GlobCount Response: body = freq: u64 (exactly 8)
             if body_len ~= 8 then
                 resp_tree:add_expert_info(
                     PI_MALFORMED, PI_ERROR,
-                    "BeRead response: body must be exactly 8 bytes (u64 freq)"
+                    type_str .. " response: body must be exactly 8 bytes (u64 freq)"
                 )
             else
                 local freq_val = body:uint64():tonumber()
@@ -204,10 +572,83 @@ local function dissect_message(buffer, pinfo, tree, kind, payload_len)
                 pinfo.cols.info:append(" freq=" .. tostring(freq_val))
             end
 
-        elseif kind == LC_READ_BYTE or kind == LC_WRITE_BYTE then
-            -- LcRead/LcWrite Response:
+        elseif kind == DROP_BYTE then
+            -- Drop Response: body = queued_micros: u64 (exactly 8)
+            if body_len ~= 8 then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Drop response: body must be exactly 8 bytes (u64 queued_micros)")
+            else
+                resp_tree:add(f_queued, body)
+                pinfo.cols.info:append(" queued=" .. tostring(body:uint64():tonumber()) .. "us")
+            end
+
+        elseif kind == CANCEL_BYTE then
+            -- Cancelled Response: empty body, answers the aborted request
+            if body_len ~= 0 then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Cancelled response: body must be empty")
+            end
+
+        elseif kind == STATS_BYTE then
+            -- Stats Response: body = count: u64 + count * (name len: u64 + name bytes + value: u64)
+            if body_len < 8 then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Stats response: body too short for count")
+            else
+                local count = body(0,8):uint64():tonumber()
+                resp_tree:add(f_count, body(0,8))
+                pinfo.cols.info:append(" counters=" .. tostring(count))
+                local off = 8
+                for _ = 1, count do
+                    local name_len = off + 8 <= body_len and body(off,8):uint64():tonumber() or nil
+                    if name_len == nil or off + 16 + name_len > body_len then
+                        resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, "Stats response: truncated counter")
+                        break
+                    end
+                    resp_tree:add(f_stat_name, body(off + 8, name_len), body(off + 8, name_len):string())
+                    resp_tree:add(f_stat_value, body(off + 8 + name_len, 8))
+                    off = off + 16 + name_len
+                end
+            end
+
+        elseif kind == MULTI_GET_BYTE or kind == MULTI_PUT_BYTE then
+            -- MultiGet/MultiPut Response: body = count: u64 + count * (tag: u8 [+ len: u64 + username bytes if SOME])
+            if body_len < 8 then
+                resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " response: body too short for count")
+            else
+                local count = body(0,8):uint64():tonumber()
+                resp_tree:add(f_count, body(0,8))
+                pinfo.cols.info:append(" count=" .. tostring(count))
+                local off = 8
+                for _ = 1, count do
+                    if off + 1 > body_len then
+                        resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " response: truncated entry")
+                        break
+                    end
+                    resp_tree:add(f_resp_has_username, body(off,1))
+                    local tag = body(off,1):uint()
+                    off = off + 1
+                    if tag == SOME_BYTE then
+                        local uname_len = off + 8 <= body_len and body(off,8):uint64():tonumber() or nil
+                        if uname_len == nil or off + 8 + uname_len > body_len then
+                            resp_tree:add_expert_info(PI_MALFORMED, PI_ERROR, type_str .. " response: truncated username")
+                            break
+                        end
+                        resp_tree:add(f_resp_username, body(off + 8, uname_len), uname_len > 0 and body(off + 8, uname_len):string() or "")
+                        off = off + 8 + uname_len
+                    end
+                end
+            end
+
+        elseif kind == LC_READ_BYTE or kind == LC_WRITE_BYTE or kind == LC_DELETE_BYTE
+            or kind == LC_INSERT_IF_ABSENT_BYTE or kind == LC_CAS_BYTE then
+            -- LcRead/LcWrite/LcDelete/LcInsertIfAbsent Response:
             -- body[0] = 0 (NONE) or 1 (SOME)
             -- if SOME, remainder = username bytes (may be empty)
+            -- LcCompareAndSwap Response: swapped: u8 followed by the same layout
+            if kind == LC_CAS_BYTE and body_len >= 1 then
+                resp_tree:add(f_resp_swapped, body(0,1))
+                pinfo.cols.info:append(" swapped=" .. (body(0,1):uint() == 1 and "true" or "false"))
+                body_len = body_len - 1
+                body = body_len > 0 and body(1, body_len) or body(1, 0)
+            end
             if body_len < 1 then
                 resp_tree:add_expert_info(
                     PI_MALFORMED, PI_ERROR,
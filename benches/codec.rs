@@ -0,0 +1,148 @@
+// Allocations and time per message for the packet codec: owned serialize/deserialize against
+// encoding into a reused buffer and decoding views into the receive buffer, then bytes and time per
+// round trip of large BE results with and without compression.
+// Run with `cargo bench --bench codec`
+use std::{alloc::{GlobalAlloc, Layout, System}, hint::black_box, sync::atomic::{AtomicU64, Ordering}, time::Instant};
+
+use aspen_rust::{COMPRESSION_THRESHOLD, packet::{BeResults, ErrorCode, Features, FrameDecoder, FrameLimits, Layout as WireLayout, Message, MessageType, Request, RequestOptions, RequestType, RequestView, Response, ResponseView, Stamp}};
+
+struct CountingAlloc;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCS.fetch_add(1, Ordering::Relaxed);
+    unsafe { System.alloc(layout) }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { System.dealloc(ptr, layout) }
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    ALLOCS.fetch_add(1, Ordering::Relaxed);
+    unsafe { System.realloc(ptr, layout, new_size) }
+  }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: u64 = 100_000;
+const BATCH_SIZE: usize = 8;
+const LARGE_RESULTS: usize = 1000; // matches in the BE responses used to weigh compression
+
+fn main() {
+  let options = RequestOptions { batch_size: BATCH_SIZE, be_results: BeResults::KeysAndUsernames, ..RequestOptions::default() };
+  let mut report = format!("--- CODEC BENCHMARK: {ITERATIONS} ROUND TRIPS PER MESSAGE, BATCHES OF {BATCH_SIZE} ---\n");
+  report = format!("{report}{:<26} {:>14} {:>14} {:>12} {:>12}\n", "MESSAGE", "OWNED ALLOCS", "VIEW ALLOCS", "OWNED NS", "VIEW NS");
+
+  let mut buf = Vec::new();
+  for kind in RequestType::iterator() {
+    let req = Request::random_in(kind, 1, 0..1000, &options);
+    let owned = measure(|| {
+      black_box(Request::deserialize(&req.serialize()).unwrap());
+    });
+    let view = measure(|| {
+      buf.clear();
+      req.encode_into(&mut buf);
+      black_box(RequestView::decode(&buf).unwrap());
+    });
+    report = format!("{report}{}", row(format!("{:?} REQUEST", kind), owned, view));
+  }
+
+  for res in sample_responses() {
+    let owned = measure(|| {
+      black_box(Response::deserialize(&res.serialize()).unwrap());
+    });
+    let view = measure(|| {
+      buf.clear();
+      res.encode_into(&mut buf);
+      black_box(ResponseView::decode(&buf).unwrap());
+    });
+    report = format!("{report}{}", row(format!("{:?} RESPONSE", res.kind()), owned, view));
+  }
+
+  report = format!("{report}\n--- COMPRESSION OVER {COMPRESSION_THRESHOLD} BYTES: {LARGE_RESULTS} RESULTS PER RESPONSE ---\n");
+  report = format!("{report}{:<26} {:>14} {:>14} {:>14} {:>14}\n", "MESSAGE", "PLAIN BYTES", "WIRE BYTES", "PLAIN NS", "COMPRESSED NS");
+  let mut decoder = FrameDecoder::new(FrameLimits::responses());
+  for res in large_responses() {
+    let plain = measure(|| {
+      buf.clear();
+      res.encode_into(&mut buf);
+      decoder.push(&buf);
+      black_box(decoder.next_response().unwrap().unwrap());
+    }).1;
+    let compressed = measure(|| {
+      buf.clear();
+      res.encode_framed(&mut buf, WireLayout::Fixed, Stamp::default(), Some(COMPRESSION_THRESHOLD));
+      decoder.push(&buf);
+      black_box(decoder.next_response().unwrap().unwrap());
+    }).1;
+    let plain_len = res.serialize().len();
+    buf.clear();
+    res.encode_framed(&mut buf, WireLayout::Fixed, Stamp::default(), Some(COMPRESSION_THRESHOLD));
+    report = format!("{report}{:<26} {:>14} {:>14} {:>14.0} {:>14.0}\n", format!("{:?} RESPONSE", res.kind()), plain_len, buf.len(), plain, compressed);
+  }
+  println!("{report}");
+}
+
+// allocations and nanoseconds per call of `round_trip`, after a warm up call that may grow reused buffers
+fn measure(mut round_trip: impl FnMut()) -> (f64, f64) {
+  round_trip();
+  let allocs = ALLOCS.load(Ordering::Relaxed);
+  let timer = Instant::now();
+  for _ in 0..ITERATIONS {
+    round_trip();
+  }
+  let nanos = timer.elapsed().as_nanos() as f64;
+  ((ALLOCS.load(Ordering::Relaxed) - allocs) as f64 / ITERATIONS as f64, nanos / ITERATIONS as f64)
+}
+
+fn row(name: String, owned: (f64, f64), view: (f64, f64)) -> String {
+  format!("{:<26} {:>14.2} {:>14.2} {:>12.0} {:>12.0}\n", name, owned.0, view.0, owned.1, view.1)
+}
+
+// BE results as a store of generated usernames returns them
+fn large_responses() -> Vec<Response> {
+  let entries: Vec<(u64, Vec<u8>)> = (0..LARGE_RESULTS as u64).map(|id| (id * 37, format!("user{}", id * 37).into_bytes())).collect();
+  vec![
+    Response::BeRead {
+      req_id: 1,
+      freq: LARGE_RESULTS as u64,
+      keys: entries.iter().map(|(id, _)| *id).collect(),
+      usernames: entries.iter().map(|(_, username)| username.clone()).collect()
+    },
+    Response::MultiGet { req_id: 1, usernames: entries.iter().map(|(_, username)| Some(username.clone())).collect() },
+    Response::RangeScan { req_id: 1, entries, cursor: None },
+  ]
+}
+
+fn sample_responses() -> Vec<Response> {
+  let username = || Some(b"quietlemur1984".to_vec());
+  let entries: Vec<(u64, Vec<u8>)> = (0..BATCH_SIZE as u64).map(|id| (id, format!("user{id}").into_bytes())).collect();
+  vec![
+    Response::HelloAck { req_id: 1, version: 1, features: Features::PIPELINING },
+    Response::HelloReject { req_id: 1, version: 1, reason: "unsupported protocol version 2".to_string() },
+    Response::Error { req_id: 1, code: ErrorCode::KeyOutOfRange, message: "key 9000000 is not below 8500000".to_string() },
+    Response::Chunk { req_id: 1, entries: entries.clone() },
+    Response::BeRead {
+      req_id: 1,
+      freq: 42,
+      keys: entries.iter().map(|(id, _)| *id).collect(),
+      usernames: entries.iter().map(|(_, username)| username.clone()).collect()
+    },
+    Response::LcRead { req_id: 1, username: username() },
+    Response::LcWrite { req_id: 1, username: username() },
+    Response::LcDelete { req_id: 1, username: username() },
+    Response::LcInsertIfAbsent { req_id: 1, username: None },
+    Response::LcCompareAndSwap { req_id: 1, swapped: true, username: username() },
+    Response::MultiGet { req_id: 1, usernames: vec![username(); BATCH_SIZE] },
+    Response::MultiPut { req_id: 1, usernames: vec![username(); BATCH_SIZE] },
+    Response::RangeScan { req_id: 1, entries, cursor: Some(BATCH_SIZE as u64) },
+    Response::PrefixCount { req_id: 1, freq: 42 },
+    Response::RegexCount { req_id: 1, freq: 42 },
+    Response::GlobCount { req_id: 1, freq: 42 },
+  ]
+}
@@ -0,0 +1,81 @@
+// Bytes on the wire and round trip latency of LC requests in the fixed layout against the compact one,
+// over a single blocking connection to an in-process server holding NUM_KEYS generated usernames.
+// Run with `cargo bench --bench compact`
+use std::{collections::HashMap, io::{Read, Write}, net::TcpStream, sync::mpsc, thread, time::Instant};
+
+use aspen_rust::{PROTOCOL_VERSION, packet::{Features, FrameDecoder, FrameLimits, Layout, Message, Request, RequestOptions, RequestType, ResponseView}, server, store::Store};
+use hdrhistogram::Histogram;
+
+const PORT: usize = 12346;
+const NUM_KEYS: usize = 1_000_000;
+const ROUND_TRIPS: u64 = 20_000;
+const LC_TYPES: [RequestType; 2] = [RequestType::LcRead, RequestType::LcWrite];
+
+fn main() {
+  let usernames = (0..NUM_KEYS).map(|key| (key, format!("user{key}").into_bytes())).collect::<HashMap<_, _>>();
+  let store = Store::from_map(usernames);
+  let (tx, rx) = mpsc::sync_channel::<()>(1);
+  thread::spawn(move || server::DefaultSmolServer::init(2, PORT, tx, store));
+  rx.recv().unwrap();
+
+  let mut report = format!("--- COMPACT BENCHMARK: {ROUND_TRIPS} ROUND TRIPS PER MESSAGE ---\n");
+  report = format!("{report}{:<10} {:<8} {:>12} {:>12} {:>10} {:>10}\n", "MESSAGE", "LAYOUT", "REQ BYTES", "RES BYTES", "P50 US", "P99 US");
+  for kind in LC_TYPES {
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let (req_bytes, res_bytes, latency) = round_trips(kind, layout);
+      report = format!("{report}{:<10} {:<8} {:>12.1} {:>12.1} {:>10.1} {:>10.1}\n",
+        format!("{kind:?}"), format!("{layout:?}"), req_bytes, res_bytes,
+        latency.value_at_quantile(0.5) as f64 / 1000.0, latency.value_at_quantile(0.99) as f64 / 1000.0);
+    }
+  }
+  println!("{report}");
+}
+
+// average request and response bytes and the latency histogram in nanoseconds of `ROUND_TRIPS` requests
+fn round_trips(kind: RequestType, layout: Layout) -> (f64, f64, Histogram<u64>) {
+  let features = match layout {
+    Layout::Fixed => Features::PIPELINING,
+    Layout::Compact => Features::PIPELINING | Features::COMPACT,
+  };
+  let mut stream = TcpStream::connect(format!("127.0.0.1:{PORT}")).unwrap();
+  stream.set_nodelay(true).unwrap();
+  let mut decoder = FrameDecoder::new(FrameLimits::responses());
+  let mut buf = [0; 4096];
+
+  let hello = Request::Hello { req_id: 0, version: PROTOCOL_VERSION, features };
+  stream.write_all(&hello.serialize()).unwrap();
+  let negotiated = loop {
+    if let Some(ResponseView::HelloAck { features, .. }) = decoder.next_response().unwrap() {
+      break features;
+    }
+    let bytes_read = stream.read(&mut buf).unwrap();
+    decoder.push(&buf[..bytes_read]);
+  };
+  assert_eq!(Layout::negotiated(negotiated), layout, "server did not agree to the {layout:?} layout");
+  decoder.set_layout(layout);
+
+  let options = RequestOptions::default();
+  let mut latency = Histogram::<u64>::new(3).unwrap();
+  let (mut req_bytes, mut res_bytes) = (0, 0);
+  let mut write_buf = Vec::new();
+  for req_id in 1..=ROUND_TRIPS {
+    write_buf.clear();
+    Request::random_in(kind, req_id, 0..NUM_KEYS as u64, &options).encode_with(&mut write_buf, layout);
+    req_bytes += write_buf.len();
+
+    let timer = Instant::now();
+    stream.write_all(&write_buf).unwrap();
+    loop {
+      let pending = decoder.pending().len();
+      if decoder.next_frame().unwrap().is_some() {
+        res_bytes += pending - decoder.pending().len();
+        break;
+      }
+      let bytes_read = stream.read(&mut buf).unwrap();
+      assert!(bytes_read > 0, "server closed the connection");
+      decoder.push(&buf[..bytes_read]);
+    }
+    latency.record(timer.elapsed().as_nanos() as u64).unwrap();
+  }
+  (req_bytes as f64 / ROUND_TRIPS as f64, res_bytes as f64 / ROUND_TRIPS as f64, latency)
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashMap, fs::{self, File}, io::{ErrorKind, Read, Write}, net::TcpStream, sync::{Arc, atomic::{AtomicU64, Ordering}}, thread::{self, JoinHandle}, time::Instant};
+use std::{collections::{HashMap, HashSet}, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, sync::{Arc, atomic::{AtomicU64, Ordering}}, thread::{self, JoinHandle}, time::Instant};
 use hdrhistogram::Histogram;
 use rand::Rng;
 
-use crate::{AspenRsError, BUF_LEN, LEN_LENGTH, NetworkError, ParseError, SIG_FIG, packet::{Message, MessageType, Request, RequestType, Response, ResponseType}};
+use crate::{AspenRsError, CAPACITY, client::{ClientOptions, LatencyBreakdown, Negotiated, Outcomes, ServerSnapshots, Stream, Tagger, Traffic, encode_request, is_corrupt_frame, is_disconnect, latency_by_quant_distr, write_report, SeenValues, verify::Verifier}, BUF_LEN, NetworkError, ParseError, SIG_FIG, packet::{Features, FrameDecoder, FrameLimits, FrameStats, Message, MessageType, Request, RequestOptions, RequestType, ResponseType, ResponseView, Tags, frame_server_timing}};
 
 #[derive(Debug)]
 pub struct ClosedBench {
@@ -11,6 +11,9 @@ pub struct ClosedBench {
   lc_write_read_ratio: f32,
   num_threads: usize,
   workload: usize,
+  write_mix: Vec<(RequestType, f32)>,
+  be_mix: Vec<(RequestType, f32)>,
+  options: ClientOptions,
 }
 
 impl ClosedBench {
@@ -21,21 +24,52 @@ impl ClosedBench {
       lc_write_read_ratio,
       num_threads,
       workload,
+      write_mix: vec![(RequestType::LcWrite, 1.0)],
+      be_mix: vec![(RequestType::BeRead, 1.0)],
+      options: ClientOptions::default(),
     }
   }
 
+  // the options shared with the open loop. Verifying gives each connection its own slice of the keyspace
+  pub fn options(mut self, options: ClientOptions) -> Self {
+    assert!(!options.verify || self.num_threads * self.conns_per_thr <= CAPACITY, "verifying needs at least one key per connection");
+    self.options = options;
+    self
+  }
+
+  // relative weights of the update types making up the LC write share of the workload
+  pub fn write_mix(mut self, write_mix: HashMap<RequestType, f32>) -> Self {
+    self.write_mix = weighted_mix(write_mix);
+    assert!(!self.write_mix.is_empty(), "write mix needs at least one update type with a positive weight");
+    self
+  }
+
+  // relative weights of the BE operation types making up the BE share of the workload
+  pub fn be_mix(mut self, be_mix: HashMap<RequestType, f32>) -> Self {
+    self.be_mix = weighted_mix(be_mix);
+    assert!(!self.be_mix.is_empty(), "BE mix needs at least one operation type with a positive weight");
+    self
+  }
+
   pub fn run(&self, port: usize) {
     let mut handles: Vec<JoinHandle<ClientThread>> = Vec::new();
     let req_id = Arc::new(AtomicU64::new(0));
+    let options = Arc::new(self.options.clone());
     println!("Creating {} client threads", self.num_threads);
-    for _ in 0..self.num_threads {
-      let workload = self.workload / self.num_threads;
-      let ratio = self.be_lc_ratio;
-      let conns_per_thr = self.conns_per_thr;
-      let wr_ratio = self.lc_write_read_ratio;
+    for thread_idx in 0..self.num_threads {
+      let config = ThreadConfig {
+        workload: self.workload / self.num_threads,
+        be_prob: self.be_lc_ratio,
+        wr_lc_prob: self.lc_write_read_ratio,
+        conns_per_thr: self.conns_per_thr,
+        verify_conns: self.options.verify.then_some(self.num_threads * self.conns_per_thr),
+        options: options.clone(),
+        write_mix: self.write_mix.clone(),
+        be_mix: self.be_mix.clone(),
+      };
       let req_id = req_id.clone();
       handles.push(
-        thread::spawn(move || {ClientThread::init(port, workload, ratio, conns_per_thr, wr_ratio, req_id)})
+        thread::spawn(move || {ClientThread::init(port, thread_idx, req_id, config)})
       );
     }
 
@@ -45,6 +79,8 @@ impl ClosedBench {
       client_threads.push(handle.join().unwrap());
     }
 
+    let mut stats = self.options.stats_connection(port);
+    let start_stats = stats.as_mut().map(|stats| stats.snapshot().unwrap());
     println!("Begin sending requests...");
     let tp_timer = Instant::now();
     let mut handles: Vec<JoinHandle<ClientThread>> = Vec::new();
@@ -61,153 +97,157 @@ impl ClosedBench {
     }
 
     let tp_time= tp_timer.elapsed().as_secs_f32();
+    let server = stats.zip(start_stats).map(|(mut stats, start)| ServerSnapshots { start, end: stats.snapshot().unwrap() });
     println!("All requests fulfilled in {tp_time} seconds! Calculating statistics...");
 
     let mut stat_map: HashMap<ResponseType, Histogram<u64>> = HashMap::new();
+    let mut first_chunk_map: HashMap<ResponseType, Histogram<u64>> = HashMap::new();
     for i in ResponseType::iterator() {
       stat_map.insert(i, Histogram::new_with_bounds(1, u64::MAX,SIG_FIG).unwrap());
+      first_chunk_map.insert(i, Histogram::new_with_bounds(1, u64::MAX,SIG_FIG).unwrap());
     }
 
+    let mut outcomes = Outcomes::default();
+    let mut traffic = Traffic::default();
+    let mut breakdown = LatencyBreakdown::default();
     for thr in client_threads {
       for (t, l) in thr.latencies {
         let hist = stat_map.get_mut(&t).unwrap();
         l.iter().for_each(|i| {let _ = hist.record(*i as u64);});
       }
+      for (t, l) in thr.first_chunks {
+        let hist = first_chunk_map.get_mut(&t).unwrap();
+        l.iter().for_each(|i| {let _ = hist.record(*i as u64);});
+      }
+      outcomes.merge(&thr.outcomes);
+      traffic.merge(&thr.traffic);
+      breakdown.merge(&thr.breakdown);
     }
 
-    self.general_results(tp_time, &stat_map);
-    self.latency_by_quant_distr(&stat_map);
+    let completed = self.general_results(tp_time, &stat_map);
+    write_report(completed, &outcomes, &traffic, server.as_ref(), &breakdown, &stat_map, &first_chunk_map);
+    latency_by_quant_distr(&stat_map);
     
     println!("Completed benchmark!");
   }
 
-  fn general_results(&self, total_secs: f32, stat_map: &HashMap<ResponseType, Histogram<u64>>) {
+  // header, setup and throughput of the report
+  fn general_results(&self, total_secs: f32, stat_map: &HashMap<ResponseType, Histogram<u64>>) -> String {
     let datetime = chrono::offset::Local::now();
     let header = format!("--- CLOSED-LOOP BENCHMARK TEST: {datetime} ---\n");
     
-    let setup = format!("SETUP:\n    THREADS: {},\n    CONNECTIONS PER THREAD: {},\n    NUM TASKS: {}\n    BE:LC RATIO: {}\n    LC WRITE:READ RATIO: {}\n\n",
-        self.num_threads, self.conns_per_thr, self.workload, self.be_lc_ratio, self.lc_write_read_ratio);
-    let throughput = format!("THROUGHPUT: {} TASKS / {} SECONDS = {} TASKS PER SECOND\n\n", self.workload, total_secs, self.workload as f32 / total_secs);
-
-    let mut stats = String::new();
-    for t in ResponseType::iterator(){
-      let hist = stat_map.get(&t).unwrap();
-      let title = format!("{:?} STATS:\n", t);
-      let size = format!("     SIZE: {}\n", hist.len());
-
-      let vals = [
-        hist.value_at_quantile(0.5) as f64,
-        hist.value_at_quantile(0.95) as f64,
-        hist.value_at_quantile(0.99) as f64,
-        hist.value_at_quantile(0.999) as f64,
-        hist.mean(),
-        hist.stdev()
-      ];
-
-      let mut val_strs: Vec<String> = Vec::new();
-
-      for val in vals {
-        if val < 1e4 {
-          // micros
-          val_strs.push(format!("{} µs", val as u64));
-        } else if val < 1e6 {
-          // millis
-          val_strs.push(format!("{:.3} ms", (val / 1000.0)));
-        } else {
-          // seconds
-          val_strs.push(format!("{:.6} secs", (val / 1000000.0)));
-        }
-      }
-      
-      let median = format!("     p50 LATENCY: {}\n", val_strs[0]);
-      let p95 = format!("     p95 LATENCY: {}\n", val_strs[1]);
-      let p99 = format!("     p99 LATENCY: {}\n", val_strs[2]);
-      let p999 = format!("     p99.9 LATENCY: {}\n", val_strs[3]);
-      let mean = format!("     MEAN LATENCY: {}\n", val_strs[4]);
-      let stddev = format!("     STD DEV: {}\n", val_strs[5]);
-
-      stats = format!("{stats}{title}{size}{median}{p95}{p99}{p999}{mean}{stddev}\n");
+    let mut write_mix = String::new();
+    for (t, weight) in &self.write_mix {
+      write_mix = format!("{write_mix}        {:?}: {}\n", t, weight);
     }
-
-    // let data = format!("DATA:\n    BE DATA: {:?}\n    LC DATA: {:?}", be_agg, lc_agg);
-    let prev = String::from_utf8_lossy(&fs::read("out/benchmark.txt").unwrap()).to_string();
-    fs::write("out/benchmark.txt", format!("{header}{setup}{throughput}{stats}{prev}")).unwrap();
-  }
-
-  fn latency_by_quant_distr(&self, stat_map: &HashMap<ResponseType, Histogram<u64>>) {
-    for (t, hist) in stat_map {
-      let path = match t {
-        ResponseType::BeRead => "beread",
-        ResponseType::LcRead => "lcread",
-        ResponseType::LcWrite => "lcwrite",
-      };
-
-      let file = File::open("bench/quantiles.txt").unwrap();
-      let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-      let quantiles: Vec<f64> = rdr.records().map(
-        |s| s.unwrap().get(0).unwrap().to_string().parse::<f64>().unwrap()).collect();
-      
-      let mut hist_data = format!("{:^8}    {:^8}    {:^8}    {:^8.3}\n", "Value", "Quantile", "Agg Count", "1/1-quantile");
-      for quantile in quantiles {
-        hist_data = format!("{hist_data}{:>8}    {:>8}    {:>8}    {:>8.3}\n",
-         hist.value_at_quantile(quantile), quantile, (hist.len() as f64 * quantile) as u64, 1.0 / (1.0 - quantile));
-      }
-      let _ = fs::write(format!("out/{path}.txt"), hist_data);
+    let mut be_mix = String::new();
+    for (t, weight) in &self.be_mix {
+      be_mix = format!("{be_mix}        {:?}: {}\n", t, weight);
     }
+    let setup = format!("SETUP:\n    THREADS: {},\n    CONNECTIONS PER THREAD: {},\n    NUM TASKS: {}\n    BE:LC RATIO: {}\n    LC WRITE:READ RATIO: {}\n    BE MIX:\n{be_mix}    LC WRITE MIX:\n{write_mix}{}\n",
+        self.num_threads, self.conns_per_thr, self.workload, self.be_lc_ratio, self.lc_write_read_ratio, self.options.report());
+    // every response recorded a latency, errors included
+    let completed: u64 = stat_map.values().map(Histogram::len).sum();
+    let throughput = format!("THROUGHPUT: {} TASKS / {} SECONDS = {} TASKS PER SECOND\n\n", completed, total_secs, completed as f32 / total_secs);
+    format!("{header}{setup}{throughput}")
   }
 }
 
+// Per-thread share of the bench settings
+struct ThreadConfig {
+  workload: usize,
+  be_prob: f32,
+  wr_lc_prob: f32,
+  conns_per_thr: usize,
+  verify_conns: Option<usize>, // total connections in the bench when verifying
+  options: Arc<ClientOptions>,
+  write_mix: Vec<(RequestType, f32)>,
+  be_mix: Vec<(RequestType, f32)>,
+}
+
 struct ClientThread {
   connections: Vec<Connection>,
   latencies: HashMap<ResponseType, Vec<u128>>,
+  first_chunks: HashMap<ResponseType, Vec<u128>>,
   remaining_work: usize,
   be_prob: f32,
   wr_lc_prob: f32,
-  req_id: Arc<AtomicU64>
+  req_id: Arc<AtomicU64>,
+  outcomes: Outcomes,
+  traffic: Traffic,
+  breakdown: LatencyBreakdown,
+  write_mix: Vec<(RequestType, f32)>,
+  be_mix: Vec<(RequestType, f32)>,
+  requests: RequestOptions,
 }
 
 impl ClientThread {
-  fn init(port: usize, workload: usize, be_prob: f32, conns_per_thr: usize, wr_lc_prob: f32, req_id: Arc<AtomicU64>) -> Self {
+  fn init(port: usize, thread_idx: usize, req_id: Arc<AtomicU64>, config: ThreadConfig) -> Self {
     let mut conns: Vec<Connection> = Vec::new();
-    for _ in 0..conns_per_thr {
-      conns.push(Connection::new(format!("127.0.0.1:{port}").as_str()).unwrap());
+    for c in 0..config.conns_per_thr {
+      // with verification on, every connection in the bench owns a disjoint key range
+      let conn = thread_idx * config.conns_per_thr + c;
+      let verifier = config.verify_conns.map(|num_conns| Verifier::new(conn, num_conns));
+      let tagger = config.options.tagger(conn);
+      conns.push(Connection::new(format!("127.0.0.1:{port}").as_str(), config.options.clone(), tagger, verifier).unwrap());
     }
 
     let mut latencies: HashMap<ResponseType, Vec<u128>> = HashMap::new();
+    let mut first_chunks: HashMap<ResponseType, Vec<u128>> = HashMap::new();
     for t in ResponseType::iterator() {
       latencies.insert(t, Vec::new());
+      first_chunks.insert(t, Vec::new());
     }
 
     ClientThread {
       connections: conns,
       latencies,
-      remaining_work: workload,
-      be_prob,
-      wr_lc_prob,
-      req_id
+      first_chunks,
+      remaining_work: config.workload,
+      be_prob: config.be_prob,
+      wr_lc_prob: config.wr_lc_prob,
+      req_id,
+      outcomes: Outcomes::default(),
+      traffic: Traffic::default(),
+      breakdown: LatencyBreakdown::default(),
+      write_mix: config.write_mix,
+      be_mix: config.be_mix,
+      requests: config.options.requests,
     }
   }
 
-  fn generate_random_request(&mut self) -> Request {
+  fn generate_random_request(&mut self, conn: usize) -> Request {
     let req_id = self.req_id.load(Ordering::Relaxed);
     self.req_id.fetch_add(1, Ordering::Relaxed);
     let be_rat: f32 = rand::rng().random();
     let wr_rat: f32 = rand::rng().random();
-    if be_rat <= self.be_prob {
-      Request::random(RequestType::BeRead, req_id)
+    let kind = if be_rat <= self.be_prob {
+      pick_weighted(&self.be_mix)
     } else if wr_rat <= self.wr_lc_prob {
-      Request::random(RequestType::LcWrite, req_id)
+      pick_weighted(&self.write_mix)
     } else {
-      Request::random(RequestType::LcRead, req_id)
-    }
+      RequestType::LcRead
+    };
+    let kind = match kind {
+      RequestType::LcRead if self.requests.batch_size > 1 => RequestType::MultiGet,
+      RequestType::LcWrite if self.requests.batch_size > 1 => RequestType::MultiPut,
+      kind => kind,
+    };
+    let connection = &self.connections[conn];
+    let keys = match &connection.verifier {
+      Some(verifier) => verifier.keys(),
+      None => 0..CAPACITY as u64,
+    };
+    let mut req = Request::random_in(kind, req_id, keys, &self.requests);
+    connection.seen.aim(&mut req, connection.verifier.as_ref());
+    req
   }
 
   fn send_packets(mut self) -> Result<Self, AspenRsError> {
-    let mut tasks_pending: usize = 0;
+    let mut tasks_pending: usize = 0; // taken from the workload and not yet answered, timed out or dropped
     let mut i = 0;
     let num_conns = self.connections.len();
+    let mut dead_conns = 0;
     while self.remaining_work > 0 || tasks_pending > 0 {
       
       // println!("Chose connection at {} with status {:?}", conn.stream.local_addr().unwrap().port(), conn.status);
@@ -215,7 +255,8 @@ impl ClientThread {
         let conn = &mut self.connections[i];
         if conn.status.kind() == ConnStateType::Ready && self.remaining_work > 0 {
           self.remaining_work -= 1;
-          Some(self.generate_random_request())
+          tasks_pending += 1;
+          Some(self.generate_random_request(i))
         } else {
           None
         }
@@ -223,68 +264,189 @@ impl ClientThread {
 
       let conn = &mut self.connections[i];
 
-      match conn.progress(req)? {
-        Progress::CompletedResponse(res_type, latency) => {
+      let progress = match conn.progress(req) {
+        Err(e) if is_corrupt_frame(&e) => {
+          conn.outcomes.corrupt_frames += 1;
+          Progress::Disconnected(true)
+        },
+        Err(AspenRsError::NetworkError(e)) => {
+          eprintln!("Connection to {} failed: {e}", conn.addr);
+          Progress::Disconnected(conn.in_flight())
+        },
+        progress => progress?,
+      };
+      match progress {
+        Progress::CompletedResponse(res_type, latency, first_chunk) => {
           self.latencies.get_mut(&res_type).unwrap().push(latency);
+          if let Some(first_chunk) = first_chunk {
+            self.first_chunks.get_mut(&res_type).unwrap().push(first_chunk);
+          }
           tasks_pending -= 1;
         }
-        Progress::ConnectionReset(in_flight) => {
-          conn.reconnect()?;
+        Progress::TimedOut | Progress::Expired => {
+          tasks_pending -= 1;
+        }
+        Progress::Disconnected(in_flight) => {
+          dead_conns += usize::from(!conn.restart());
+          tasks_pending -= 1;
           if in_flight {
-            tasks_pending -= 1;
+            conn.outcomes.drops += 1;
+          } else {
+            // nothing of the request reached the server, so it is sent again on the new connection
+            self.remaining_work += 1;
           }
         },
-        Progress::SentRequest => {
-          tasks_pending += 1;
-        }
+        Progress::WriteTimedOut => {
+          // the rest of the request would follow whatever is sent next, so the stream is started over
+          dead_conns += usize::from(!conn.restart());
+          tasks_pending -= 1;
+        },
         _ => {},
       }
+      if dead_conns == num_conns {
+        // no connection is left to send the rest of the workload on
+        self.outcomes.drops += self.remaining_work as u64;
+        self.remaining_work = 0;
+      }
       i = (i + 1) % num_conns;
     }
+
+    for conn in &self.connections {
+      self.outcomes.merge(&conn.outcomes);
+      self.traffic.merge(&conn.traffic());
+      self.breakdown.merge(&conn.breakdown);
+    }
     Ok(self)
   }
 }
 
+// positive weights in request type order, so reports list them consistently
+fn weighted_mix(mix: HashMap<RequestType, f32>) -> Vec<(RequestType, f32)> {
+  RequestType::iterator()
+    .filter_map(|t| mix.get(&t).map(|weight| (t, *weight)))
+    .filter(|(_, weight)| *weight > 0.0)
+    .collect()
+}
+
+fn pick_weighted(mix: &[(RequestType, f32)]) -> RequestType {
+  let total: f32 = mix.iter().map(|(_, weight)| weight).sum();
+  let mut pick = rand::rng().random_range(0.0..total);
+  for (t, weight) in mix {
+    if pick < *weight {
+      return *t;
+    }
+    pick -= weight;
+  }
+  mix.last().unwrap().0
+}
+
 pub enum Progress {
   WouldBlock,
   MadeProgress,
   SentRequest,
-  CompletedResponse(ResponseType, u128),
-  ConnectionReset(bool), // in flight?
+  CompletedResponse(ResponseType, u128, Option<u128>), // latency to the last and, if streamed, the first chunk
+  TimedOut,
+  WriteTimedOut, // the server stopped reading before the request was written
+  Expired, // dropped by the server with its deadline passed
+  Disconnected(bool), // reset or closed by peer, in flight?
   Idle
 }
 struct Connection {
   stream: TcpStream,
-  status: ConnectionStatus
+  addr: SocketAddr,
+  status: ConnectionStatus,
+  options: Arc<ClientOptions>, // offered again on every reconnect
+  negotiated: Negotiated,
+  tagger: Option<Tagger>,
+  decoder: FrameDecoder,
+  write_buf: Vec<u8>, // the request being written, the buffer is reused for the next one
+  sent: FrameStats,
+  timed_out: HashSet<u64>,
+  outcomes: Outcomes,
+  breakdown: LatencyBreakdown,
+  verifier: Option<Verifier>,
+  seen: SeenValues,
 }
 
 impl Connection {
-  fn new(addr: &str) -> Result<Self, NetworkError> {
-    let stream = TcpStream::connect(addr)?;
-    stream.set_nonblocking(true)?;
+  fn new(addr: &str, options: Arc<ClientOptions>, tagger: Option<Tagger>, verifier: Option<Verifier>) -> Result<Self, AspenRsError> {
+    // besides the options, the connection offers batches and streaming. Requests are sent one at a time,
+    // so unlike the open loop it does not offer pipelining
+    let mut decoder = FrameDecoder::new(FrameLimits::responses());
+    let (stream, negotiated) = options.connect(addr, Features::NONE, &mut decoder)?;
+    let addr = stream.peer_addr().map_err(NetworkError::from)?;
     Ok(Connection { 
       stream, 
-      status: ConnectionStatus::Ready 
+      addr,
+      status: ConnectionStatus::Ready,
+      options,
+      negotiated,
+      tagger,
+      decoder,
+      write_buf: Vec::new(),
+      sent: FrameStats::default(),
+      timed_out: HashSet::new(),
+      outcomes: Outcomes::default(),
+      breakdown: LatencyBreakdown::default(),
+      verifier,
+      seen: SeenValues::default(),
     })
   }
 
-  fn reconnect(&mut self) -> Result<(), NetworkError> {
-    let stream = TcpStream::connect(self.stream.local_addr()?)?;
-    stream.set_nonblocking(true)?;
-    self.stream = stream;
+  fn traffic(&self) -> Traffic {
+    Traffic { sent: self.sent, received: self.decoder.stats() }
+  }
+
+  fn reconnect(&mut self) -> Result<(), AspenRsError> {
+    (self.stream, self.negotiated) = self.options.reconnect(self.addr, Features::NONE, &mut self.decoder)?;
+    self.outcomes.reconnects += 1;
     self.status = ConnectionStatus::Ready;
+    self.timed_out = HashSet::new();
+    self.seen.abandon_all();
+    if let Some(verifier) = &mut self.verifier {
+      verifier.forget_all();
+    }
     Ok(())
   }
 
+  // reconnect, or give up on the connection if the server cannot be reached again so the thread carries on
+  // with its other connections. Returns whether the connection is still usable
+  fn restart(&mut self) -> bool {
+    if let Err(e) = self.reconnect() {
+      eprintln!("Giving up on connection to {}: {e}", self.addr);
+      self.status = ConnectionStatus::Dead;
+      return false;
+    }
+    true
+  }
+
+  // whether the server may have received any of the request being sent
+  fn in_flight(&self) -> bool {
+    match &self.status {
+      ConnectionStatus::WritingRequest { start_time, .. } => start_time.is_some(),
+      ConnectionStatus::ReadingResponse { .. } => true,
+      ConnectionStatus::Ready | ConnectionStatus::Dead => false,
+    }
+  }
+
   fn progress(&mut self, req: Option<Request>) -> Result<Progress, AspenRsError> {
     match &mut self.status {
+        ConnectionStatus::Dead => Ok(Progress::Idle),
         ConnectionStatus::Ready => {
           match req {
             Some(req) => {
+              if let Some(verifier) = &mut self.verifier {
+                verifier.track(&req);
+              }
+              self.seen.sent(&req);
+              let stamp = self.negotiated.stamp(self.tagger.as_ref(), req.kind());
+              encode_request(&req, &mut self.write_buf, &self.negotiated, stamp, &mut self.sent);
               self.status = ConnectionStatus::WritingRequest { 
                 req: req.kind(), 
+                req_id: req.req_id(),
+                tags: stamp.tags,
+                encoded_time: Instant::now(),
                 start_time: None, 
-                write_buf: req.serialize(), 
                 offset: 0 
               };
               Ok(Progress::MadeProgress)
@@ -292,9 +454,20 @@ impl Connection {
             None => Ok(Progress::Idle), 
           }
         },
-        ConnectionStatus::WritingRequest { req, start_time, write_buf, offset } => {
-          let req_bytes = write_buf.len();
-          match self.stream.write(&write_buf[*offset..req_bytes]) {
+        ConnectionStatus::WritingRequest { req, req_id, tags, encoded_time, start_time, offset } => {
+          // a request the server stops reading is timed out like one it never answers, counted from its
+          // first byte or, when not even that went out, from being encoded
+          if self.options.timeout.is_some_and(|timeout| start_time.unwrap_or(*encoded_time).elapsed() > timeout) {
+            let req_id = *req_id;
+            self.outcomes.record_timeout(ResponseType::from_request(*req));
+            self.seen.abandon(req_id);
+            if let Some(verifier) = &mut self.verifier {
+              verifier.forget(req_id);
+            }
+            return Ok(Progress::WriteTimedOut);
+          }
+          let req_bytes = self.write_buf.len();
+          match self.stream.write(&self.write_buf[*offset..req_bytes]) {
             Ok(bytes_written) => {
               let was_started = start_time.is_some();
               if !was_started {
@@ -303,9 +476,10 @@ impl Connection {
               if bytes_written + *offset == req_bytes {
                 self.status = ConnectionStatus::ReadingResponse { 
                   exp_type: ResponseType::from_request(*req), 
+                  req_id: *req_id,
+                  tags: *tags,
                   start_time: (*start_time).unwrap(), 
-                  read_buf: Vec::new(), 
-                  expected_len: None  
+                  stream: Stream::default(),
                 };
               } else {
                 *offset += bytes_written;
@@ -316,54 +490,97 @@ impl Connection {
               Ok(Progress::SentRequest)
             },
             Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Progress::WouldBlock),
-            Err(e) if e.kind() == ErrorKind::ConnectionReset => Ok(Progress::ConnectionReset(start_time.is_some())),
+            Err(e) if is_disconnect(&e) => Ok(Progress::Disconnected(start_time.is_some())),
             Err(e) => Err(AspenRsError::NetworkError(NetworkError::from(e)))
           }
         },
-        ConnectionStatus::ReadingResponse { exp_type, start_time, read_buf, expected_len } => {
+        ConnectionStatus::ReadingResponse { exp_type, req_id, tags, start_time, .. } => {
+          let (exp_type, req_id, tags, start_time) = (*exp_type, *req_id, *tags, *start_time);
+          if self.options.timeout.is_some_and(|timeout| start_time.elapsed() > timeout) {
+            // a late response is recognized by its req_id and discarded
+            self.outcomes.record_timeout(exp_type);
+            self.timed_out.insert(req_id);
+            self.seen.abandon(req_id);
+            if let Some(verifier) = &mut self.verifier {
+              verifier.forget(req_id);
+            }
+            self.status = ConnectionStatus::Ready;
+            return Ok(Progress::TimedOut);
+          }
+
           let mut buf = [0; BUF_LEN];
-          let check_type = read_buf.is_empty();
           match self.stream.read(&mut buf) {
             Ok(bytes_read) => {
               if bytes_read > 0 {
-                read_buf.extend_from_slice(&buf[0..bytes_read]);
+                self.decoder.push(&buf[0..bytes_read]);
               } else {
-                return Err(AspenRsError::NetworkError(NetworkError::ConnectionClosed));
-              }
-    
-              let packet_type = ResponseType::from_value(*read_buf.first().unwrap())?;
-              
-              // TODO: Add Drop packet handling
-              if check_type && *exp_type != packet_type {
-                return Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType{ exp_type: *exp_type, given_type: packet_type }));
+                return Ok(Progress::Disconnected(true));
               }
     
-              if expected_len.is_none() {
-                if read_buf.len() < (1 + LEN_LENGTH) {
-                  return Ok(Progress::MadeProgress);
+              while let Some(frame) = self.decoder.next_frame().map_err(AspenRsError::ParseError)? {
+                let server_timing = frame_server_timing(frame, self.negotiated.layout);
+                let res = ResponseView::decode_with(frame, self.negotiated.layout, self.options.utf8).map_err(AspenRsError::ParseError)?;
+                if self.timed_out.contains(&res.req_id()) {
+                  // a late stream is counted once, when the response ending it arrives
+                  if res.kind() != ResponseType::Chunk {
+                    self.timed_out.remove(&res.req_id());
+                    self.outcomes.late_responses += 1;
+                  }
+                  continue;
+                }
+                if res.req_id() != req_id {
+                  return Err(AspenRsError::InternalError(format!("expected response for request {req_id} but got {}", res.req_id())));
+                }
+                let ConnectionStatus::ReadingResponse { stream, .. } = &mut self.status else {
+                  unreachable!("status changed while reading");
+                };
+                if let ResponseView::Chunk { entries, .. } = res {
+                  stream.push(start_time, entries);
+                  continue;
+                }
+                self.seen.received(&res);
+                let stream = std::mem::take(stream);
+                let first_chunk = stream.first_chunk;
+                let kind = res.kind();
+                if let ResponseView::Drop { queued_micros, .. } = res {
+                  self.outcomes.record_expired(exp_type, queued_micros);
+                  if let Some(verifier) = &mut self.verifier {
+                    verifier.untrack(req_id);
+                  }
+                  self.status = ConnectionStatus::Ready;
+                  return Ok(Progress::Expired);
+                }
+                if let ResponseView::Error { code, .. } = &res {
+                  // the server may refuse any request, which says nothing about the keys it touched
+                  self.outcomes.record_error(*code);
+                  if let Some(verifier) = &mut self.verifier {
+                    verifier.forget(req_id);
+                  }
+                } else if kind != exp_type {
+                  return Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType{ exp_type, given_type: kind }));
+                } else if stream.is_streamed() || self.verifier.is_some() {
+                  // only streamed or verified responses are copied out of the buffer
+                  let res = stream.reassemble(res.into_owned())?;
+                  if let Some(verifier) = &mut self.verifier
+                    && let Err(mismatch) = verifier.check(&res) {
+                    self.outcomes.record_mismatch(mismatch);
+                  }
                 }
-    
-                let len_arr: [u8; 8] = read_buf[1..(1+LEN_LENGTH)].try_into().unwrap();
-                *expected_len = Some(usize::from_be_bytes(len_arr));
-              }
-    
-              let total_exp_len = 1 + LEN_LENGTH + expected_len.expect("Should not be None based on previous checks");
-    
-              if read_buf.len() < total_exp_len {
-                Ok(Progress::MadeProgress)
-              } else if read_buf.len() == total_exp_len {
-                let _res = Response::deserialize(read_buf).map_err(AspenRsError::ParseError)?;
-                // optional check for response
                 let latency = start_time.elapsed().as_micros();
+                if let Some(tags) = tags {
+                  self.breakdown.record_tags(tags, latency);
+                }
+                if let Some(timing) = server_timing {
+                  self.breakdown.record_server(kind, latency, timing);
+                }
                 self.status = ConnectionStatus::Ready;
-                // println!("Response {:?} received from {} in {} µs", _res, self.stream.local_addr().unwrap(), latency);
-                return Ok(Progress::CompletedResponse(packet_type, latency));
-              } else {
-                return Err(AspenRsError::ParseError(ParseError::UnexpectedLength { payload_len: read_buf.len(), exp_len: total_exp_len }));
+                // println!("Response {:?} received from {} in {} µs", res, self.stream.local_addr().unwrap(), latency);
+                return Ok(Progress::CompletedResponse(kind, latency, first_chunk));
               }
+              Ok(Progress::MadeProgress)
             },
             Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Progress::WouldBlock),
-            Err(e) if e.kind() == ErrorKind::ConnectionReset => Ok(Progress::ConnectionReset(true)),
+            Err(e) if is_disconnect(&e) => Ok(Progress::Disconnected(true)),
             Err(e) => Err(AspenRsError::NetworkError(NetworkError::from(e)))
           }   
         },
@@ -376,16 +593,20 @@ enum ConnectionStatus {
   Ready,
   WritingRequest {
       req: RequestType,
+      req_id: u64,
+      tags: Option<Tags>,
+      encoded_time: Instant,
       start_time: Option<Instant>,
-      write_buf: Vec<u8>,
       offset: usize, // start writing at this value
   },
   ReadingResponse {
       exp_type: ResponseType,
+      req_id: u64,
+      tags: Option<Tags>,
       start_time: Instant,
-      read_buf: Vec<u8>,
-      expected_len: Option<usize>,
-  }
+      stream: Stream,
+  },
+  Dead, // could not be reconnected, takes no more requests
 }
 
 impl ConnectionStatus {
@@ -394,6 +615,7 @@ impl ConnectionStatus {
         ConnectionStatus::Ready => ConnStateType::Ready,
         ConnectionStatus::WritingRequest { .. } => ConnStateType::WritingRequest,
         ConnectionStatus::ReadingResponse { .. } => ConnStateType::ReadingResponse,
+        ConnectionStatus::Dead => ConnStateType::Dead,
     }
   }
 }
@@ -402,5 +624,6 @@ impl ConnectionStatus {
 enum ConnStateType {
   Ready,
   WritingRequest,
-  ReadingResponse
-}
\ No newline at end of file
+  ReadingResponse,
+  Dead,
+}
@@ -1,2 +1,803 @@
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::{self, ErrorKind, Read, Write}, net::{SocketAddr, TcpStream, ToSocketAddrs}, thread, time::{Duration, Instant}};
+
+use hdrhistogram::Histogram;
+use rand::Rng;
+
+use crate::{AspenRsError, BUF_LEN, MISMATCH_LOG_LEN, NetworkError, PROTOCOL_VERSION, ParseError, RECONNECT_BACKOFF_MILLIS, RECONNECT_RETRIES, SEEN_VALUES_LEN, SIG_FIG, packet::{BeResults, ErrorCode, Features, FrameDecoder, FrameLimits, FrameStats, Layout, ListView, Message, MessageType, Priority, Request, RequestOptions, RequestType, Response, ResponseType, ResponseView, Stamp, ServerTiming, Tags, Timing, Utf8Mode, append_checksum}};
+use verify::Verifier;
+
 pub mod closed;
-pub mod open;
\ No newline at end of file
+pub mod open;
+pub mod verify;
+
+// Requests that did not complete normally during a run
+#[derive(Default, Debug)]
+pub struct Outcomes {
+  pub timeouts: HashMap<ResponseType, u64>,
+  pub expired: HashMap<ResponseType, u64>, // dropped by the server with their deadline passed
+  pub expired_queued_micros: u64, // summed over expired requests, as reported by the server
+  pub late_responses: u64,
+  pub cancelled: u64, // timed out and aborted by the server instead of answered late
+  pub outstanding: u64,
+  pub drops: u64,
+  pub reconnects: u64,
+  pub corrupt_frames: u64, // failed their checksum, each one ends its connection
+  pub mismatches: u64,
+  pub mismatch_log: Vec<String>,
+  pub errors: HashMap<ErrorCode, u64>,
+}
+
+impl Outcomes {
+  pub fn record_timeout(&mut self, kind: ResponseType) {
+    *self.timeouts.entry(kind).or_insert(0) += 1;
+  }
+
+  pub fn record_expired(&mut self, kind: ResponseType, queued_micros: u64) {
+    *self.expired.entry(kind).or_insert(0) += 1;
+    self.expired_queued_micros += queued_micros;
+  }
+
+  pub fn record_mismatch(&mut self, mismatch: String) {
+    self.mismatches += 1;
+    if self.mismatch_log.len() < MISMATCH_LOG_LEN {
+      self.mismatch_log.push(mismatch);
+    }
+  }
+
+  pub fn record_error(&mut self, code: ErrorCode) {
+    *self.errors.entry(code).or_insert(0) += 1;
+  }
+
+  pub fn total_timeouts(&self) -> u64 {
+    self.timeouts.values().sum()
+  }
+
+  pub fn total_expired(&self) -> u64 {
+    self.expired.values().sum()
+  }
+
+  pub fn merge(&mut self, other: &Outcomes) {
+    for (kind, count) in &other.timeouts {
+      *self.timeouts.entry(*kind).or_insert(0) += count;
+    }
+    for (kind, count) in &other.expired {
+      *self.expired.entry(*kind).or_insert(0) += count;
+    }
+    self.expired_queued_micros += other.expired_queued_micros;
+    for (code, count) in &other.errors {
+      *self.errors.entry(*code).or_insert(0) += count;
+    }
+    self.late_responses += other.late_responses;
+    self.cancelled += other.cancelled;
+    self.outstanding += other.outstanding;
+    self.drops += other.drops;
+    self.reconnects += other.reconnects;
+    self.corrupt_frames += other.corrupt_frames;
+    self.mismatches += other.mismatches;
+    for mismatch in &other.mismatch_log {
+      if self.mismatch_log.len() < MISMATCH_LOG_LEN {
+        self.mismatch_log.push(mismatch.clone());
+      }
+    }
+  }
+
+  pub fn report(&self) -> String {
+    let mut timeouts = String::new();
+    for t in ResponseType::iterator() {
+      if let Some(count) = self.timeouts.get(&t) {
+        timeouts = format!("{timeouts}        {:?}: {}\n", t, count);
+      }
+    }
+    let mut expired = String::new();
+    for t in ResponseType::iterator() {
+      if let Some(count) = self.expired.get(&t) {
+        expired = format!("{expired}        {:?}: {}\n", t, count);
+      }
+    }
+    let mut errors = String::new();
+    for code in ErrorCode::iterator() {
+      if let Some(count) = self.errors.get(&code) {
+        errors = format!("{errors}        {:?}: {}\n", code, count);
+      }
+    }
+    let mut mismatches = String::new();
+    for mismatch in &self.mismatch_log {
+      mismatches = format!("{mismatches}        {mismatch}\n");
+    }
+    let expired_queued = self.expired_queued_micros / self.total_expired().max(1);
+    format!("OUTCOMES:\n    TIMED OUT: {}\n{timeouts}    EXPIRED ON SERVER: {} (MEAN QUEUEING {} µs)\n{expired}    LATE RESPONSES DISCARDED: {}\n    CANCELLED ON SERVER: {}\n    OUTSTANDING AT END OF RUN: {}\n    DROPPED ON RECONNECT: {}\n    RECONNECTS: {}\n    CORRUPT FRAMES: {}\n    SERVER ERRORS: {}\n{errors}    VERIFICATION MISMATCHES: {}\n{mismatches}\n",
+      self.total_timeouts(), self.total_expired(), expired_queued, self.late_responses, self.cancelled, self.outstanding, self.drops, self.reconnects, self.corrupt_frames, self.errors.values().sum::<u64>(), self.mismatches)
+  }
+}
+
+// Frames sent and received over every connection of a run, before compression and on the wire
+#[derive(Default, Debug)]
+pub struct Traffic {
+  pub sent: FrameStats,
+  pub received: FrameStats,
+}
+
+impl Traffic {
+  pub fn merge(&mut self, other: &Traffic) {
+    self.sent.merge(&other.sent);
+    self.received.merge(&other.received);
+  }
+
+  pub fn report(&self) -> String {
+    let direction = |name: &str, stats: &FrameStats| format!("    {name}: {} FRAMES ({} COMPRESSED), {} BYTES, {} ON THE WIRE ({:.3} OF THE ORIGINAL)\n",
+      stats.frames, stats.compressed, stats.bytes, stats.wire_bytes, stats.wire_bytes as f64 / stats.bytes.max(1) as f64);
+    format!("TRAFFIC:\n{}{}\n", direction("SENT", &self.sent), direction("RECEIVED", &self.received))
+  }
+}
+
+// The server's counters at the start and at the end of a run
+#[derive(Default, Debug)]
+pub struct ServerSnapshots {
+  pub start: Vec<(String, u64)>,
+  pub end: Vec<(String, u64)>,
+}
+
+impl ServerSnapshots {
+  // by the server's names, with how much each changed over the run. Gauges such as open_connections are
+  // only as of the snapshot
+  pub fn report(&self) -> String {
+    let start: HashMap<&str, u64> = self.start.iter().map(|(name, value)| (name.as_str(), *value)).collect();
+    let change = |name: &str| {
+      let end = self.end.iter().find(|(end_name, _)| end_name == name).map_or(0, |(_, value)| *value);
+      end as i128 - start.get(name).copied().unwrap_or(0) as i128
+    };
+    // the share of the run the executor threads spent polling workers
+    let threads = self.end.iter().find(|(name, _)| name == "executor_threads").map_or(0, |(_, value)| *value);
+    let utilization = change("busy_nanos") as f64 / (change("uptime_micros") as f64 * 1000.0 * threads as f64).max(1.0);
+    let gauge = |counters: &[(String, u64)], name: &str| counters.iter().find(|(gauge_name, _)| gauge_name == name).map_or(0, |(_, value)| *value);
+    let queue_depth = format!("    QUEUE DEPTH: {} -> {} BYTES RECEIVED AND NOT YET SERVED\n", gauge(&self.start, "queued_bytes"), gauge(&self.end, "queued_bytes"));
+    let mut counters = String::new();
+    for (name, value) in &self.end {
+      counters = format!("{counters}    {name}: {} -> {value} ({:+})\n", start.get(name.as_str()).copied().unwrap_or(0), change(name));
+    }
+    format!("SERVER STATS:\n    EXECUTOR UTILIZATION: {utilization:.3}\n{queue_depth}{counters}\n")
+  }
+}
+
+// Latencies split other than by type alone: those of tagged requests by tenant and by priority, and those
+// of responses with a server timing trailer into the parts spent on the server and the rest
+#[derive(Default, Debug)]
+pub struct LatencyBreakdown {
+  pub by_tenant: BTreeMap<u64, Histogram<u64>>,
+  pub by_priority: BTreeMap<Priority, Histogram<u64>>,
+  pub by_component: HashMap<ResponseType, LatencyComponents>,
+}
+
+impl LatencyBreakdown {
+  pub fn record_tags(&mut self, tags: Tags, latency: u128) {
+    let _ = self.by_tenant.entry(tags.tenant).or_insert_with(new_hist).record(latency as u64);
+    let _ = self.by_priority.entry(tags.priority).or_insert_with(new_hist).record(latency as u64);
+  }
+
+  pub fn record_server(&mut self, kind: ResponseType, latency: u128, timing: ServerTiming) {
+    self.by_component.entry(kind).or_default().record(latency as u64, timing);
+  }
+
+  pub fn merge(&mut self, other: &LatencyBreakdown) {
+    for (tenant, hist) in &other.by_tenant {
+      let _ = self.by_tenant.entry(*tenant).or_insert_with(|| Histogram::new_from(hist)).add(hist);
+    }
+    for (priority, hist) in &other.by_priority {
+      let _ = self.by_priority.entry(*priority).or_insert_with(|| Histogram::new_from(hist)).add(hist);
+    }
+    for (kind, components) in &other.by_component {
+      self.by_component.entry(*kind).or_default().merge(components);
+    }
+  }
+
+  pub fn report(&self) -> String {
+    let mut stats = String::new();
+    for priority in Priority::iterator() {
+      if let Some(hist) = self.by_priority.get(&priority) {
+        stats = format!("{stats}{}", latency_stats(format!("{:?} PRIORITY STATS", priority), hist));
+      }
+    }
+    for (tenant, hist) in &self.by_tenant {
+      stats = format!("{stats}{}", latency_stats(format!("TENANT {tenant} STATS"), hist));
+    }
+    for kind in ResponseType::iterator() {
+      if let Some(components) = self.by_component.get(&kind) {
+        stats = format!("{stats}{}", components.report(kind));
+      }
+    }
+    stats
+  }
+}
+
+// Where the latency of one response type went: to the server's queue, to executing the request, and to
+// everything else, the network and both ends' buffers
+#[derive(Debug)]
+pub struct LatencyComponents {
+  pub other: Histogram<u64>,
+  pub queued: Histogram<u64>,
+  pub exec: Histogram<u64>,
+  pub yields: u64,
+}
+
+impl Default for LatencyComponents {
+  fn default() -> Self {
+    LatencyComponents { other: new_hist(), queued: new_hist(), exec: new_hist(), yields: 0 }
+  }
+}
+
+impl LatencyComponents {
+  fn record(&mut self, latency: u64, timing: ServerTiming) {
+    let _ = self.other.record(latency.saturating_sub(timing.queued_micros + timing.exec_micros));
+    let _ = self.queued.record(timing.queued_micros);
+    let _ = self.exec.record(timing.exec_micros);
+    self.yields += timing.yields;
+  }
+
+  fn merge(&mut self, other: &LatencyComponents) {
+    let _ = self.other.add(&other.other);
+    let _ = self.queued.add(&other.queued);
+    let _ = self.exec.add(&other.exec);
+    self.yields += other.yields;
+  }
+
+  fn report(&self, kind: ResponseType) -> String {
+    let component = |name: &str, hist: &Histogram<u64>| format!("     {name}: p50 {} µs, p99 {} µs, MEAN {} µs\n",
+      hist.value_at_quantile(0.5), hist.value_at_quantile(0.99), hist.mean() as u64);
+    format!("{:?} SERVER TIMING:\n     SIZE: {}\n{}{}{}     MEAN YIELDS: {:.2}\n", kind, self.exec.len(), component("QUEUEING", &self.queued),
+      component("EXECUTION", &self.exec), component("NETWORK AND CLIENT", &self.other), self.yields as f64 / self.exec.len().max(1) as f64)
+  }
+}
+
+fn new_hist() -> Histogram<u64> {
+  Histogram::new_with_bounds(1, u64::MAX, SIG_FIG).unwrap()
+}
+
+// Tags a connection puts on its requests: its tenant, and the priority of each request type
+#[derive(Clone, Debug)]
+struct Tagger {
+  tenant: u64,
+  priorities: HashMap<RequestType, Priority>, // Normal for types not listed
+}
+
+impl Tagger {
+  // connection `conn` of the bench, spread round robin over `tenants` tenants
+  fn new(conn: usize, tenants: u64, priorities: &HashMap<RequestType, Priority>) -> Self {
+    Tagger { tenant: conn as u64 % tenants, priorities: priorities.clone() }
+  }
+
+  fn tags(&self, kind: RequestType) -> Tags {
+    Tags { priority: self.priorities.get(&kind).copied().unwrap_or_default(), tenant: self.tenant }
+  }
+}
+
+// optional features every client offers. Without them the server answers batches with an error
+// and sends scans as a single response
+const CLIENT_FEATURES: Features = Features::BATCH_OPS.union(Features::STREAMING);
+
+// Options both benches share: what their requests carry, which features they offer the server and what they
+// check and report besides latencies. A bench takes them whole with its `options` builder
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+  timeout: Option<Duration>,
+  utf8: Utf8Mode,
+  verify: bool,
+  compact: bool,
+  compress_over: Option<usize>,
+  checksums: bool,
+  server_timing: bool,
+  server_stats: bool,
+  deadline: Option<Duration>,
+  tenants: Option<u64>,
+  priorities: HashMap<RequestType, Priority>,
+  requests: RequestOptions,
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    ClientOptions {
+      timeout: None,
+      utf8: Utf8Mode::Lossy,
+      verify: false,
+      compact: false,
+      compress_over: None,
+      checksums: false,
+      server_timing: false,
+      server_stats: false,
+      deadline: None,
+      tenants: None,
+      priorities: HashMap::new(),
+      requests: RequestOptions::default(),
+    }
+  }
+}
+
+impl ClientOptions {
+  // requests without a response after this long are counted as timed out. The open loop also cancels them if
+  // the server agrees to it, so it does not finish scans no one waits for
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  // how text in responses (error messages and Hello rejections) that is not valid UTF-8 is decoded. Strict
+  // ends the run at the first such response instead of reporting it with replacement characters
+  pub fn utf8(mut self, utf8: Utf8Mode) -> Self {
+    self.utf8 = utf8;
+    self
+  }
+
+  // check responses against a shadow model of each connection's own slice of the keyspace
+  pub fn verify(mut self, verify: bool) -> Self {
+    self.verify = verify;
+    self
+  }
+
+  // offer the compact layout, whose varint lengths, req_ids and keys shrink small requests
+  pub fn compact(mut self, compact: bool) -> Self {
+    self.compact = compact;
+    self
+  }
+
+  // offer compression, with request bodies longer than `threshold` bytes compressed once the server agrees.
+  // The server picks its own threshold for responses
+  pub fn compression(mut self, threshold: usize) -> Self {
+    self.compress_over = Some(threshold);
+    self
+  }
+
+  // offer a CRC32C trailer on every frame, so corruption on the way is caught instead of decoded
+  pub fn checksums(mut self, checksums: bool) -> Self {
+    self.checksums = checksums;
+    self
+  }
+
+  // offer a trailer on every response telling how long the server queued and executed its request, so
+  // latencies are broken down into the server's part and the rest
+  pub fn server_timing(mut self, server_timing: bool) -> Self {
+    self.server_timing = server_timing;
+    self
+  }
+
+  // ask the server for its counters before and after the run, so the report shows what changed on its side
+  pub fn server_stats(mut self, server_stats: bool) -> Self {
+    self.server_stats = server_stats;
+    self
+  }
+
+  // offer deadlines, with every request due `deadline` after it is encoded. The server drops requests it
+  // gets to later than that, so they count as expired instead of taking up its time
+  pub fn deadline(mut self, deadline: Duration) -> Self {
+    self.deadline = Some(deadline);
+    self
+  }
+
+  // spread connections round robin over `tenants` tenants, each tagging its requests with its own
+  pub fn tenants(mut self, tenants: u64) -> Self {
+    assert!(tenants > 0, "requests need at least one tenant");
+    self.tenants = Some(tenants);
+    self
+  }
+
+  // tag requests with the priority of their type, Normal for types not listed, so the server can schedule
+  // them by priority instead of type
+  pub fn priorities(mut self, priorities: HashMap<RequestType, Priority>) -> Self {
+    self.priorities = priorities;
+    self
+  }
+
+  // keys carried by each MultiGet and MultiPut. Above 1, the closed loop sends its LC reads and unconditional
+  // writes as such batches
+  pub fn batch_size(mut self, batch_size: usize) -> Self {
+    assert!(batch_size > 0, "batches need at least one key");
+    self.requests.batch_size = batch_size;
+    self
+  }
+
+  // what BeReads return besides the count, with at most `limit` matches when they return any
+  pub fn be_results(mut self, results: BeResults, limit: u64) -> Self {
+    self.requests.be_results = results;
+    self.requests.be_limit = limit;
+    self
+  }
+
+  // offered to the server on top of the bench's own `extra` features
+  fn features(&self, extra: Features) -> Features {
+    let mut features = CLIENT_FEATURES | extra;
+    if self.compact {
+      features = features | Features::COMPACT;
+    }
+    if self.compress_over.is_some() {
+      features = features | Features::COMPRESSION;
+    }
+    if self.checksums {
+      features = features | Features::CHECKSUMS;
+    }
+    if self.server_timing {
+      features = features | Features::SERVER_TIMING;
+    }
+    if self.deadline.is_some() {
+      features = features | Features::DEADLINES | Features::DROP_RESPONSES;
+    }
+    if self.tenants.is_some() || !self.priorities.is_empty() {
+      features = features | Features::TAGS;
+    }
+    features
+  }
+
+  // the tags of connection `conn` of the bench, if requests are tagged at all
+  fn tagger(&self, conn: usize) -> Option<Tagger> {
+    (self.tenants.is_some() || !self.priorities.is_empty()).then(|| Tagger::new(conn, self.tenants.unwrap_or(1), &self.priorities))
+  }
+
+  // open a connection offering the options and `extra`, and set `decoder` up for what the server agreed to
+  fn connect<A: ToSocketAddrs>(&self, addr: A, extra: Features, decoder: &mut FrameDecoder) -> Result<(TcpStream, Negotiated), AspenRsError> {
+    let (stream, agreed) = connect(addr, self.features(extra), self.utf8)?;
+    Ok((stream, self.negotiated(agreed, decoder)))
+  }
+
+  // connect again after the server went away, retrying with backoff. Whatever `decoder` held of the old
+  // connection is dropped, and what was agreed is negotiated anew
+  fn reconnect(&self, addr: SocketAddr, extra: Features, decoder: &mut FrameDecoder) -> Result<(TcpStream, Negotiated), AspenRsError> {
+    let (stream, agreed) = connect_with_backoff(addr, self.features(extra), self.utf8)?;
+    Ok((stream, self.negotiated(agreed, decoder)))
+  }
+
+  fn negotiated(&self, agreed: Features, decoder: &mut FrameDecoder) -> Negotiated {
+    let negotiated = Negotiated {
+      agreed,
+      layout: Layout::negotiated(agreed),
+      compression: self.compress_over.filter(|_| agreed.contains(Features::COMPRESSION)),
+      budget: self.deadline.filter(|_| agreed.contains(Features::DEADLINES | Features::DROP_RESPONSES)),
+    };
+    decoder.clear();
+    decoder.set_layout(negotiated.layout);
+    decoder.set_checksums(negotiated.checksums());
+    decoder.set_timing_trailers(agreed.contains(Features::SERVER_TIMING));
+    decoder.set_utf8(self.utf8);
+    negotiated
+  }
+
+  fn stats_connection(&self, port: usize) -> Option<StatsConnection> {
+    self.server_stats.then(|| StatsConnection::connect(format!("127.0.0.1:{port}"), self.utf8).unwrap())
+  }
+
+  // setup lines of a bench's report
+  fn report(&self) -> String {
+    let mut priorities = String::new();
+    for t in RequestType::iterator() {
+      if let Some(priority) = self.priorities.get(&t) {
+        priorities = format!("{priorities}        {:?}: {:?}\n", t, priority);
+      }
+    }
+    format!("    BATCH SIZE: {}\n    BE RESULTS: {:?} (LIMIT {})\n    TIMEOUT: {:?}\n    VERIFY: {}\n    COMPACT: {}\n    COMPRESS OVER: {:?}\n    CHECKSUMS: {}\n    SERVER TIMING: {}\n    SERVER STATS: {}\n    DEADLINE: {:?}\n    TENANTS: {:?}\n    PRIORITIES:\n{priorities}",
+      self.requests.batch_size, self.requests.be_results, self.requests.be_limit, self.timeout, self.verify, self.compact, self.compress_over, self.checksums, self.server_timing, self.server_stats, self.deadline, self.tenants)
+  }
+}
+
+// What the server agreed to of what a connection offered, negotiated anew on every reconnect
+#[derive(Clone, Copy, Debug)]
+struct Negotiated {
+  agreed: Features,
+  layout: Layout,
+  compression: Option<usize>, // threshold in effect once the server agreed to compression
+  budget: Option<Duration>, // deadline in effect once the server agreed to deadlines
+}
+
+impl Negotiated {
+  fn checksums(&self) -> bool {
+    self.agreed.contains(Features::CHECKSUMS)
+  }
+
+  // the stamp of a request of type `kind` encoded now, tagged by `tagger` if the server agreed to tags
+  fn stamp(&self, tagger: Option<&Tagger>, kind: RequestType) -> Stamp {
+    Stamp {
+      timing: self.budget.map(|budget| Timing::now(Some(budget))),
+      tags: tagger.filter(|_| self.agreed.contains(Features::TAGS)).map(|tagger| tagger.tags(kind)),
+    }
+  }
+}
+
+// Chunks of a streamed response received so far
+#[derive(Clone, Debug, Default)]
+struct Stream {
+  first_chunk: Option<u128>, // µs from sending the request to the first chunk
+  entries: Vec<(u64, Vec<u8>)>,
+}
+
+impl Stream {
+  fn push(&mut self, start_time: Instant, entries: ListView<'_, (u64, &[u8])>) {
+    self.first_chunk.get_or_insert_with(|| start_time.elapsed().as_micros());
+    self.entries.extend(entries.iter().map(|(id, username)| (id, username.to_vec())));
+  }
+
+  fn is_streamed(&self) -> bool {
+    self.first_chunk.is_some()
+  }
+
+  // the response ending the stream carries the last entries, the streamed ones go in front of them
+  fn reassemble(self, res: Response) -> Result<Response, AspenRsError> {
+    if !self.is_streamed() {
+      return Ok(res);
+    }
+    match res {
+      Response::RangeScan { req_id, entries, cursor } => {
+        let mut streamed = self.entries;
+        streamed.extend(entries);
+        Ok(Response::RangeScan { req_id, entries: streamed, cursor })
+      },
+      // the server gave up part way through
+      Response::Error { .. } => Ok(res),
+      res => Err(AspenRsError::InternalError(format!("{:?} cannot end a streamed response", res.kind()))),
+    }
+  }
+}
+
+// Values recent reads returned and updates stored, so a CompareAndSwap can expect one that is
+// likely still there instead of a random one that never matches
+#[derive(Debug, Default)]
+struct SeenValues {
+  pending: HashMap<u64, Request>, // reads and updates in flight, by req_id
+  values: Vec<(u64, Vec<u8>)>,
+}
+
+impl SeenValues {
+  fn sent(&mut self, req: &Request) {
+    if matches!(req, Request::LcRead { .. } | Request::MultiGet { .. } | Request::LcWrite { .. } | Request::MultiPut { .. } | Request::LcCompareAndSwap { .. }) {
+      self.pending.insert(req.req_id(), req.clone());
+    }
+  }
+
+  // the request will not be answered, or its answer will be discarded
+  fn abandon(&mut self, req_id: u64) {
+    self.pending.remove(&req_id);
+  }
+
+  fn abandon_all(&mut self) {
+    self.pending = HashMap::new();
+  }
+
+  fn received(&mut self, res: &ResponseView<'_>) {
+    let Some(req) = self.pending.remove(&res.req_id()) else {
+      return;
+    };
+    match (req, res) {
+      (Request::LcRead { id, .. }, ResponseView::LcRead { username: Some(username), .. }) => self.keep(id, username.to_vec()),
+      (Request::MultiGet { ids, .. }, ResponseView::MultiGet { usernames, .. }) => {
+        for (id, username) in ids.into_iter().zip(usernames.iter()) {
+          if let Some(username) = username {
+            self.keep(id, username.to_vec());
+          }
+        }
+      },
+      (Request::LcWrite { id, username, .. }, ResponseView::LcWrite { .. }) => self.keep(id, username),
+      (Request::MultiPut { entries, .. }, ResponseView::MultiPut { .. }) => {
+        for (id, username) in entries {
+          self.keep(id, username);
+        }
+      },
+      (Request::LcCompareAndSwap { id, username, .. }, ResponseView::LcCompareAndSwap { swapped: true, .. }) => self.keep(id, username),
+      _ => {},
+    }
+  }
+
+  fn keep(&mut self, id: u64, username: Vec<u8>) {
+    if self.values.len() < SEEN_VALUES_LEN {
+      self.values.push((id, username));
+    } else {
+      self.values[rand::rng().random_range(0..SEEN_VALUES_LEN)] = (id, username);
+    }
+  }
+
+  // points a CompareAndSwap at a seen key and the value it held. A verifier knows the value
+  // the connection's later updates have stored since, other connections' updates make it fail
+  fn aim(&self, req: &mut Request, verifier: Option<&Verifier>) {
+    let Request::LcCompareAndSwap { id, expected, .. } = req else {
+      return;
+    };
+    if self.values.is_empty() {
+      return;
+    }
+    let (seen_id, seen) = &self.values[rand::rng().random_range(0..self.values.len())];
+    *id = *seen_id;
+    *expected = verifier.and_then(|verifier| verifier.stored(*seen_id)).unwrap_or(seen).clone();
+  }
+}
+
+// report section for one latency histogram
+fn latency_stats(title: String, hist: &Histogram<u64>) -> String {
+  let title = format!("{title}:\n");
+  let size = format!("     SIZE: {}\n", hist.len());
+
+  let vals = [
+    hist.value_at_quantile(0.5) as f64,
+    hist.value_at_quantile(0.95) as f64,
+    hist.value_at_quantile(0.99) as f64,
+    hist.value_at_quantile(0.999) as f64,
+    hist.mean(),
+    hist.stdev()
+  ];
+
+  let mut val_strs: Vec<String> = Vec::new();
+
+  for val in vals {
+    if val < 1e4 {
+      // micros
+      val_strs.push(format!("{} µs", val as u64));
+    } else if val < 1e6 {
+      // millis
+      val_strs.push(format!("{:.3} ms", (val / 1000.0)));
+    } else {
+      // seconds
+      val_strs.push(format!("{:.6} secs", (val / 1000000.0)));
+    }
+  }
+  
+  let median = format!("     p50 LATENCY: {}\n", val_strs[0]);
+  let p95 = format!("     p95 LATENCY: {}\n", val_strs[1]);
+  let p99 = format!("     p99 LATENCY: {}\n", val_strs[2]);
+  let p999 = format!("     p99.9 LATENCY: {}\n", val_strs[3]);
+  let mean = format!("     MEAN LATENCY: {}\n", val_strs[4]);
+  let stddev = format!("     STD DEV: {}\n", val_strs[5]);
+
+  format!("{title}{size}{median}{p95}{p99}{p999}{mean}{stddev}\n")
+}
+
+// whether the peer went away, in which case the connection should be re-established
+fn is_disconnect(e: &io::Error) -> bool {
+  matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof)
+}
+
+// open a non-blocking connection to the server and negotiate features with it
+fn connect<A: ToSocketAddrs>(addr: A, features: Features, utf8: Utf8Mode) -> Result<(TcpStream, Features), AspenRsError> {
+  let mut stream = TcpStream::connect(addr).map_err(NetworkError::from)?;
+  let features = handshake(&mut stream, features, utf8)?;
+  stream.set_nonblocking(true).map_err(NetworkError::from)?;
+  Ok((stream, features))
+}
+
+// a frame that failed its checksum may have had its length corrupted too, so nothing after it can be framed
+// and its connection is dropped as if the server had closed it
+fn is_corrupt_frame(e: &AspenRsError) -> bool {
+  matches!(e, AspenRsError::ParseError(ParseError::ChecksumMismatch { .. }))
+}
+
+// encode a request in the connection's layout carrying `stamp`, compressed and checksummed if the server
+// agreed to it, and count it as sent
+fn encode_request(req: &Request, buf: &mut Vec<u8>, negotiated: &Negotiated, stamp: Stamp, sent: &mut FrameStats) {
+  buf.clear();
+  let frame_len = req.encode_framed(buf, negotiated.layout, stamp, negotiated.compression);
+  // a frame is only sent compressed if that shrinks it
+  let compressed = buf.len() < frame_len;
+  if negotiated.checksums() {
+    append_checksum(buf, 0);
+  }
+  sent.record(frame_len, buf.len(), compressed);
+}
+
+// connect to the server, retrying with exponential backoff before giving up
+fn connect_with_backoff(addr: SocketAddr, features: Features, utf8: Utf8Mode) -> Result<(TcpStream, Features), AspenRsError> {
+  let mut backoff = Duration::from_millis(RECONNECT_BACKOFF_MILLIS);
+  let mut attempt = 0;
+  loop {
+    match connect(addr, features, utf8) {
+      Err(AspenRsError::NetworkError(_)) if attempt < RECONNECT_RETRIES => {
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+      },
+      res => return res,
+    }
+  }
+}
+
+// send Hello on a blocking stream and wait for the server's answer, returning the negotiated features
+fn handshake(stream: &mut TcpStream, features: Features, utf8: Utf8Mode) -> Result<Features, AspenRsError> {
+  let hello = Request::Hello { req_id: 0, version: PROTOCOL_VERSION, features };
+  stream.write_all(&hello.serialize()).map_err(NetworkError::from)?;
+
+  let mut decoder = FrameDecoder::new(FrameLimits::responses());
+  decoder.set_utf8(utf8);
+  receive_blocking(stream, &mut decoder)?;
+  match decoder.next_response()?.expect("a whole frame was received") {
+    ResponseView::HelloAck { features, .. } => Ok(features),
+    ResponseView::HelloReject { version, reason, .. } => Err(AspenRsError::HandshakeRejected { version, reason: reason.into_owned() }),
+    res => Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType { given_type: res.kind(), exp_type: ResponseType::HelloAck })),
+  }
+}
+
+// read from a blocking stream until the decoder holds a whole frame
+fn receive_blocking(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Result<(), AspenRsError> {
+  let mut buf = [0; BUF_LEN];
+  while !decoder.has_frame()? {
+    let bytes_read = stream.read(&mut buf).map_err(NetworkError::from)?;
+    if bytes_read == 0 {
+      return Err(AspenRsError::NetworkError(NetworkError::ConnectionClosed));
+    }
+    decoder.push(&buf[0..bytes_read]);
+  }
+  Ok(())
+}
+
+// Asks the server for its counters over a connection of its own, so the answers do not wait behind a run's
+// requests. The server leaves the connection out of them
+pub struct StatsConnection {
+  stream: TcpStream,
+  decoder: FrameDecoder,
+  snapshots: u64, // Stats requests sent so far
+}
+
+impl StatsConnection {
+  // `utf8` decodes the counters' names
+  pub fn connect<A: ToSocketAddrs>(addr: A, utf8: Utf8Mode) -> Result<Self, AspenRsError> {
+    let mut stream = TcpStream::connect(addr).map_err(NetworkError::from)?;
+    if !handshake(&mut stream, Features::STATS, utf8)?.contains(Features::STATS) {
+      return Err(AspenRsError::InternalError("server does not offer statistics".to_string()));
+    }
+    let mut decoder = FrameDecoder::new(FrameLimits::responses());
+    decoder.set_utf8(utf8);
+    Ok(StatsConnection { stream, decoder, snapshots: 0 })
+  }
+
+  pub fn snapshot(&mut self) -> Result<Vec<(String, u64)>, AspenRsError> {
+    self.snapshots += 1;
+    self.stream.write_all(&Request::Stats { req_id: self.snapshots }.serialize()).map_err(NetworkError::from)?;
+    receive_blocking(&mut self.stream, &mut self.decoder)?;
+    match self.decoder.next_response()?.expect("a whole frame was received").into_owned() {
+      Response::Stats { counters, .. } => Ok(counters),
+      Response::Error { code, message, .. } => Err(AspenRsError::InternalError(format!("server statistics failed with {code:?}: {message}"))),
+      res => Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType { given_type: res.kind(), exp_type: ResponseType::Stats })),
+    }
+  }
+}
+
+// put a run's report in front of the earlier ones in out/benchmark.txt. `head` is the bench's own header,
+// setup and throughput, followed by the sections both benches report
+#[allow(clippy::too_many_arguments)]
+fn write_report(head: String, outcomes: &Outcomes, traffic: &Traffic, server: Option<&ServerSnapshots>, breakdown: &LatencyBreakdown,
+  stat_map: &HashMap<ResponseType, Histogram<u64>>,
+  first_chunk_map: &HashMap<ResponseType, Histogram<u64>>) {
+  let outcomes = outcomes.report();
+  let traffic = traffic.report();
+  let server = server.map(ServerSnapshots::report).unwrap_or_default();
+
+  let mut stats = String::new();
+  for t in ResponseType::iterator(){
+    let hist = stat_map.get(&t).unwrap();
+    if !hist.is_empty() {
+      stats = format!("{stats}{}", latency_stats(format!("{:?} STATS", t), hist));
+    }
+    // streamed responses also get the latency of their first chunk, the stats above are to the last one
+    let first_hist = first_chunk_map.get(&t).unwrap();
+    if !first_hist.is_empty() {
+      stats = format!("{stats}{}", latency_stats(format!("{:?} FIRST CHUNK STATS", t), first_hist));
+    }
+  }
+  let stats = format!("{stats}{}", breakdown.report());
+
+  // let data = format!("DATA:\n    BE DATA: {:?}\n    LC DATA: {:?}", be_agg, lc_agg);
+  let prev = String::from_utf8_lossy(&fs::read("out/benchmark.txt").unwrap()).to_string();
+  fs::write("out/benchmark.txt", format!("{head}{outcomes}{traffic}{server}{stats}{prev}")).unwrap();
+}
+
+fn latency_by_quant_distr(stat_map: &HashMap<ResponseType, Histogram<u64>>) {
+  for (t, hist) in stat_map {
+    if hist.is_empty() {
+      continue;
+    }
+    let path = format!("{:?}", t).to_lowercase();
+
+    let file = File::open("bench/quantiles.txt").unwrap();
+    let mut rdr = csv::ReaderBuilder::new()
+      .has_headers(true)
+      .from_reader(file);
+    let quantiles: Vec<f64> = rdr.records().map(
+      |s| s.unwrap().get(0).unwrap().to_string().parse::<f64>().unwrap()).collect();
+    
+    let mut hist_data = format!("{:^8}    {:^8}    {:^8}    {:^8.3}\n", "Value", "Quantile", "Agg Count", "1/1-quantile");
+    for quantile in quantiles {
+      hist_data = format!("{hist_data}{:>8}    {:>8}    {:>8}    {:>8.3}\n",
+       hist.value_at_quantile(quantile), quantile, (hist.len() as f64 * quantile) as u64, 1.0 / (1.0 - quantile));
+    }
+    let _ = fs::write(format!("out/{path}.txt"), hist_data);
+  }
+}
@@ -1,43 +1,64 @@
-use std::{collections::{HashMap, VecDeque}, fs::{self, File}, io::{ErrorKind, Read, Write}, net::TcpStream, thread::{self, JoinHandle}, time::Instant};
+use std::{collections::{HashMap, HashSet, VecDeque}, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, sync::Arc, thread::{self, JoinHandle}, time::Instant};
 
 use hdrhistogram::Histogram;
-use rand::Rng;
 use rand_distr::{Distribution, Exp};
-use crate::{AspenRsError, BUF_LEN, LEN_LENGTH, NetworkError, ParseError, SIG_FIG, packet::{Message, MessageType, Request, RequestType, Response, ResponseType}};
+use crate::{AspenRsError, CAPACITY, client::{ClientOptions, LatencyBreakdown, Negotiated, Outcomes, ServerSnapshots, Stream, Tagger, Traffic, encode_request, is_corrupt_frame, is_disconnect, latency_by_quant_distr, write_report, SeenValues, verify::Verifier}, BUF_LEN, LATE_SEND_MICROS, MAX_LATE_FRAC, NetworkError, ParseError, SIG_FIG, packet::{Features, FrameDecoder, FrameLimits, FrameStats, Message, MessageType, Request, RequestOptions, RequestType, ResponseType, ResponseView, ServerTiming, Stamp, Tags, frame_server_timing}};
 
 
 pub struct OpenBench {
-  target_rps: u64,
+  class_rps: HashMap<RequestType, f64>,
   runtime_secs: f32,
-  be_lc_ratio: f32,
-  lc_wr_ratio: f32,
   num_threads: usize,
   conns_per_thr: usize,
+  per_conn_arrivals: bool,
+  options: ClientOptions,
 }
 
 impl OpenBench {
-  pub fn new(target_rps: u64,
+  // each request type gets its own Poisson arrival process; types without a rate are not sent
+  pub fn new(class_rps: HashMap<RequestType, f64>,
     runtime_secs: f32,
-    be_lc_ratio: f32,
-    lc_wr_ratio: f32,
     num_threads: usize,
     conns_per_thr: usize) -> Self {
-    OpenBench { target_rps, runtime_secs, be_lc_ratio, lc_wr_ratio, num_threads, conns_per_thr }
+    OpenBench { class_rps, runtime_secs, num_threads, conns_per_thr, per_conn_arrivals: false, options: ClientOptions::default() }
+  }
+
+  // give every connection its own arrival processes instead of spreading one per-thread stream over them
+  pub fn per_conn_arrivals(mut self, per_conn_arrivals: bool) -> Self {
+    self.per_conn_arrivals = per_conn_arrivals;
+    self
+  }
+
+  // the options shared with the closed loop. Verifying gives each connection its own slice of the keyspace
+  pub fn options(mut self, options: ClientOptions) -> Self {
+    assert!(!options.verify || self.num_threads * self.conns_per_thr <= CAPACITY, "verifying needs at least one key per connection");
+    self.options = options;
+    self
+  }
+
+  fn target_rps(&self) -> f64 {
+    self.class_rps.values().sum()
   }
 
   pub fn run(&self, port: usize) {
     let mut handles: Vec<JoinHandle<ClientThread>> = Vec::new();
     println!("Creating {} client threads", self.num_threads);
+    let options = Arc::new(self.options.clone());
     for i in 0..self.num_threads {
-      let be_prob = self.be_lc_ratio;
       let conns_per_thr = self.conns_per_thr;
-      let lc_wr_prob = self.lc_wr_ratio;
       let shift: u8 = (usize::BITS - self.num_threads.leading_zeros()).try_into().unwrap();
-      let rps = self.target_rps;
+      let config = ThreadConfig {
+        // the target rate is for the whole bench, so each thread offers an equal share
+        class_rps: self.class_rps.iter()
+          .map(|(t, rps)| (*t, rps / self.num_threads as f64))
+          .collect(),
+        per_conn_arrivals: self.per_conn_arrivals,
+        verify_conns: self.options.verify.then_some(self.num_threads * conns_per_thr),
+        options: options.clone(),
+      };
       handles.push(
         thread::spawn(move || {
-          ClientThread::init(port,conns_per_thr,be_prob,
-            lc_wr_prob,i as u64,shift,rps)
+          ClientThread::init(port,conns_per_thr,i as u64,shift,config)
         })
       );
     }
@@ -48,6 +69,8 @@ impl OpenBench {
       client_threads.push(handle.join().unwrap());
     }
 
+    let mut stats = self.options.stats_connection(port);
+    let start_stats = stats.as_mut().map(|stats| stats.snapshot().unwrap());
     println!("Begin sending requests...");
     let mut handles: Vec<JoinHandle<ClientThread>> = Vec::new();
     for thread in client_threads {
@@ -62,211 +85,291 @@ impl OpenBench {
     for handle in handles {
       client_threads.push(handle.join().unwrap());
     }
+    let server = stats.zip(start_stats).map(|(mut stats, start)| ServerSnapshots { start, end: stats.snapshot().unwrap() });
 
     let mut stat_map: HashMap<ResponseType, Histogram<u64>> = HashMap::new();
+    let mut first_chunk_map: HashMap<ResponseType, Histogram<u64>> = HashMap::new();
     for i in ResponseType::iterator() {
       stat_map.insert(i, Histogram::new_with_bounds(1, u64::MAX,SIG_FIG).unwrap());
+      first_chunk_map.insert(i, Histogram::new_with_bounds(1, u64::MAX,SIG_FIG).unwrap());
     }
 
-    let mut drop_count = 0u64;
-    let mut reqs = 0u64;
+    let mut offered = OfferedLoad::default();
+    let mut outcomes = Outcomes::default();
+    let mut traffic = Traffic::default();
+    let mut breakdown = LatencyBreakdown::default();
     for thr in client_threads {
       for (t, l) in thr.latencies {
         let hist = stat_map.get_mut(&t).unwrap();
         l.iter().for_each(|i| {let _ = hist.record(*i as u64);});
       }
+      for (t, l) in thr.first_chunks {
+        let hist = first_chunk_map.get_mut(&t).unwrap();
+        l.iter().for_each(|i| {let _ = hist.record(*i as u64);});
+      }
 
-      drop_count += thr.drop_count;
-      reqs += thr.req_id >> thr.req_id_shift;
+      offered.merge(&thr.offered);
+      outcomes.merge(&thr.outcomes);
+      traffic.merge(&thr.traffic);
+      breakdown.merge(&thr.breakdown);
+    }
 
-      self.general_results(reqs, drop_count, &stat_map);
-      self.latency_by_quant_distr(&stat_map);
-      
-      println!("Completed benchmark!");
+    if !self.kept_up(&offered) {
+      eprintln!("WARNING: client could not keep up with the target load, see out/benchmark.txt");
     }
+
+    let head = self.general_results(&offered, &outcomes);
+    write_report(head, &outcomes, &traffic, server.as_ref(), &breakdown, &stat_map, &first_chunk_map);
+    latency_by_quant_distr(&stat_map);
+
+    println!("Completed benchmark!");
   }
 
-  fn general_results(&self, reqs: u64, drops: u64, stat_map: &HashMap<ResponseType, Histogram<u64>>) {
+  // a class is off target when its send count is more than 3 standard deviations from the Poisson mean
+  fn class_on_target(&self, kind: RequestType, sent: u64) -> bool {
+    let expected = self.class_rps.get(&kind).copied().unwrap_or(0.0) * self.runtime_secs as f64;
+    (sent as f64 - expected).abs() <= 3.0 * expected.sqrt()
+  }
+
+  fn kept_up(&self, offered: &OfferedLoad) -> bool {
+    let late_frac = offered.late_sends as f64 / offered.total().max(1) as f64;
+    late_frac <= MAX_LATE_FRAC && RequestType::iterator().all(|t| self.class_on_target(t, offered.sent_of(t)))
+  }
+
+  // header, setup, client effectiveness and throughput of the report
+  fn general_results(&self, offered: &OfferedLoad, outcomes: &Outcomes) -> String {
     let datetime = chrono::offset::Local::now();
     let header = format!("--- OPEN-LOOP BENCHMARK TEST: {datetime} ---\n");
     
-    let setup = format!("SETUP:\n    THREADS: {},\n    CONNECTIONS PER THREAD: {},\n    TARGET RPS: {}\n    BE:LC RATIO: {}\n    LC WRITE:READ RATIO: {}\n\n",
-        self.num_threads, self.conns_per_thr, self.target_rps, self.be_lc_ratio, self.lc_wr_ratio);
-    let client = format!("CLIENT EFFECTIVENESS:\n    {} REQUESTS SENT / {} SECONDS = {} RPS \n\n",
-      reqs, self.runtime_secs, reqs as f64 / self.runtime_secs as f64);
-    let throughput = format!("THROUGHPUT: ({} REQUESTS SENT - {} REQUESTS DROPPED) / {} SECONDS = {} TASKS PER SECOND\n\n",
-       reqs, drops, self.runtime_secs, (reqs - drops) as f64 / self.runtime_secs as f64);
-
-    let mut stats = String::new();
-    for t in ResponseType::iterator(){
-      let hist = stat_map.get(&t).unwrap();
-      let title = format!("{:?} STATS:\n", t);
-      let size = format!("     SIZE: {}\n", hist.len());
-
-      let vals = [
-        hist.value_at_quantile(0.5) as f64,
-        hist.value_at_quantile(0.95) as f64,
-        hist.value_at_quantile(0.99) as f64,
-        hist.value_at_quantile(0.999) as f64,
-        hist.mean(),
-        hist.stdev()
-      ];
-
-      let mut val_strs: Vec<String> = Vec::new();
-
-      for val in vals {
-        if val < 1e4 {
-          // micros
-          val_strs.push(format!("{} µs", val as u64));
-        } else if val < 1e6 {
-          // millis
-          val_strs.push(format!("{:.3} ms", (val / 1000.0)));
-        } else {
-          // seconds
-          val_strs.push(format!("{:.6} secs", (val / 1000000.0)));
-        }
-      }
-      
-      let median = format!("     p50 LATENCY: {}\n", val_strs[0]);
-      let p95 = format!("     p95 LATENCY: {}\n", val_strs[1]);
-      let p99 = format!("     p99 LATENCY: {}\n", val_strs[2]);
-      let p999 = format!("     p99.9 LATENCY: {}\n", val_strs[3]);
-      let mean = format!("     MEAN LATENCY: {}\n", val_strs[4]);
-      let stddev = format!("     STD DEV: {}\n", val_strs[5]);
-
-      stats = format!("{stats}{title}{size}{median}{p95}{p99}{p999}{mean}{stddev}\n");
+    let mut class_rates = String::new();
+    for t in RequestType::iterator().filter(|t| self.class_rps.contains_key(t)) {
+      let rps = self.class_rps[&t];
+      class_rates = format!("{class_rates}    {:?} TARGET RPS: {}\n", t, rps);
     }
-
-    // let data = format!("DATA:\n    BE DATA: {:?}\n    LC DATA: {:?}", be_agg, lc_agg);
-    let prev = String::from_utf8_lossy(&fs::read("out/benchmark.txt").unwrap()).to_string();
-    fs::write("out/benchmark.txt", format!("{header}{setup}{client}{throughput}{stats}{prev}")).unwrap();
+    let setup = format!("SETUP:\n    THREADS: {},\n    CONNECTIONS PER THREAD: {},\n    PER-CONNECTION ARRIVALS: {}\n{}    TARGET RPS: {}\n{class_rates}\n",
+        self.num_threads, self.conns_per_thr, self.per_conn_arrivals, self.options.report(), self.target_rps());
+    let reqs = offered.total();
+    let mut class_offered = String::new();
+    for t in RequestType::iterator().filter(|t| self.class_rps.contains_key(t)) {
+      let sent = offered.sent_of(t);
+      let target = self.class_rps[&t];
+      let achieved = sent as f64 / self.runtime_secs as f64;
+      let flag = if self.class_on_target(t, sent) { "" } else { " <- OFF TARGET" };
+      class_offered = format!("{class_offered}    {:?}: {} SENT = {:.3} RPS OF {} TARGET RPS{flag}\n", t, sent, achieved, target);
+    }
+    let verdict = if self.kept_up(offered) { "OK" } else { "CLIENT COULD NOT KEEP UP WITH TARGET LOAD" };
+    let client = format!("CLIENT EFFECTIVENESS:\n    {} REQUESTS SENT / {} SECONDS = {} RPS OF {} TARGET RPS\n{class_offered}    LATE SENDS (> {} µs BEHIND SCHEDULE): {}\n    MAX SEND LAG: {} µs\n    VERDICT: {verdict}\n\n",
+      reqs, self.runtime_secs, reqs as f64 / self.runtime_secs as f64, self.target_rps(), LATE_SEND_MICROS, offered.late_sends, offered.max_lag_micros);
+    let failed = outcomes.drops + outcomes.total_timeouts() + outcomes.total_expired() + outcomes.outstanding;
+    let throughput = format!("THROUGHPUT: ({} REQUESTS SENT - {} REQUESTS DROPPED, TIMED OUT, EXPIRED OR OUTSTANDING) / {} SECONDS = {} TASKS PER SECOND\n\n",
+       reqs, failed, self.runtime_secs, reqs.saturating_sub(failed) as f64 / self.runtime_secs as f64);
+    format!("{header}{setup}{client}{throughput}")
   }
+}
 
-  fn latency_by_quant_distr(&self, stat_map: &HashMap<ResponseType, Histogram<u64>>) {
-    for (t, hist) in stat_map {
-      let path = match t {
-        ResponseType::BeRead => "beread",
-        ResponseType::LcRead => "lcread",
-        ResponseType::LcWrite => "lcwrite",
-      };
+// Load actually offered by the client, used to check it kept up with the target rate
+#[derive(Default)]
+struct OfferedLoad {
+  sent: HashMap<RequestType, u64>,
+  late_sends: u64,
+  max_lag_micros: u128,
+}
 
-      let file = File::open("bench/quantiles.txt").unwrap();
-      let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-      let quantiles: Vec<f64> = rdr.records().map(
-        |s| s.unwrap().get(0).unwrap().to_string().parse::<f64>().unwrap()).collect();
-      
-      let mut hist_data = format!("{:^8}    {:^8}    {:^8}    {:^8.3}\n", "Value", "Quantile", "Agg Count", "1/1-quantile");
-      for quantile in quantiles {
-        hist_data = format!("{hist_data}{:>8}    {:>8}    {:>8}    {:>8.3}\n",
-         hist.value_at_quantile(quantile), quantile, (hist.len() as f64 * quantile) as u64, 1.0 / (1.0 - quantile));
-      }
-      let _ = fs::write(format!("out/{path}.txt"), hist_data);
+impl OfferedLoad {
+  fn record(&mut self, kind: RequestType, lag_micros: u128) {
+    *self.sent.entry(kind).or_insert(0) += 1;
+    if lag_micros > LATE_SEND_MICROS {
+      self.late_sends += 1;
     }
+    self.max_lag_micros = self.max_lag_micros.max(lag_micros);
+  }
+
+  fn merge(&mut self, other: &OfferedLoad) {
+    for (kind, sent) in &other.sent {
+      *self.sent.entry(*kind).or_insert(0) += sent;
+    }
+    self.late_sends += other.late_sends;
+    self.max_lag_micros = self.max_lag_micros.max(other.max_lag_micros);
+  }
+
+  fn sent_of(&self, kind: RequestType) -> u64 {
+    self.sent.get(&kind).copied().unwrap_or(0)
+  }
+
+  fn total(&self) -> u64 {
+    self.sent.values().sum()
   }
 }
 
+// Per-thread share of the bench settings
+struct ThreadConfig {
+  class_rps: HashMap<RequestType, f64>,
+  per_conn_arrivals: bool,
+  verify_conns: Option<usize>, // total connections in the bench when verifying
+  options: Arc<ClientOptions>,
+}
+
 struct ClientThread {
   conns: Vec<Connection>,
-  be_prob: f32,
-  lc_wr_prob: f32,
   req_id: u64,
   req_id_mask: u64,
   req_id_shift: u8,
   latencies: HashMap<ResponseType, Vec<u128>>,
-  drop_count: u64,
-  target_rps: u64,
+  first_chunks: HashMap<ResponseType, Vec<u128>>,
+  class_rps: HashMap<RequestType, f64>,
+  per_conn_arrivals: bool,
+  requests: RequestOptions,
+  offered: OfferedLoad,
+  outcomes: Outcomes,
+  traffic: Traffic,
+  breakdown: LatencyBreakdown,
+}
+
+/// Independent Poisson arrival process for a single request type.
+struct Arrivals {
+  kind: RequestType,
+  conn: Option<usize>, // fixed target connection, random if None
+  exp: Exp<f64>,
+  next_fire: f64,
 }
 
 impl ClientThread {
   fn init(port: usize, 
     conns_per_thr: usize, 
-    be_prob: f32, 
-    lc_wr_prob: f32, 
     req_id_mask: u64, 
     req_id_shift: u8, 
-    target_rps: u64) -> Self {
+    config: ThreadConfig) -> Self {
     let mut conns: Vec<Connection> = Vec::new();
-    for _ in 0..conns_per_thr {
-      conns.push(Connection::new(format!("127.0.0.1:{port}").as_str()).unwrap());
+    for c in 0..conns_per_thr {
+      // with verification on, every connection in the bench owns a disjoint key range
+      let conn = req_id_mask as usize * conns_per_thr + c;
+      let verifier = config.verify_conns.map(|num_conns| Verifier::new(conn, num_conns));
+      let tagger = config.options.tagger(conn);
+      conns.push(Connection::new(format!("127.0.0.1:{port}").as_str(), config.options.clone(), tagger, verifier).unwrap());
     }
 
     let mut latencies: HashMap<ResponseType, Vec<u128>> = HashMap::new();
+    let mut first_chunks: HashMap<ResponseType, Vec<u128>> = HashMap::new();
     for t in ResponseType::iterator() {
       latencies.insert(t, Vec::new());
+      first_chunks.insert(t, Vec::new());
     }
     ClientThread {
         conns,
         latencies,
-        be_prob,
-        lc_wr_prob,
+        first_chunks,
         req_id: req_id_mask,
         req_id_mask,
         req_id_shift,
-        drop_count: 0,
-        target_rps
+        class_rps: config.class_rps,
+        per_conn_arrivals: config.per_conn_arrivals,
+        requests: config.options.requests,
+        offered: OfferedLoad::default(),
+        outcomes: Outcomes::default(),
+        traffic: Traffic::default(),
+        breakdown: LatencyBreakdown::default(),
     }
   }
 
-  fn generate_random_request(&mut self) -> (Request, u64) {
+  fn generate_random_request(&mut self, kind: RequestType, conn: usize) -> (Request, u64) {
     let req_id = self.req_id;
     self.req_id = (((self.req_id >> self.req_id_shift) + 1) << self.req_id_shift) | self.req_id_mask;
-    let be_rat: f32 = rand::rng().random();
-    let wr_rat: f32 = rand::rng().random();
-    if be_rat <= self.be_prob {
-      (Request::random(RequestType::BeRead, req_id), req_id)
-    } else if wr_rat <= self.lc_wr_prob {
-      (Request::random(RequestType::LcWrite, req_id), req_id)
-    } else {
-      (Request::random(RequestType::LcRead, req_id), req_id)
-    }
+    let connection = &self.conns[conn];
+    let keys = match &connection.verifier {
+      Some(verifier) => verifier.keys(),
+      None => 0..CAPACITY as u64,
+    };
+    let mut req = Request::random_in(kind, req_id, keys, &self.requests);
+    connection.seen.aim(&mut req, connection.verifier.as_ref());
+    (req, req_id)
   }
 
   fn send_packets(mut self, runtime_secs: f32) -> Result<Self, AspenRsError> {
-    let exp = Exp::new(self.target_rps as f64).unwrap();
     let mut rng = rand::rng();
     let n = self.conns.len();
+    let targets: Vec<Option<usize>> = if self.per_conn_arrivals {
+      (0..n).map(Some).collect()
+    } else {
+      vec![None]
+    };
+
+    let mut arrivals: Vec<Arrivals> = Vec::new();
+    for kind in RequestType::iterator() {
+      let rps = self.class_rps.get(&kind).copied().unwrap_or(0.0) / targets.len() as f64;
+      if rps > 0.0 {
+        for conn in &targets {
+          let exp = Exp::new(rps).unwrap();
+          let next_fire = exp.sample(&mut rng);
+          arrivals.push(Arrivals { kind, conn: *conn, exp, next_fire });
+        }
+      }
+    }
+
     let start_time = Instant::now();
-    let mut next_fire = exp.sample(&mut rng);
+    let mut live: Vec<usize> = (0..n).collect(); // connections that have not been given up on
   
     loop {
       if start_time.elapsed().as_secs_f32() > runtime_secs {
         break;
       }
-      while start_time.elapsed().as_secs_f64() > next_fire {
-        // send/enqueue request
-        let (req, req_id) = self.generate_random_request();
-        
-        let conn = &mut self.conns[rand::random_range(0..n)];
-        conn.enqueue_new_request(req, req_id)?;
-
-        next_fire += exp.sample(&mut rng);
+      for arrival in &mut arrivals {
+        loop {
+          let now = start_time.elapsed().as_secs_f64();
+          if now <= arrival.next_fire || arrival.next_fire > runtime_secs as f64 {
+            break;
+          }
+          // send/enqueue request, or drop it when the connection it is bound for was given up on
+          let conn = match arrival.conn {
+            Some(conn) => Some(conn).filter(|conn| !self.conns[*conn].dead),
+            None if live.is_empty() => None,
+            None => Some(live[rand::random_range(0..live.len())]),
+          };
+          match conn {
+            Some(conn) => {
+              let (req, req_id) = self.generate_random_request(arrival.kind, conn);
+              self.conns[conn].enqueue_new_request(req, req_id)?;
+            },
+            None => self.outcomes.drops += 1,
+          }
+          self.offered.record(arrival.kind, ((now - arrival.next_fire) * 1e6) as u128);
+
+          arrival.next_fire += arrival.exp.sample(&mut rng);
+        }
       }
       
       // progress writes
-      for conn in &mut self.conns {
-        if !conn.write_queue.is_empty() && 
-          OpenProgress::ConnectionReset == conn.progress_writes()? {
-          conn.reconnect()?;
+      let mut died = false;
+      for conn in self.conns.iter_mut().filter(|conn| !conn.dead) {
+        if conn.has_writes() &&
+          OpenProgress::Disconnected == survive_failure(conn.progress_writes(), conn.addr)? {
+          died |= !conn.restart();
         }
       }
 
       // progress reads
-      for conn in &mut self.conns {
-        if !conn.read_queue.is_empty() && 
-          OpenProgress::ConnectionReset == conn.progress_reads()? {
-          conn.reconnect()?;
+      for conn in self.conns.iter_mut().filter(|conn| !conn.dead) {
+        if (!conn.in_flight.is_empty() || !conn.timed_out.is_empty()) &&
+          OpenProgress::Disconnected == survive_failure(conn.progress_reads(), conn.addr)? {
+          died |= !conn.restart();
         }
+        conn.expire_requests();
+      }
+      if died {
+        live.retain(|c| !self.conns[*c].dead);
       }
     }
 
-    for conn in &self.conns {
-      self.drop_count += conn.drop_count;
+    for conn in &mut self.conns {
+      conn.finish();
+      self.outcomes.merge(&conn.outcomes);
+      self.traffic.merge(&conn.traffic());
+      self.breakdown.merge(&conn.breakdown);
       
       for kind in ResponseType::iterator() {
         let latencies = conn.latencies.get(&kind).unwrap();
         self.latencies.get_mut(&kind).unwrap().extend_from_slice(latencies);
+        let first_chunks = conn.first_chunks.get(&kind).unwrap();
+        self.first_chunks.get_mut(&kind).unwrap().extend_from_slice(first_chunks);
       }
     }
 
@@ -276,48 +379,117 @@ impl ClientThread {
 
 struct Connection {
   stream: TcpStream,
+  addr: SocketAddr,
+  options: Arc<ClientOptions>, // offered again on every reconnect
+  negotiated: Negotiated,
+  tagger: Option<Tagger>,
+  dead: bool, // could not be reconnected, takes no more requests
+  sent: FrameStats,
 
   in_flight: HashMap<u64, RequestState>,
   write_queue: VecDeque<u64>,
-  read_queue: VecDeque<u64>,
+  decoder: FrameDecoder,
+  spare_bufs: Vec<Vec<u8>>, // write buffers of sent requests, reused for the next ones
+  cancel_buf: Vec<u8>, // Cancels for timed out requests, written between requests
+  cancel_written: usize,
+
+  deadlines: VecDeque<(Instant, u64)>, // sent requests in send order
+  timed_out: HashSet<u64>,
 
   latencies: HashMap<ResponseType, Vec<u128>>,
-  drop_count: u64,
+  first_chunks: HashMap<ResponseType, Vec<u128>>,
+  outcomes: Outcomes,
+  breakdown: LatencyBreakdown,
+  verifier: Option<Verifier>,
+  seen: SeenValues,
 }
 
 impl Connection {
-  fn new(addr: &str) -> Result<Self, NetworkError> {
-    let stream = TcpStream::connect(addr)?;
-    stream.set_nonblocking(true)?;
+  fn new(addr: &str, options: Arc<ClientOptions>, tagger: Option<Tagger>, verifier: Option<Verifier>) -> Result<Self, AspenRsError> {
+    let mut decoder = FrameDecoder::new(FrameLimits::responses());
+    let (stream, negotiated) = options.connect(addr, open_features(&options), &mut decoder)?;
+    check_features(negotiated.agreed)?;
+    let addr = stream.peer_addr().map_err(NetworkError::from)?;
     
     let mut latencies: HashMap<ResponseType, Vec<u128>> = HashMap::new();
+    let mut first_chunks: HashMap<ResponseType, Vec<u128>> = HashMap::new();
     for t in ResponseType::iterator() {
       latencies.insert(t, Vec::new());
+      first_chunks.insert(t, Vec::new());
     }
 
     Ok(Connection {
         stream,
+        addr,
+        options,
+        negotiated,
+        tagger,
+        dead: false,
+        sent: FrameStats::default(),
         in_flight: HashMap::new(),
         write_queue: VecDeque::new(),
-        read_queue: VecDeque::new(),
+        decoder,
+        spare_bufs: Vec::new(),
+        cancel_buf: Vec::new(),
+        cancel_written: 0,
+        deadlines: VecDeque::new(),
+        timed_out: HashSet::new(),
         latencies,
-        drop_count: 0
+        first_chunks,
+        outcomes: Outcomes::default(),
+        breakdown: LatencyBreakdown::default(),
+        verifier,
+        seen: SeenValues::default(),
     })
   }
 
-  fn reconnect(&mut self) -> Result<(), NetworkError> {
-      let stream = TcpStream::connect(self.stream.local_addr()?)?;
-      stream.set_nonblocking(true)?;
-      self.stream = stream;
-      self.drop_count += self.in_flight.len() as u64;
+  fn traffic(&self) -> Traffic {
+    Traffic { sent: self.sent, received: self.decoder.stats() }
+  }
+
+  fn reconnect(&mut self) -> Result<(), AspenRsError> {
+      (self.stream, self.negotiated) = self.options.reconnect(self.addr, open_features(&self.options), &mut self.decoder)?;
+      check_features(self.negotiated.agreed)?;
+      self.outcomes.reconnects += 1;
+      self.outcomes.drops += self.in_flight.len() as u64;
       self.in_flight = HashMap::new();
-      self.read_queue = VecDeque::new();
       self.write_queue = VecDeque::new();
+      self.cancel_buf.clear();
+      self.cancel_written = 0;
+      self.deadlines = VecDeque::new();
+      self.timed_out = HashSet::new();
+      self.seen.abandon_all();
+      if let Some(verifier) = &mut self.verifier {
+        verifier.forget_all();
+      }
       Ok(())
   }
 
+  // reconnect, or give up on the connection if the server cannot be reached again so the thread carries on
+  // with its other connections. What it had in flight is dropped either way. Returns whether it is still usable
+  fn restart(&mut self) -> bool {
+    if let Err(e) = self.reconnect() {
+      eprintln!("Giving up on connection to {}: {e}", self.addr);
+      self.outcomes.drops += self.in_flight.len() as u64;
+      self.in_flight = HashMap::new();
+      self.write_queue = VecDeque::new();
+      self.deadlines = VecDeque::new();
+      self.timed_out = HashSet::new();
+      self.dead = true;
+      return false;
+    }
+    true
+  }
+
   fn enqueue_new_request(&mut self, req: Request, req_id: u64) -> Result<(), AspenRsError> {
-    let i = self.in_flight.insert(req_id, RequestState::new(req));
+    if let Some(verifier) = &mut self.verifier {
+      verifier.track(&req);
+    }
+    self.seen.sent(&req);
+    let mut write_buf = self.spare_bufs.pop().unwrap_or_default();
+    let stamp = self.negotiated.stamp(self.tagger.as_ref(), req.kind());
+    encode_request(&req, &mut write_buf, &self.negotiated, stamp, &mut self.sent);
+    let i = self.in_flight.insert(req_id, RequestState::new(req.kind(), stamp.tags, write_buf));
     if let Some(req) = i {
       return Err(AspenRsError::InternalError(format!("req_id {req_id} already exists with {:?}", req)));
     }
@@ -325,12 +497,69 @@ impl Connection {
     Ok(())
   }
 
+  // give up on sent requests whose deadline has passed, their responses are discarded if they arrive later.
+  // If the server agreed to it, it is asked to abort them, and answers them with Cancelled if it still could
+  fn expire_requests(&mut self) {
+    let now = Instant::now();
+    while let Some((deadline, req_id)) = self.deadlines.front().copied() {
+      if deadline > now {
+        break;
+      }
+      self.deadlines.pop_front();
+      if let Some(RequestState::Reading { res_type, .. }) = self.in_flight.get(&req_id) {
+        self.outcomes.record_timeout(*res_type);
+        self.in_flight.remove(&req_id);
+        self.timed_out.insert(req_id);
+        self.seen.abandon(req_id);
+        if let Some(verifier) = &mut self.verifier {
+          verifier.forget(req_id);
+        }
+        if self.negotiated.agreed.contains(Features::CANCEL) {
+          let mut buf = self.spare_bufs.pop().unwrap_or_default();
+          encode_request(&Request::Cancel { req_id }, &mut buf, &self.negotiated, Stamp::default(), &mut self.sent);
+          self.cancel_buf.extend_from_slice(&buf);
+          self.spare_bufs.push(buf);
+        }
+      }
+    }
+  }
+
+  fn has_writes(&self) -> bool {
+    !self.write_queue.is_empty() || self.cancel_written < self.cancel_buf.len()
+  }
+
+  // whether the request at the front of the write queue is partly written
+  fn writing_request(&self) -> bool {
+    matches!(self.write_queue.front().and_then(|req_id| self.in_flight.get(req_id)), Some(RequestState::Writing { offset, .. }) if *offset > 0)
+  }
+
+  // requests that were never sent or never answered, counted at the end of the run
+  fn finish(&mut self) {
+    self.outcomes.outstanding += self.in_flight.len() as u64;
+  }
+
   fn progress_writes(&mut self) -> Result<OpenProgress, AspenRsError> {
-    while self.write_queue.front().is_some() {
-      let req_id = self.write_queue.front().unwrap();
+    loop {
+      // Cancels go out between requests, never in the middle of one
+      if self.cancel_written < self.cancel_buf.len() && !self.writing_request() {
+        match self.stream.write(&self.cancel_buf[self.cancel_written..]) {
+          Ok(bytes_written) => self.cancel_written += bytes_written,
+          Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+          Err(e) if is_disconnect(&e) => return Ok(OpenProgress::Disconnected),
+          Err(e) => return Err(AspenRsError::NetworkError(NetworkError::from(e))),
+        }
+        if self.cancel_written < self.cancel_buf.len() {
+          break;
+        }
+        self.cancel_buf.clear();
+        self.cancel_written = 0;
+      }
+      let Some(req_id) = self.write_queue.front() else {
+        break;
+      };
       let req = self.in_flight.get_mut(req_id).unwrap();
       match req {
-        RequestState::Writing { req_type, start_time, write_buf, offset } => {
+        RequestState::Writing { req_type, tags, start_time, write_buf, offset } => {
           let req_bytes = write_buf.len();
           match self.stream.write(&write_buf[*offset..req_bytes]) {
             Ok(bytes_written) => {
@@ -339,12 +568,17 @@ impl Connection {
                   *start_time = Some(Instant::now());
               }
               if bytes_written + *offset == req_bytes {
+                let start_time = (*start_time).unwrap();
+                self.spare_bufs.push(std::mem::take(write_buf));
                 *req = RequestState::Reading { 
                   res_type: ResponseType::from_request(*req_type), 
-                  start_time: (*start_time).unwrap(), 
-                  read_buf: Vec::new(), 
-                  expected_len: None  
+                  tags: *tags,
+                  start_time,
+                  stream: Stream::default(),
                 };
+                if let Some(timeout) = self.options.timeout {
+                  self.deadlines.push_back((start_time + timeout, *req_id));
+                }
                 self.write_queue.pop_front().unwrap();
               } else {
                 *offset += bytes_written;
@@ -352,7 +586,7 @@ impl Connection {
               }
             },
             Err(e) if e.kind() == ErrorKind::WouldBlock => break,
-            Err(e) if e.kind() == ErrorKind::ConnectionReset => {return Ok(OpenProgress::ConnectionReset)},
+            Err(e) if is_disconnect(&e) => {return Ok(OpenProgress::Disconnected)},
             Err(e) => {return Err(AspenRsError::NetworkError(NetworkError::from(e)));}
           }
         },
@@ -365,97 +599,220 @@ impl Connection {
   }
 
   fn progress_reads(&mut self) -> Result<OpenProgress, AspenRsError> {
-    while self.read_queue.front().is_some() {
-      let req_id = self.read_queue.front().unwrap();
-      let req = self.in_flight.get_mut(req_id).unwrap();
-      match req {
-        RequestState::Reading { res_type, start_time, read_buf, expected_len } => {
-          let mut buf = [0; BUF_LEN];
-          let check_type = read_buf.is_empty();
-          match self.stream.read(&mut buf) {
-            Ok(bytes_read) => {
-              if bytes_read > 0 {
-                read_buf.extend_from_slice(&buf[0..bytes_read]);
-              } else {
-                return Err(AspenRsError::NetworkError(NetworkError::ConnectionClosed));
-              }
-    
-              let packet_type = ResponseType::from_value(*read_buf.first().unwrap())?;
-              
-              // TODO: Add Drop packet handling
-              if check_type && *res_type != packet_type {
-                return Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType{ exp_type: *res_type, given_type: packet_type }));
-              }
-    
-              if expected_len.is_none() {
-                if read_buf.len() < (1 + LEN_LENGTH) {
-                  break;
-                }
-    
-                let len_arr: [u8; 8] = read_buf[1..(1+LEN_LENGTH)].try_into().unwrap();
-                *expected_len = Some(usize::from_be_bytes(len_arr));
-              }
-    
-              let total_exp_len = 1 + LEN_LENGTH + expected_len.expect("Should not be None based on previous checks");
-    
-              if read_buf.len() < total_exp_len {
-                break;
-              } else if read_buf.len() == total_exp_len {
-                let _res = Response::deserialize(read_buf).map_err(AspenRsError::ParseError)?;
-                // optional check for response
-                let latency = start_time.elapsed().as_micros();
-                self.latencies.get_mut(res_type).unwrap().push(latency);
-                self.read_queue.pop_front().unwrap();
-                // println!("Response {:?} received from {} in {} µs", _res, self.stream.local_addr().unwrap(), latency);
-              } else {
-                return Err(AspenRsError::ParseError(ParseError::UnexpectedLength { payload_len: read_buf.len(), exp_len: total_exp_len }));
-              }
-            },
-            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
-            Err(e) if e.kind() == ErrorKind::ConnectionReset => return Ok(OpenProgress::ConnectionReset),
-            Err(e) => return Err(AspenRsError::NetworkError(NetworkError::from(e)))
-          }
-        },
-        RequestState::Writing { .. } => {
-          return Err(AspenRsError::InternalError(format!("request {req_id} in read queue with write state")));
+    let mut buf = [0; BUF_LEN];
+    loop {
+      match self.stream.read(&mut buf) {
+        Ok(0) => return Ok(OpenProgress::Disconnected),
+        Ok(bytes_read) => self.decoder.push(&buf[0..bytes_read]),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+        Err(e) if is_disconnect(&e) => return Ok(OpenProgress::Disconnected),
+        Err(e) => return Err(AspenRsError::NetworkError(NetworkError::from(e)))
+      }
+
+      // responses arrive back to back, so a single read may hold several of them. They are decoded in place,
+      // with the decoder moved out while they are completed
+      let mut decoder = std::mem::take(&mut self.decoder);
+      let completed = self.complete_all(&mut decoder);
+      self.decoder = decoder;
+      match completed {
+        Err(e) if is_corrupt_frame(&e) => {
+          self.outcomes.corrupt_frames += 1;
+          return Ok(OpenProgress::Disconnected);
         },
+        res => res?,
       }
     }
     Ok(OpenProgress::MadeProgress)
   }
+
+  fn complete_all(&mut self, decoder: &mut FrameDecoder) -> Result<(), AspenRsError> {
+    while let Some(frame) = decoder.next_frame().map_err(AspenRsError::ParseError)? {
+      let server_timing = frame_server_timing(frame, self.negotiated.layout);
+      self.complete(ResponseView::decode_with(frame, self.negotiated.layout, self.options.utf8)?, server_timing)?;
+    }
+    Ok(())
+  }
+
+  fn complete(&mut self, res: ResponseView<'_>, server_timing: Option<ServerTiming>) -> Result<(), AspenRsError> {
+    let req_id = res.req_id();
+    if let ResponseView::Chunk { entries, .. } = res {
+      match self.in_flight.get_mut(&req_id) {
+        Some(RequestState::Reading { start_time, stream, .. }) => stream.push(*start_time, entries),
+        // a late stream is counted once, when the response ending it arrives
+        None if self.timed_out.contains(&req_id) => {},
+        _ => return Err(AspenRsError::InternalError(format!("chunk for request {req_id} that is not being read"))),
+      }
+      return Ok(());
+    }
+
+    match self.in_flight.remove(&req_id) {
+      Some(RequestState::Reading { res_type, tags, start_time, stream }) => {
+        self.seen.received(&res);
+        if let Some(first_chunk) = stream.first_chunk {
+          self.first_chunks.get_mut(&res_type).unwrap().push(first_chunk);
+        }
+        let kind = res.kind();
+        if let ResponseView::Drop { queued_micros, .. } = res {
+          self.outcomes.record_expired(res_type, queued_micros);
+          if let Some(verifier) = &mut self.verifier {
+            verifier.untrack(req_id);
+          }
+          return Ok(());
+        }
+        if let ResponseView::Error { code, .. } = &res {
+          // the server may refuse any request, which says nothing about the keys it touched
+          self.outcomes.record_error(*code);
+          if let Some(verifier) = &mut self.verifier {
+            verifier.forget(req_id);
+          }
+        } else if res_type != kind {
+          return Err(AspenRsError::ParseError(ParseError::UnexpectedMessageType{ exp_type: res_type, given_type: kind }));
+        } else if stream.is_streamed() || self.verifier.is_some() {
+          // only streamed or verified responses are copied out of the buffer
+          let res = stream.reassemble(res.into_owned())?;
+          if let Some(verifier) = &mut self.verifier
+            && let Err(mismatch) = verifier.check(&res) {
+            self.outcomes.record_mismatch(mismatch);
+          }
+        }
+        let latency = start_time.elapsed().as_micros();
+        self.latencies.get_mut(&kind).unwrap().push(latency);
+        if let Some(tags) = tags {
+          self.breakdown.record_tags(tags, latency);
+        }
+        if let Some(timing) = server_timing {
+          self.breakdown.record_server(kind, latency, timing);
+        }
+        Ok(())
+      },
+      Some(RequestState::Writing { .. }) => {
+        Err(AspenRsError::InternalError(format!("response for request {req_id} received before it was sent")))
+      },
+      None if self.timed_out.remove(&req_id) => {
+        if res.kind() == ResponseType::Cancelled {
+          self.outcomes.cancelled += 1;
+        } else {
+          self.outcomes.late_responses += 1;
+        }
+        Ok(())
+      },
+      None => Err(AspenRsError::InternalError(format!("response for unknown request {req_id}"))),
+    }
+  }
+}
+
+// requests are pipelined, so the server has to accept a new one before answering the last
+const OPEN_FEATURES: Features = Features::PIPELINING;
+
+// offered on top of the shared options: pipelining, and cancelling requests that time out
+fn open_features(options: &ClientOptions) -> Features {
+  if options.timeout.is_some() {
+    OPEN_FEATURES | Features::CANCEL
+  } else {
+    OPEN_FEATURES
+  }
+}
+
+fn check_features(features: Features) -> Result<(), AspenRsError> {
+  if !features.contains(OPEN_FEATURES) {
+    return Err(AspenRsError::InternalError(format!("server only supports {:?}, open loop needs {:?}", features, OPEN_FEATURES)));
+  }
+  Ok(())
+}
+
+// an IO error other than a disconnect still leaves the server to reconnect to, so the connection is restarted
+// instead of the run ending
+fn survive_failure(progress: Result<OpenProgress, AspenRsError>, addr: SocketAddr) -> Result<OpenProgress, AspenRsError> {
+  match progress {
+    Err(AspenRsError::NetworkError(e)) => {
+      eprintln!("Connection to {addr} failed: {e}");
+      Ok(OpenProgress::Disconnected)
+    },
+    progress => progress,
+  }
 }
 
 #[derive(PartialEq, Eq)]
 pub enum OpenProgress {
   MadeProgress,
-  ConnectionReset,
+  Disconnected, // reset or closed by peer
 }
 
 #[derive(Debug)]
 enum RequestState {
   Writing {
       req_type: RequestType,
+      tags: Option<Tags>,
       start_time: Option<Instant>,
       write_buf: Vec<u8>,
       offset: usize, // start writing at this value
   },
   Reading {
       res_type: ResponseType,
+      tags: Option<Tags>,
       start_time: Instant,
-      read_buf: Vec<u8>,
-      expected_len: Option<usize>,
+      stream: Stream,
   }
 }
 
 impl RequestState {
-  fn new(req: Request) -> Self {
-    let kind = req.kind();
-    let write_buf = req.serialize();
+  // `write_buf` holds the encoded request
+  fn new(req_type: RequestType, tags: Option<Tags>, write_buf: Vec<u8>) -> Self {
     RequestState::Writing { 
-      req_type: kind, 
+      req_type, 
+      tags,
       start_time: None, 
       write_buf, 
       offset: 0
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{net::TcpListener, time::Duration};
+  use super::*;
+  use crate::{CHECKSUM_LEN, PROTOCOL_VERSION, client::receive_blocking, packet::{Layout, RequestView, Response, append_checksum}};
+
+  #[test]
+  fn corrupt_frames_are_counted_and_end_the_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    // agrees to every feature offered, then answers the first request with a frame corrupted on the way
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut decoder = FrameDecoder::new(FrameLimits::requests());
+      receive_blocking(&mut stream, &mut decoder).unwrap();
+      let Some(RequestView::Hello { req_id, features, .. }) = decoder.next_request().unwrap() else {
+        panic!("expected a Hello");
+      };
+      stream.write_all(&Response::HelloAck { req_id, version: PROTOCOL_VERSION, features }.serialize()).unwrap();
+      decoder.set_checksums(true);
+      receive_blocking(&mut stream, &mut decoder).unwrap();
+      let req_id = decoder.next_request().unwrap().unwrap().req_id();
+      let mut frame = Vec::new();
+      Response::LcRead { req_id, username: Some(b"alice".to_vec()) }.encode_with(&mut frame, Layout::Fixed);
+      append_checksum(&mut frame, 0);
+      let last_body_byte = frame.len() - CHECKSUM_LEN - 1;
+      frame[last_body_byte] ^= 0x10;
+      stream.write_all(&frame).unwrap();
+      // until the client gives up on the connection
+      let _ = stream.read(&mut [0; 1]);
+    });
+
+    let options = Arc::new(ClientOptions::default().checksums(true));
+    let mut conn = Connection::new(&addr.to_string(), options, None, None).unwrap();
+    assert!(conn.negotiated.checksums());
+    conn.enqueue_new_request(Request::LcRead { req_id: 1, id: 0 }, 1).unwrap();
+    while conn.has_writes() {
+      conn.progress_writes().unwrap();
+    }
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while conn.progress_reads().unwrap() != OpenProgress::Disconnected {
+      assert!(Instant::now() < give_up, "the corrupt frame never arrived");
+    }
+    assert_eq!(conn.outcomes.corrupt_frames, 1);
+    drop(conn);
+    server.join().unwrap();
+  }
+}
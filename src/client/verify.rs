@@ -0,0 +1,309 @@
+use std::{collections::HashMap, ops::Range};
+
+use globset::Glob;
+use memchr::memmem;
+use regex::bytes::Regex;
+
+use crate::{CAPACITY, packet::{BeResults, Message, Request, Response}};
+
+// Shadow model of the keys owned by a single connection, used to check the
+// responses it receives. Connections own disjoint key ranges, so every change
+// to those keys is made (and seen in order) by the owning connection.
+pub struct Verifier {
+  keys: Range<u64>,
+  model: HashMap<u64, Option<Vec<u8>>>,
+  pending: HashMap<u64, Request>,
+}
+
+impl Verifier {
+  // key range owned by connection `conn` out of `num_conns`
+  pub fn new(conn: usize, num_conns: usize) -> Self {
+    assert!(num_conns <= CAPACITY, "{num_conns} connections cannot each own one of {CAPACITY} keys");
+    let share = (CAPACITY / num_conns) as u64;
+    let start = conn as u64 * share;
+    Verifier {
+      keys: start..(start + share),
+      model: HashMap::new(),
+      pending: HashMap::new(),
+    }
+  }
+
+  pub fn keys(&self) -> Range<u64> {
+    self.keys.clone()
+  }
+
+  // the value the model holds for `id`, if it knows it is stored
+  pub fn stored(&self, id: u64) -> Option<&Vec<u8>> {
+    self.model.get(&id).and_then(Option::as_ref)
+  }
+
+  pub fn track(&mut self, req: &Request) {
+    self.pending.insert(req.req_id(), req.clone());
+  }
+
+  // the request was dropped without being applied, so the model still holds
+  pub fn untrack(&mut self, req_id: u64) {
+    self.pending.remove(&req_id);
+  }
+
+  // the request may or may not have been applied, so whatever it touched is no longer known
+  pub fn forget(&mut self, req_id: u64) {
+    if let Some(req) = self.pending.remove(&req_id) {
+      match req {
+        Request::LcRead { id, .. } | Request::LcWrite { id, .. } | Request::LcDelete { id, .. }
+          | Request::LcInsertIfAbsent { id, .. } | Request::LcCompareAndSwap { id, .. } => {
+          self.model.remove(&id);
+        },
+        Request::MultiGet { ids, .. } => {
+          for id in ids {
+            self.model.remove(&id);
+          }
+        },
+        Request::MultiPut { entries, .. } => {
+          for (id, _) in entries {
+            self.model.remove(&id);
+          }
+        },
+        Request::Hello { .. } | Request::BeRead { .. } | Request::RangeScan { .. } | Request::PrefixCount { .. }
+          | Request::RegexCount { .. } | Request::GlobCount { .. } | Request::Cancel { .. } | Request::Stats { .. } => {},
+      }
+    }
+  }
+
+  pub fn forget_all(&mut self) {
+    self.pending = HashMap::new();
+    self.model = HashMap::new();
+  }
+
+  // returns a description of the mismatch if the response is inconsistent with the model
+  pub fn check(&mut self, res: &Response) -> Result<(), String> {
+    let Some(req) = self.pending.remove(&res.req_id()) else {
+      return Err(format!("response {:?} does not match any request", res));
+    };
+
+    match (&req, res) {
+      (Request::LcRead { id, .. }, Response::LcRead { username, .. }) => {
+        match self.model.get(id) {
+          Some(known) if known != username => {
+            Err(format!("LcRead of key {id} returned {}, expected {}", show(username.as_deref()), show(known.as_deref())))
+          },
+          Some(_) => Ok(()),
+          None => {
+            self.model.insert(*id, username.clone());
+            Ok(())
+          }
+        }
+      },
+      (Request::LcWrite { id, username: new, .. }, Response::LcWrite { username: prev, .. }) => {
+        let known = self.model.insert(*id, Some(new.clone()));
+        check_prev("LcWrite", *id, prev, known)
+      },
+      (Request::LcDelete { id, .. }, Response::LcDelete { username: prev, .. }) => {
+        let known = self.model.insert(*id, None);
+        check_prev("LcDelete", *id, prev, known)
+      },
+      (Request::LcInsertIfAbsent { id, username: new, .. }, Response::LcInsertIfAbsent { username: prev, .. }) => {
+        let stored = prev.clone().or_else(|| Some(new.clone()));
+        let known = self.model.insert(*id, stored);
+        check_prev("LcInsertIfAbsent", *id, prev, known)
+      },
+      (Request::LcCompareAndSwap { id, expected, username: new, .. }, Response::LcCompareAndSwap { swapped, username: prev, .. }) => {
+        if *swapped != (prev.as_ref() == Some(expected)) {
+          self.model.remove(id);
+          return Err(format!("LcCompareAndSwap of key {id} found {} expecting {} but swapped was {swapped}", show(prev.as_deref()), show(Some(expected))));
+        }
+        let stored = if *swapped { Some(new.clone()) } else { prev.clone() };
+        let known = self.model.insert(*id, stored);
+        check_prev("LcCompareAndSwap", *id, prev, known)
+      },
+      (Request::MultiGet { ids, .. }, Response::MultiGet { usernames, .. }) if ids.len() == usernames.len() => {
+        for (id, username) in ids.iter().zip(usernames) {
+          match self.model.get(id) {
+            Some(known) if known != username => {
+              return Err(format!("MultiGet of key {id} returned {}, expected {}", show(username.as_deref()), show(known.as_deref())));
+            },
+            Some(_) => {},
+            None => {
+              self.model.insert(*id, username.clone());
+            }
+          }
+        }
+        Ok(())
+      },
+      (Request::MultiPut { entries, .. }, Response::MultiPut { usernames: prevs, .. }) if entries.len() == prevs.len() => {
+        // entries are applied in order, so a repeated key sees the value put by the earlier entry
+        let mut res = Ok(());
+        for ((id, new), prev) in entries.iter().zip(prevs) {
+          let known = self.model.insert(*id, Some(new.clone()));
+          res = res.and(check_prev("MultiPut", *id, prev, known));
+        }
+        res
+      },
+      (Request::MultiGet { ids, .. }, Response::MultiGet { usernames, .. }) => {
+        Err(format!("MultiGet of {} keys returned {} values", ids.len(), usernames.len()))
+      },
+      (Request::MultiPut { entries, .. }, Response::MultiPut { usernames, .. }) => {
+        Err(format!("MultiPut of {} keys returned {} values", entries.len(), usernames.len()))
+      },
+      (Request::BeRead { substring, results, limit, .. }, Response::BeRead { freq, keys, usernames, .. }) => {
+        self.check_count("BeRead", substring, *freq, |u| memmem::find(u, substring).is_some())
+          .and(self.check_be_results(substring, *results, *limit, *freq, keys, usernames))
+      },
+      (Request::PrefixCount { prefix, .. }, Response::PrefixCount { freq, .. }) => {
+        self.check_count("PrefixCount", prefix, *freq, |u| u.starts_with(prefix))
+      },
+      (Request::RegexCount { pattern, .. }, Response::RegexCount { freq, .. }) => {
+        let Ok(regex) = Regex::new(pattern) else {
+          return Err(format!("RegexCount of invalid regex {:?} counted {freq}", pattern));
+        };
+        self.check_count("RegexCount", pattern.as_bytes(), *freq, |u| regex.is_match(u))
+      },
+      (Request::GlobCount { pattern, .. }, Response::GlobCount { freq, .. }) => {
+        let Some(glob) = Glob::new(pattern).ok().and_then(|glob| Regex::new(glob.regex()).ok()) else {
+          return Err(format!("GlobCount of invalid glob {:?} counted {freq}", pattern));
+        };
+        self.check_count("GlobCount", pattern.as_bytes(), *freq, |u| glob.is_match(u))
+      },
+      (Request::RangeScan { start, end, limit, .. }, Response::RangeScan { entries, cursor, .. }) => {
+        self.check_range_scan(*start, *end, *limit, entries, *cursor)
+      },
+      _ => Err(format!("request {:?} answered with {:?}", req.kind(), res.kind())),
+    }
+  }
+
+  // every known matching username is in the store when the scan runs
+  fn check_count(&self, op: &str, pattern: &[u8], freq: u64, matches: impl Fn(&[u8]) -> bool) -> Result<(), String> {
+    let min_freq = self.model.values().flatten().filter(|u| matches(u)).count() as u64;
+    if freq < min_freq || freq > CAPACITY as u64 {
+      Err(format!("{op} of {} counted {freq}, expected between {min_freq} and {CAPACITY}", show(Some(pattern))))
+    } else {
+      Ok(())
+    }
+  }
+
+  // returned matches have to fit the requested mode and limit, and agree with the model where both halves came back
+  fn check_be_results(&self, substring: &[u8], results: BeResults, limit: u64, freq: u64, keys: &[u64], usernames: &[Vec<u8>]) -> Result<(), String> {
+    let expected = if results == BeResults::Count { 0 } else { limit.min(freq) };
+    let wanted = |asked: bool, len: usize| if asked { len as u64 == expected } else { len == 0 };
+    if !wanted(results.keys(), keys.len()) || !wanted(results.usernames(), usernames.len()) {
+      return Err(format!("BeRead of {} as {:?} limited to {limit} matched {freq} but returned {} keys and {} usernames",
+        show(Some(substring)), results, keys.len(), usernames.len()));
+    }
+    if let Some(username) = usernames.iter().find(|u| memmem::find(u, substring).is_none()) {
+      return Err(format!("BeRead of {} returned non-matching username {}", show(Some(substring)), show(Some(username))));
+    }
+    if results == BeResults::KeysAndUsernames {
+      for (id, username) in keys.iter().zip(usernames) {
+        if let Some(known) = self.model.get(id) && known.as_ref() != Some(username) {
+          return Err(format!("BeRead returned {} for key {id}, expected {}", show(Some(username)), show(known.as_deref())));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // the scanned part of the range has to agree with every key the model knows about
+  fn check_range_scan(&self, start: u64, end: u64, limit: u64, entries: &[(u64, Vec<u8>)], cursor: Option<u64>) -> Result<(), String> {
+    let scanned_end = cursor.unwrap_or(end);
+    if entries.len() as u64 > limit || scanned_end < start || scanned_end > end {
+      return Err(format!("RangeScan of {start}..{end} limited to {limit} returned {} values with cursor {:?}", entries.len(), cursor));
+    }
+    if !entries.windows(2).all(|w| w[0].0 < w[1].0) || entries.iter().any(|(id, _)| *id < start || *id >= scanned_end) {
+      return Err(format!("RangeScan of {start}..{end} returned keys out of order or outside {start}..{scanned_end}"));
+    }
+
+    let returned: HashMap<u64, &Vec<u8>> = entries.iter().map(|(id, username)| (*id, username)).collect();
+    for (id, known) in self.model.iter().filter(|(id, _)| (start..scanned_end).contains(*id)) {
+      if returned.get(id).copied() != known.as_ref() {
+        return Err(format!("RangeScan of key {id} returned {}, expected {}", show(returned.get(id).map(|u| u.as_slice())), show(known.as_deref())));
+      }
+    }
+    Ok(())
+  }
+}
+
+// the value an update replaced has to be the one the model last saw, if it saw one
+fn check_prev(op: &str, id: u64, prev: &Option<Vec<u8>>, known: Option<Option<Vec<u8>>>) -> Result<(), String> {
+  match known {
+    Some(known) if known != *prev => {
+      Err(format!("{op} of key {id} replaced {}, expected {}", show(prev.as_deref()), show(known.as_deref())))
+    },
+    _ => Ok(()),
+  }
+}
+
+// values are bytes, described as escaped text
+fn show(value: Option<&[u8]>) -> String {
+  match value {
+    Some(value) => format!("\"{}\"", value.escape_ascii()),
+    None => "None".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn value(username: &str) -> Option<Vec<u8>> {
+    Some(username.as_bytes().to_vec())
+  }
+
+  // sends `req` and checks the response the server gave to it
+  fn exchange(verifier: &mut Verifier, req: Request, res: Response) -> Result<(), String> {
+    verifier.track(&req);
+    verifier.check(&res)
+  }
+
+  fn write(verifier: &mut Verifier, req_id: u64, username: &str, prev: Option<Vec<u8>>) -> Result<(), String> {
+    exchange(verifier, Request::LcWrite { req_id, id: 3, username: username.as_bytes().to_vec() }, Response::LcWrite { req_id, username: prev })
+  }
+
+  fn read(verifier: &mut Verifier, req_id: u64, username: Option<Vec<u8>>) -> Result<(), String> {
+    exchange(verifier, Request::LcRead { req_id, id: 3 }, Response::LcRead { req_id, username })
+  }
+
+  #[test]
+  fn consistent_responses_pass() {
+    let mut verifier = Verifier::new(0, 1);
+    read(&mut verifier, 1, value("alice")).unwrap();
+    write(&mut verifier, 2, "bob", value("alice")).unwrap();
+    read(&mut verifier, 3, value("bob")).unwrap();
+    let cas = Request::LcCompareAndSwap { req_id: 4, id: 3, expected: b"bob".to_vec(), username: b"carol".to_vec() };
+    exchange(&mut verifier, cas, Response::LcCompareAndSwap { req_id: 4, swapped: true, username: value("bob") }).unwrap();
+    read(&mut verifier, 5, value("carol")).unwrap();
+  }
+
+  #[test]
+  fn stale_reads_are_mismatches() {
+    let mut verifier = Verifier::new(0, 1);
+    read(&mut verifier, 1, value("alice")).unwrap();
+    write(&mut verifier, 2, "bob", value("alice")).unwrap();
+    let mismatch = read(&mut verifier, 3, value("alice")).unwrap_err();
+    assert!(mismatch.starts_with("LcRead of key 3"), "{mismatch}");
+  }
+
+  #[test]
+  fn lost_writes_are_mismatches() {
+    let mut verifier = Verifier::new(0, 1);
+    write(&mut verifier, 1, "bob", value("alice")).unwrap();
+    // the next write replaced the value from before the first one, which never landed
+    let mismatch = write(&mut verifier, 2, "carol", value("alice")).unwrap_err();
+    assert!(mismatch.starts_with("LcWrite of key 3"), "{mismatch}");
+  }
+
+  #[test]
+  fn wrong_compare_and_swap_outcomes_are_mismatches() {
+    let mut verifier = Verifier::new(0, 1);
+    read(&mut verifier, 1, value("bob")).unwrap();
+    // the expected value was there, yet nothing was swapped
+    let cas = Request::LcCompareAndSwap { req_id: 2, id: 3, expected: b"bob".to_vec(), username: b"carol".to_vec() };
+    let mismatch = exchange(&mut verifier, cas, Response::LcCompareAndSwap { req_id: 2, swapped: false, username: value("bob") }).unwrap_err();
+    assert!(mismatch.starts_with("LcCompareAndSwap of key 3"), "{mismatch}");
+
+    // swapped against a value other than the one the model knows
+    read(&mut verifier, 3, value("dave")).unwrap();
+    let cas = Request::LcCompareAndSwap { req_id: 4, id: 3, expected: b"bob".to_vec(), username: b"carol".to_vec() };
+    let mismatch = exchange(&mut verifier, cas, Response::LcCompareAndSwap { req_id: 4, swapped: true, username: value("bob") }).unwrap_err();
+    assert!(mismatch.starts_with("LcCompareAndSwap of key 3 replaced"), "{mismatch}");
+  }
+}
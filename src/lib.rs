@@ -10,16 +10,62 @@ pub mod packet;
 pub mod store;
 
 const CAPACITY: usize = 8500000;
+pub const PROTOCOL_VERSION: u16 = 1;
+const HELLO_BYTE: u8 = 1;
+const HELLO_REJECT_BYTE: u8 = 2;
+const ERROR_BYTE: u8 = 3;
+const CHUNK_BYTE: u8 = 4;
+const DROP_BYTE: u8 = 5;
 const BE_BYTE: u8 = 6;
 const LC_READ_BYTE: u8 = 7;
 const LC_WRITE_BYTE: u8 = 8;
+const LC_DELETE_BYTE: u8 = 9;
+const LC_INSERT_IF_ABSENT_BYTE: u8 = 10;
+const LC_CAS_BYTE: u8 = 11;
+const MULTI_GET_BYTE: u8 = 12;
+const MULTI_PUT_BYTE: u8 = 13;
+const RANGE_SCAN_BYTE: u8 = 14;
+const PREFIX_COUNT_BYTE: u8 = 15;
+const REGEX_COUNT_BYTE: u8 = 16;
+const GLOB_COUNT_BYTE: u8 = 17;
+const CANCEL_BYTE: u8 = 18;
+const STATS_BYTE: u8 = 19;
 const NONE_BYTE: u8 = 0;
 const SOME_BYTE: u8 = 1;
+const COMPRESSED_FLAG: u8 = 0x80; // set in a frame's type byte when its body is LZ4 compressed
+const TIMED_FLAG: u8 = 0x40; // set in a request's type byte when its PayloadHeader carries send time and deadline
+const TAGGED_FLAG: u8 = 0x20; // set in a request's type byte when its PayloadHeader carries priority and tenant
+const KIND_MASK: u8 = 0x1f; // bits of a frame's type byte naming its type, the others are flags
 const SUBSTRING_LEN: usize = 3;
+const PREFIX_LEN: usize = 2;
+const RANGE_SCAN_SPAN: u64 = 1000; // keys covered by a generated range scan
+const RANGE_SCAN_LIMIT: u64 = 100; // values returned by a generated range scan before it needs a cursor
+const BE_RESULT_LIMIT: u64 = 100; // matches returned by a generated BeRead that asks for them
+const STREAM_CHUNK_LEN: usize = 32; // entries per chunk of a streamed response
 const BUF_LEN: usize = 512;
+const READ_AHEAD_LEN: usize = 1 << 20; // bytes a server reads past a running task while watching for its Cancel
+const MAX_PAYLOAD_LEN: usize = 1 << 24; // bytes, the most any frame may carry after its header
+const MAX_VALUE_LEN: usize = 1 << 16; // bytes of a request carrying at most one value
+const MAX_PATTERN_LEN: usize = 1 << 12; // bytes of a request carrying a search pattern
+const MAX_BATCH_LEN: usize = 1 << 20; // bytes of a batch request, and of responses that are not scans or batches
 const LEN_LENGTH: usize = size_of::<u64>();
+const CHECKSUM_LEN: usize = size_of::<u32>(); // CRC32C trailer of every frame once checksums are agreed on
+const SERVER_TIMING_LEN: usize = 4 * size_of::<u64>(); // trailer of every response once server timing is agreed on
+const MAX_VARINT_LEN: usize = 10; // bytes of a LEB128 encoded u64
+const METRICS_BUCKETS_MICROS: [u64; 14] = [50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000]; // upper bounds of the server's latency histograms
+const MAX_HTTP_REQUEST_LEN: usize = 8192; // bytes of a request to the metrics endpoint, headers included
+const METRICS_READ_TIMEOUT_MILLIS: u64 = 1000; // for each read of a request to the metrics endpoint, so a stalled scrape is dropped
+const METRICS_ACCEPT_BACKOFF_MILLIS: u64 = 100; // wait after the metrics endpoint fails to accept a connection
+pub const COMPRESSION_THRESHOLD: usize = 1024; // bytes, shorter bodies are not worth compressing
 const SIG_FIG: u8 = 3;
-const YIELD_FREQ: usize = 5; // yield every 2^n best effort sub-operations
+const YIELD_FREQ: usize = 5; // yield every 2^n best effort sub-operations of Normal priority
+const MAX_OUTRANKED_WAIT: u64 = 64; // tasks that may finish while one waits for higher priorities, before it goes anyway
+const LATE_SEND_MICROS: u128 = 1000; // open-loop sends further behind schedule count as late
+const MAX_LATE_FRAC: f64 = 0.01;
+const RECONNECT_RETRIES: u32 = 6;
+const MISMATCH_LOG_LEN: usize = 10; // mismatches described in the report
+const SEEN_VALUES_LEN: usize = 256; // values read back that a connection keeps for its CompareAndSwaps to expect
+const RECONNECT_BACKOFF_MILLIS: u64 = 10; // doubled after every failed attempt
 
 #[derive(Debug, Error)]
 pub enum AspenRsError {
@@ -28,7 +74,9 @@ pub enum AspenRsError {
   #[error("parse error: {0}")]
   ParseError(#[from] ParseError),
   #[error("internal error: {0}")]
-  InternalError(String)
+  InternalError(String),
+  #[error("handshake rejected by server speaking version {version}: {reason}")]
+  HandshakeRejected{version: u16, reason: String},
 }
 
 #[derive(Debug, Error)]
@@ -66,8 +114,16 @@ pub enum ParseError {
   InvalidMessageType(u8),
   #[error("value {0} is not attributed to a option type")]
   UnexpectedOptionType(u8),
+  #[error("value {0} is not attributed to an error code")]
+  InvalidErrorCode(u8),
+  #[error("text field is not valid UTF-8: {0}")]
+  InvalidUtf8(#[from] std::str::Utf8Error),
   #[error("packet too short")]
   PacketTooShort,
+  #[error("frame of type {kind} announces {payload_len} payload bytes, more than the {max_payload_len} allowed")]
+  FrameTooLarge{kind: u8, payload_len: u64, max_payload_len: usize},
+  #[error("frame of type {kind} carries checksum {expected:#010x} but its bytes sum to {actual:#010x}")]
+  ChecksumMismatch{kind: u8, expected: u32, actual: u32},
   #[error("expected message of type {:?} but parsed message with type {:?}", given_type, exp_type)]
   UnexpectedMessageType{given_type: ResponseType, exp_type: ResponseType},
 }
\ No newline at end of file
@@ -1,10 +1,19 @@
-use std::{sync::mpsc, thread};
+use std::{collections::HashMap, env, sync::mpsc, thread, time::Duration};
 
-use aspen_rust::{client::{closed, open}, server, store::Store};
+use aspen_rust::{client::{ClientOptions, open}, packet::RequestType, server::{self, ServerOptions}, store::Store};
 
 fn main() {
     println!("Starting benchmark...");
     let port = 12345;
+    // `--metrics-port <port>` serves GET /metrics on that port while the benchmark runs
+    let mut args = env::args().skip(1);
+    let mut metrics_port = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metrics-port" => metrics_port = Some(args.next().and_then(|port| port.parse().ok()).expect("--metrics-port takes a port number")),
+            _ => panic!("unknown argument {arg}, only --metrics-port <port> is accepted"),
+        }
+    }
     let (tx, rx) = mpsc::sync_channel::<()>(1);
 
     println!("Building database...");
@@ -15,20 +24,25 @@ fn main() {
     let client_threads: usize = 3;
     let server_threads = num_threads - client_threads;
     thread::spawn(move || {
-        server::DefaultSmolServer::init(server_threads, port, tx, store);
+        server::DefaultSmolServer::init_with(server_threads, port, tx, store, ServerOptions { metrics_port, ..ServerOptions::default() });
     });
 
     rx.recv().unwrap();
 
     println!("Starting main client thread...");
     
-    // closed::ClosedBench::new(2500, 0.001, 0.1, client_threads, 64).run(port);
+    // aspen_rust::client::closed::ClosedBench::new(2500, 0.001, 0.1, client_threads, 64).run(port);
+    let class_rps = HashMap::from([
+        (RequestType::BeRead, 2.0),
+        (RequestType::LcRead, 2250.0),
+        (RequestType::LcWrite, 250.0),
+    ]);
     open::OpenBench::new(
-        2500, 
+        class_rps,
         10.0,
-        0.001, 
-        0.1, 
         client_threads,
-        64).run(port);
+        64)
+        .options(ClientOptions::default().timeout(Duration::from_secs(1)))
+        .run(port);
 }
 
@@ -1,10 +1,42 @@
+use std::{ops::{BitOr, Range}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+mod compression;
+mod frame;
+mod view;
+pub use frame::{FrameDecoder, FrameLimits, FrameStats, whole_frames};
+pub use view::{ListView, RequestView, ResponseView, WireItem};
+use view::{take, take_int};
+
 use rand::{Rng, distr::{Alphanumeric, SampleString}};
-use crate::{BE_BYTE, CAPACITY, LC_READ_BYTE, LC_WRITE_BYTE, LEN_LENGTH, NONE_BYTE, ParseError, SOME_BYTE, SUBSTRING_LEN};
+use crate::{BE_BYTE, BE_RESULT_LIMIT, CAPACITY, CHECKSUM_LEN, CHUNK_BYTE, COMPRESSED_FLAG, DROP_BYTE, ERROR_BYTE, HELLO_BYTE, HELLO_REJECT_BYTE, KIND_MASK, LC_CAS_BYTE, LC_DELETE_BYTE, LC_INSERT_IF_ABSENT_BYTE, LC_READ_BYTE, LC_WRITE_BYTE, LEN_LENGTH, GLOB_COUNT_BYTE, CANCEL_BYTE, MAX_VARINT_LEN, MULTI_GET_BYTE, MULTI_PUT_BYTE, NONE_BYTE, PREFIX_COUNT_BYTE, PREFIX_LEN, PROTOCOL_VERSION, ParseError, RANGE_SCAN_BYTE, RANGE_SCAN_LIMIT, RANGE_SCAN_SPAN, REGEX_COUNT_BYTE, SERVER_TIMING_LEN, SOME_BYTE, STATS_BYTE, SUBSTRING_LEN, TAGGED_FLAG, TIMED_FLAG};
 
 pub trait Message {
   type Tag: MessageType;
   fn kind(&self) -> Self::Tag;
-  fn serialize(&self) -> Vec<u8>;
+  // appends the whole frame, so one buffer can be reused for many messages
+  fn encode_into(&self, buf: &mut Vec<u8>) {
+    self.encode_with(buf, Layout::Fixed);
+  }
+  fn encode_with(&self, buf: &mut Vec<u8>, layout: Layout);
+  // like encode_with, with the PayloadHeader carrying `stamp`, and a body longer than `compress_over` bytes
+  // LZ4 compressed if that shrinks it. Returns the length of the frame before compression
+  fn encode_framed(&self, buf: &mut Vec<u8>, layout: Layout, stamp: Stamp, compress_over: Option<usize>) -> usize {
+    let start = buf.len();
+    self.encode_with(buf, layout);
+    if stamp != Stamp::default() {
+      stamp_frame::<Self::Tag>(buf, start, layout, stamp);
+    }
+    let frame_len = buf.len() - start;
+    if let Some(threshold) = compress_over {
+      compression::compress_frame::<Self::Tag>(buf, start, layout, threshold);
+    }
+    frame_len
+  }
+  fn serialize(&self) -> Vec<u8> {
+    let mut buf = Vec::new();
+    self.encode_into(&mut buf);
+    buf
+  }
   fn deserialize(packet: &[u8]) -> Result<Self, ParseError> where Self: std::marker::Sized;
 }
 
@@ -17,58 +49,346 @@ pub trait MessageType {
 
 #[allow(clippy::len_without_is_empty)]
 pub trait Header {
-  fn expected_len() -> usize;
-  fn len(&self) -> usize;
-  fn serialize(&self) -> Vec<u8>;
-  fn deserialize(buf: &[u8]) -> Result<Self, ParseError> where Self: std::marker::Sized;
+  fn expected_len() -> usize; // in the fixed layout
+  fn len(&self, layout: Layout) -> usize;
+  fn encode_into(&self, buf: &mut Vec<u8>, layout: Layout);
+  fn deserialize(buf: &[u8], layout: Layout) -> Result<Self, ParseError> where Self: std::marker::Sized;
+}
+
+// Optional protocol capabilities, agreed on during the Hello exchange
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Features(u32);
+
+impl Features {
+  pub const NONE: Features = Features(0);
+  pub const PIPELINING: Features = Features(1);
+  pub const DROP_RESPONSES: Features = Features(1 << 1);
+  pub const COMPRESSION: Features = Features(1 << 2);
+  pub const BATCH_OPS: Features = Features(1 << 3);
+  pub const STREAMING: Features = Features(1 << 4);
+  pub const COMPACT: Features = Features(1 << 5);
+  pub const CHECKSUMS: Features = Features(1 << 6);
+  pub const DEADLINES: Features = Features(1 << 7);
+  pub const TAGS: Features = Features(1 << 8);
+  pub const CANCEL: Features = Features(1 << 9);
+  pub const SERVER_TIMING: Features = Features(1 << 10);
+  pub const STATS: Features = Features(1 << 11);
+
+  pub fn from_bits(bits: u32) -> Self {
+    Features(bits)
+  }
+
+  pub fn bits(&self) -> u32 {
+    self.0
+  }
+
+  pub fn contains(&self, other: Features) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  pub fn intersection(&self, other: Features) -> Features {
+    Features(self.0 & other.0)
+  }
+
+  // usable in consts, unlike BitOr
+  pub const fn union(self, other: Features) -> Features {
+    Features(self.0 | other.0)
+  }
+}
+
+impl BitOr for Features {
+  type Output = Features;
+
+  fn bitor(self, rhs: Features) -> Features {
+    Features(self.0 | rhs.0)
+  }
+}
+
+// How a frame's payload length and req_id, and the key of a single key request, are written. Hello and
+// its answer always use the fixed layout, the rest of a connection is compact once both sides offered it
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug, Default)]
+pub enum Layout {
+  #[default]
+  Fixed, // big endian u64s
+  Compact, // LEB128 varints
+}
+
+impl Layout {
+  pub fn negotiated(features: Features) -> Self {
+    if features.contains(Features::COMPACT) { Layout::Compact } else { Layout::Fixed }
+  }
+}
+
+// When a request was sent and until when its answer is of use, in µs since the Unix epoch. The server
+// compares them to its own clock, so they are only meaningful when client and server share one
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Timing {
+  pub sent_micros: u64,
+  pub deadline_micros: Option<u64>,
+}
+
+impl Timing {
+  // sent now, due `budget` from now if there is one. A budget too large to count in µs never runs out
+  pub fn now(budget: Option<Duration>) -> Self {
+    let sent_micros = now_micros();
+    let deadline_micros = budget.map(|budget| sent_micros.saturating_add(u64::try_from(budget.as_micros()).unwrap_or(u64::MAX)));
+    Timing { sent_micros, deadline_micros }
+  }
+
+  pub fn expired(&self, now_micros: u64) -> bool {
+    self.deadline_micros.is_some_and(|deadline| now_micros > deadline)
+  }
+
+  // how long the request waited before `now_micros`, on the wire and in queues
+  pub fn queued_micros(&self, now_micros: u64) -> u64 {
+    now_micros.saturating_sub(self.sent_micros)
+  }
+}
+
+// Where a request's time went on the server, sent after every response frame once SERVER_TIMING is agreed
+// on. The trailer is always four big endian u64s, whatever the layout, and payload_len does not count it
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ServerTiming {
+  pub received_micros: u64, // since the Unix epoch, when the last of the request was read
+  pub queued_micros: u64, // from being received to being executed
+  pub exec_micros: u64, // from being executed to being answered
+  pub yields: u64, // times its task gave up the executor thread before it was done
+}
+
+impl ServerTiming {
+  fn encode_into(&self, buf: &mut Vec<u8>) {
+    for value in [self.received_micros, self.queued_micros, self.exec_micros, self.yields] {
+      buf.extend_from_slice(&value.to_be_bytes());
+    }
+  }
+}
+
+pub fn now_micros() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_micros() as u64)
+}
+
+// Scheduling class of a request, independent of its type
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Ord, PartialOrd, Debug, Default)]
+pub enum Priority {
+  Low,
+  #[default]
+  Normal,
+  High,
+}
+
+impl Priority {
+  pub fn value(&self) -> u8 {
+    match self {
+      Priority::Low => 0,
+      Priority::Normal => 1,
+      Priority::High => 2,
+    }
+  }
+
+  pub fn from_value(value: u8) -> Result<Self, ParseError> {
+    match value {
+      0 => Ok(Priority::Low),
+      1 => Ok(Priority::Normal),
+      2 => Ok(Priority::High),
+      _ => Err(ParseError::MalformedPacket(format!("value {value} is not attributed to a priority")))
+    }
+  }
+
+  pub fn iterator() -> impl Iterator<Item = Priority> {
+    [Priority::High, Priority::Normal, Priority::Low].iter().copied()
+  }
+}
+
+// Who sent a request and how urgent it is
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug, Default)]
+pub struct Tags {
+  pub priority: Priority,
+  pub tenant: u64,
+}
+
+// Optional fields a request's PayloadHeader carries after its req_id, each flagged in its type byte
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Stamp {
+  pub timing: Option<Timing>,
+  pub tags: Option<Tags>,
+}
+
+impl Stamp {
+  fn flags(&self) -> u8 {
+    let mut flags = 0;
+    if self.timing.is_some() {
+      flags |= TIMED_FLAG;
+    }
+    if self.tags.is_some() {
+      flags |= TAGGED_FLAG;
+    }
+    flags
+  }
+}
+
+// What a BeRead returns besides the number of matches
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug, Default)]
+pub enum BeResults {
+  #[default]
+  Count,
+  Keys,
+  Usernames,
+  KeysAndUsernames,
+}
+
+impl BeResults {
+  pub fn value(&self) -> u8 {
+    match self {
+      BeResults::Count => 0,
+      BeResults::Keys => 1,
+      BeResults::Usernames => 2,
+      BeResults::KeysAndUsernames => 3,
+    }
+  }
+
+  pub fn from_value(value: u8) -> Result<Self, ParseError> {
+    match value {
+      0 => Ok(BeResults::Count),
+      1 => Ok(BeResults::Keys),
+      2 => Ok(BeResults::Usernames),
+      3 => Ok(BeResults::KeysAndUsernames),
+      _ => Err(ParseError::MalformedPacket(format!("value {value} is not attributed to a BeRead result mode")))
+    }
+  }
+
+  pub fn keys(&self) -> bool {
+    matches!(self, BeResults::Keys | BeResults::KeysAndUsernames)
+  }
+
+  pub fn usernames(&self) -> bool {
+    matches!(self, BeResults::Usernames | BeResults::KeysAndUsernames)
+  }
+}
+
+// How text fields (regex and glob patterns, reasons and messages) that are not valid UTF-8 are decoded.
+// Values, substrings and prefixes are matched as bytes and never checked
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug, Default)]
+pub enum Utf8Mode {
+  #[default]
+  Lossy, // invalid sequences become replacement characters
+  Strict, // invalid sequences fail the whole frame
+}
+
+// Shape of the randomly generated requests
+#[derive(Clone, Copy, Debug)]
+pub struct RequestOptions {
+  pub batch_size: usize, // keys per MultiGet and MultiPut
+  pub be_results: BeResults,
+  pub be_limit: u64, // matches a BeRead returns when it returns any
+}
+
+impl Default for RequestOptions {
+  fn default() -> Self {
+    RequestOptions { batch_size: 1, be_results: BeResults::Count, be_limit: BE_RESULT_LIMIT }
+  }
 }
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum RequestType {
+  Hello,
   BeRead,
   LcRead,
-  LcWrite
+  LcWrite,
+  LcDelete,
+  LcInsertIfAbsent,
+  LcCompareAndSwap,
+  MultiGet,
+  MultiPut,
+  RangeScan,
+  PrefixCount,
+  RegexCount,
+  GlobCount,
+  Cancel,
+  Stats
 }
 
 impl MessageType for RequestType {
     fn value(&self) -> u8 {
         match &self {
+            RequestType::Hello => HELLO_BYTE,
             RequestType::BeRead => BE_BYTE,
             RequestType::LcRead => LC_READ_BYTE,
-            RequestType::LcWrite => LC_WRITE_BYTE
+            RequestType::LcWrite => LC_WRITE_BYTE,
+            RequestType::LcDelete => LC_DELETE_BYTE,
+            RequestType::LcInsertIfAbsent => LC_INSERT_IF_ABSENT_BYTE,
+            RequestType::LcCompareAndSwap => LC_CAS_BYTE,
+            RequestType::MultiGet => MULTI_GET_BYTE,
+            RequestType::MultiPut => MULTI_PUT_BYTE,
+            RequestType::RangeScan => RANGE_SCAN_BYTE,
+            RequestType::PrefixCount => PREFIX_COUNT_BYTE,
+            RequestType::RegexCount => REGEX_COUNT_BYTE,
+            RequestType::GlobCount => GLOB_COUNT_BYTE,
+            RequestType::Cancel => CANCEL_BYTE,
+            RequestType::Stats => STATS_BYTE,
         }
     }
     
     fn from_value(value: u8) -> Result<Self, ParseError> where Self: std::marker::Sized {
         match value {
+          HELLO_BYTE => Ok(RequestType::Hello),
           BE_BYTE => Ok(RequestType::BeRead),
           LC_READ_BYTE => Ok(RequestType::LcRead),
           LC_WRITE_BYTE => Ok(RequestType::LcWrite),
+          LC_DELETE_BYTE => Ok(RequestType::LcDelete),
+          LC_INSERT_IF_ABSENT_BYTE => Ok(RequestType::LcInsertIfAbsent),
+          LC_CAS_BYTE => Ok(RequestType::LcCompareAndSwap),
+          MULTI_GET_BYTE => Ok(RequestType::MultiGet),
+          MULTI_PUT_BYTE => Ok(RequestType::MultiPut),
+          RANGE_SCAN_BYTE => Ok(RequestType::RangeScan),
+          PREFIX_COUNT_BYTE => Ok(RequestType::PrefixCount),
+          REGEX_COUNT_BYTE => Ok(RequestType::RegexCount),
+          GLOB_COUNT_BYTE => Ok(RequestType::GlobCount),
+          CANCEL_BYTE => Ok(RequestType::Cancel),
+          STATS_BYTE => Ok(RequestType::Stats),
           _ => Err(ParseError::InvalidMessageType(value))
         }
     }
     
     fn expected_len(&self) -> Option<usize> {
         match &self {
+            RequestType::Hello => Some(HELLO_LEN),
             RequestType::BeRead => None,
             RequestType::LcRead => Some(2*size_of::<u64>()),
             RequestType::LcWrite => None,
+            RequestType::LcDelete => Some(2*size_of::<u64>()),
+            RequestType::LcInsertIfAbsent => None,
+            RequestType::LcCompareAndSwap => None,
+            RequestType::MultiGet => None,
+            RequestType::MultiPut => None,
+            RequestType::RangeScan => Some(4*size_of::<u64>()),
+            RequestType::PrefixCount => None,
+            RequestType::RegexCount => None,
+            RequestType::GlobCount => None,
+            RequestType::Cancel => Some(size_of::<u64>()),
+            RequestType::Stats => Some(size_of::<u64>()),
         }
     }
 
     fn iterator() -> impl Iterator<Item = RequestType> {
-      [RequestType::BeRead, RequestType::LcRead, RequestType::LcWrite].iter().copied()
+      [RequestType::Hello, RequestType::BeRead, RequestType::LcRead, RequestType::LcWrite, RequestType::LcDelete, RequestType::LcInsertIfAbsent, RequestType::LcCompareAndSwap,
+        RequestType::MultiGet, RequestType::MultiPut, RequestType::RangeScan, RequestType::PrefixCount, RequestType::RegexCount, RequestType::GlobCount, RequestType::Cancel,
+        RequestType::Stats].iter().copied()
     }
 }
 
 struct MessageHeader<T: MessageType> {
   kind: T,
   payload_len: usize,
+  flags: u8, // the high bits of the type byte, see COMPRESSED_FLAG, TIMED_FLAG and TAGGED_FLAG
 }
 
 impl<T: MessageType> MessageHeader<T> {
   fn new(kind: T, payload_len: usize) -> Self {
-    MessageHeader { kind, payload_len }
+    MessageHeader { kind, payload_len, flags: 0 }
+  }
+
+  fn compressed(&self) -> bool {
+    self.flags & COMPRESSED_FLAG != 0
   }
 }
 
@@ -77,44 +397,80 @@ impl<T: MessageType> Header for MessageHeader<T> {
     1 + LEN_LENGTH
   }
 
-  fn len(&self) -> usize {
-    MessageHeader::<T>::expected_len()
+  fn len(&self, layout: Layout) -> usize {
+    match layout {
+      Layout::Fixed => MessageHeader::<T>::expected_len(),
+      Layout::Compact => 1 + varint_len(self.payload_len as u64),
+    }
   }
 
-  fn deserialize(packet: &[u8]) -> Result<MessageHeader<T>,ParseError> {
+  fn deserialize(packet: &[u8], layout: Layout) -> Result<MessageHeader<T>,ParseError> {
     // Check for header (kind + Payload_len)
-    check_length(packet.len(), MessageHeader::<T>::expected_len())?;
-    let kind = T::from_value(packet[0])?;
-    let len: [u8; 8] = packet[1..(LEN_LENGTH + 1)].try_into().unwrap();
-    let payload_len: usize = u64::from_be_bytes(len).try_into().unwrap();
-
-    // If request has a specific length, validate
-    if let Some(exp_len) = kind.expected_len() {
-      if exp_len != payload_len {
-        return Err(ParseError::UnexpectedLength { payload_len, exp_len });
-      }
+    let (kind, payload_len, _) = frame_header(packet, layout)?.ok_or(ParseError::PacketTooShort)?;
+    let payload_len: usize = payload_len.try_into()
+      .map_err(|_| ParseError::FrameTooLarge { kind, payload_len, max_payload_len: usize::MAX })?;
+    let flags = kind & !KIND_MASK;
+    let kind = T::from_value(kind & KIND_MASK)?;
+
+    // If request has a specific length, validate. Compact payloads vary, their bodies are checked when decoded
+    if layout == Layout::Fixed
+      && flags & COMPRESSED_FLAG == 0
+      && let Some(exp_len) = kind.expected_len()
+      && let exp_len = exp_len + PayloadHeader::stamp_len(flags)
+      && exp_len != payload_len {
+      return Err(ParseError::UnexpectedLength { payload_len, exp_len });
     }
     Ok(MessageHeader {
       kind, 
-      payload_len
+      payload_len,
+      flags,
     })
   }
   
-  fn serialize(&self) -> Vec<u8> {
-    let mut packet: Vec<u8> = Vec::new();
-    packet.push(self.kind.value());
-    packet.extend_from_slice(&(self.payload_len as u64).to_be_bytes());
-    packet
+  fn encode_into(&self, buf: &mut Vec<u8>, layout: Layout) {
+    buf.push(self.kind.value() | self.flags);
+    put_int(buf, self.payload_len as u64, layout);
   }
 }
 
+// send time and deadline following the req_id of a timed request, a deadline of 0 meaning none
+const TIMING_LEN: usize = 2 * LEN_LENGTH;
+// priority and tenant following the req_id, and the timing if there is one, of a tagged request
+const TAGS_LEN: usize = 1 + LEN_LENGTH;
+
 struct PayloadHeader {
-  req_id: u64
+  req_id: u64,
+  stamp: Stamp,
 }
 
 impl PayloadHeader {
   fn new(req_id: u64) -> Self {
-    PayloadHeader { req_id }
+    PayloadHeader { req_id, stamp: Stamp::default() }
+  }
+
+  // fixed layout bytes the fields flagged in `flags` add to a payload
+  fn stamp_len(flags: u8) -> usize {
+    let timing = if flags & TIMED_FLAG != 0 { TIMING_LEN } else { 0 };
+    let tags = if flags & TAGGED_FLAG != 0 { TAGS_LEN } else { 0 };
+    timing + tags
+  }
+
+  // the Header trait cannot tell which fields follow the req_id, which the MessageHeader's flags do
+  fn deserialize_with(payload: &[u8], layout: Layout, flags: u8) -> Result<PayloadHeader, ParseError> {
+    let mut rest = payload;
+    let req_id = take_int(&mut rest, layout)?;
+    let mut stamp = Stamp::default();
+    if flags & TIMED_FLAG != 0 {
+      let sent_micros = take_int(&mut rest, layout)?;
+      let deadline_micros = take_int(&mut rest, layout)?;
+      stamp.timing = Some(Timing { sent_micros, deadline_micros: (deadline_micros > 0).then_some(deadline_micros) });
+    }
+    if flags & TAGGED_FLAG != 0 {
+      let priority = Priority::from_value(take(&mut rest, 1)?[0])?;
+      let tenant = take_int(&mut rest, layout)?;
+      stamp.tags = Some(Tags { priority, tenant });
+    }
+    Ok(PayloadHeader { req_id, stamp })
   }
 }
 
@@ -123,28 +479,46 @@ impl Header for PayloadHeader {
     LEN_LENGTH
   }
 
-  fn len(&self) -> usize {
-    PayloadHeader::expected_len()
+  fn len(&self, layout: Layout) -> usize {
+    let timing = self.stamp.timing.map_or(0, |timing| {
+      int_len(timing.sent_micros, layout) + int_len(timing.deadline_micros.unwrap_or(0), layout)
+    });
+    let tags = self.stamp.tags.map_or(0, |tags| 1 + int_len(tags.tenant, layout));
+    int_len(self.req_id, layout) + timing + tags
   }
 
-  fn serialize(&self) -> Vec<u8> {
-      self.req_id.to_be_bytes().to_vec()
+  fn encode_into(&self, buf: &mut Vec<u8>, layout: Layout) {
+    put_int(buf, self.req_id, layout);
+    if let Some(timing) = self.stamp.timing {
+      put_int(buf, timing.sent_micros, layout);
+      put_int(buf, timing.deadline_micros.unwrap_or(0), layout);
+    }
+    if let Some(tags) = self.stamp.tags {
+      buf.push(tags.priority.value());
+      put_int(buf, tags.tenant, layout);
+    }
   }
 
-  fn deserialize(payload: &[u8]) -> Result<PayloadHeader, ParseError> {
-    // payload should have at least the req_id bytes
-    check_length(payload.len(), PayloadHeader::expected_len())?;
-    let req_id_slice: [u8; 8] = payload[0..LEN_LENGTH].try_into().unwrap();
-    let req_id = u64::from_be_bytes(req_id_slice);
-    Ok(PayloadHeader { req_id })
+  fn deserialize(payload: &[u8], layout: Layout) -> Result<PayloadHeader, ParseError> {
+    PayloadHeader::deserialize_with(payload, layout, 0)
   }
 }
 
+// req_id + version + features
+const HELLO_LEN: usize = size_of::<u64>() + size_of::<u16>() + size_of::<u32>();
+
 #[derive(Clone, Debug)]
 pub enum Request {
+  Hello {
+    req_id: u64,
+    version: u16,
+    features: Features
+  },
   BeRead {
     req_id: u64,
-    substring: String
+    substring: Vec<u8>,
+    results: BeResults,
+    limit: u64
   },
   LcRead {
     req_id: u64,
@@ -153,170 +527,541 @@ pub enum Request {
   LcWrite {
     req_id: u64,
     id: u64,
-    username: String
+    username: Vec<u8>
+  },
+  LcDelete {
+    req_id: u64,
+    id: u64
+  },
+  LcInsertIfAbsent {
+    req_id: u64,
+    id: u64,
+    username: Vec<u8>
+  },
+  LcCompareAndSwap {
+    req_id: u64,
+    id: u64,
+    expected: Vec<u8>,
+    username: Vec<u8>
+  },
+  MultiGet {
+    req_id: u64,
+    ids: Vec<u64>
+  },
+  MultiPut {
+    req_id: u64,
+    entries: Vec<(u64, Vec<u8>)>
+  },
+  RangeScan {
+    req_id: u64,
+    start: u64,
+    end: u64, // exclusive
+    limit: u64
+  },
+  PrefixCount {
+    req_id: u64,
+    prefix: Vec<u8>
+  },
+  RegexCount {
+    req_id: u64,
+    pattern: String
+  },
+  GlobCount {
+    req_id: u64,
+    pattern: String
+  },
+  // not answered itself: the request it names is answered with Cancelled instead, unless it was already answered
+  Cancel {
+    req_id: u64 // of the request to abort
+  },
+  // asks for the server's counters, answered even while other connections are busy and leaving out the
+  // connection asking
+  Stats {
+    req_id: u64
   }
 }
 
 impl Request {
+  pub fn req_id(&self) -> u64 {
+    match self {
+      Request::Hello { req_id, .. } | Request::BeRead { req_id, .. } | Request::LcRead { req_id, .. } | Request::LcWrite { req_id, .. }
+        | Request::LcDelete { req_id, .. } | Request::LcInsertIfAbsent { req_id, .. } | Request::LcCompareAndSwap { req_id, .. }
+        | Request::MultiGet { req_id, .. } | Request::MultiPut { req_id, .. } | Request::RangeScan { req_id, .. }
+        | Request::PrefixCount { req_id, .. } | Request::RegexCount { req_id, .. } | Request::GlobCount { req_id, .. } | Request::Cancel { req_id }
+        | Request::Stats { req_id } => *req_id
+    }
+  }
+
   pub fn random(kind: RequestType, req_id: u64) -> Request {
+    Request::random_in(kind, req_id, 0..CAPACITY as u64, &RequestOptions::default())
+  }
+
+  // random request whose keys are drawn from `keys`
+  pub fn random_in(kind: RequestType, req_id: u64, keys: Range<u64>, options: &RequestOptions) -> Request {
     match kind {
+        RequestType::Hello => {
+            Request::Hello { req_id, version: PROTOCOL_VERSION, features: Features::NONE }
+          },
         RequestType::BeRead => {
             Request::BeRead {
               req_id,
-              substring: Alphanumeric.sample_string(&mut rand::rng(), SUBSTRING_LEN) 
+              substring: Alphanumeric.sample_string(&mut rand::rng(), SUBSTRING_LEN).into_bytes(),
+              results: options.be_results,
+              limit: options.be_limit
             }
           },
         RequestType::LcRead => {
             Request::LcRead { 
               req_id,
-              id: rand::rng().random_range(0..CAPACITY).try_into().unwrap()
+              id: rand::rng().random_range(keys)
             }
           }
         RequestType::LcWrite => {
             Request::LcWrite {
                 req_id,
-                id: rand::rng().random_range(0..CAPACITY).try_into().unwrap(),
-                username: Alphanumeric.sample_string(&mut rand::rng(), rand::rng().random_range(0..10).try_into().unwrap()),
+                id: rand::rng().random_range(keys),
+                username: random_username(),
             }
         },
+        RequestType::LcDelete => {
+            Request::LcDelete {
+              req_id,
+              id: rand::rng().random_range(keys)
+            }
+          },
+        RequestType::LcInsertIfAbsent => {
+            Request::LcInsertIfAbsent {
+              req_id,
+              id: rand::rng().random_range(keys),
+              username: random_username(),
+            }
+          },
+        RequestType::LcCompareAndSwap => {
+            Request::LcCompareAndSwap {
+              req_id,
+              id: rand::rng().random_range(keys),
+              expected: random_username(),
+              username: random_username(),
+            }
+          },
+        RequestType::MultiGet => {
+            Request::MultiGet {
+              req_id,
+              ids: (0..options.batch_size).map(|_| rand::rng().random_range(keys.clone())).collect()
+            }
+          },
+        RequestType::MultiPut => {
+            Request::MultiPut {
+              req_id,
+              entries: (0..options.batch_size).map(|_| (rand::rng().random_range(keys.clone()), random_username())).collect()
+            }
+          },
+        RequestType::RangeScan => {
+            let start = rand::rng().random_range(keys.clone());
+            Request::RangeScan {
+              req_id,
+              start,
+              end: keys.end.min(start.saturating_add(RANGE_SCAN_SPAN)),
+              limit: RANGE_SCAN_LIMIT
+            }
+          },
+        RequestType::PrefixCount => {
+            Request::PrefixCount {
+              req_id,
+              prefix: Alphanumeric.sample_string(&mut rand::rng(), PREFIX_LEN).into_bytes()
+            }
+          },
+        RequestType::RegexCount => {
+            let (first, last) = (Alphanumeric.sample_string(&mut rand::rng(), 1), Alphanumeric.sample_string(&mut rand::rng(), 1));
+            Request::RegexCount {
+              req_id,
+              pattern: format!("^{first}.*{last}[0-9]*$")
+            }
+          },
+        RequestType::GlobCount => {
+            let (first, last) = (Alphanumeric.sample_string(&mut rand::rng(), 1), Alphanumeric.sample_string(&mut rand::rng(), 1));
+            Request::GlobCount {
+              req_id,
+              pattern: format!("*{first}?{last}*")
+            }
+          },
+        RequestType::Cancel => {
+            Request::Cancel { req_id }
+          },
+        RequestType::Stats => {
+            Request::Stats { req_id }
+          },
     }
   }
 }
 
+fn random_username() -> Vec<u8> {
+  Alphanumeric.sample_string(&mut rand::rng(), rand::rng().random_range(0..10)).into_bytes()
+}
+
 impl Message for Request {
   type Tag = RequestType;
 
   fn kind(&self) -> RequestType {
       match &self {
+        Request::Hello { .. } => RequestType::Hello,
         Request::BeRead { .. } => RequestType::BeRead,
         Request::LcRead { .. } => RequestType::LcRead,
         Request::LcWrite { .. } => RequestType::LcWrite,
+        Request::LcDelete { .. } => RequestType::LcDelete,
+        Request::LcInsertIfAbsent { .. } => RequestType::LcInsertIfAbsent,
+        Request::LcCompareAndSwap { .. } => RequestType::LcCompareAndSwap,
+        Request::MultiGet { .. } => RequestType::MultiGet,
+        Request::MultiPut { .. } => RequestType::MultiPut,
+        Request::RangeScan { .. } => RequestType::RangeScan,
+        Request::PrefixCount { .. } => RequestType::PrefixCount,
+        Request::RegexCount { .. } => RequestType::RegexCount,
+        Request::GlobCount { .. } => RequestType::GlobCount,
+        Request::Cancel { .. } => RequestType::Cancel,
+        Request::Stats { .. } => RequestType::Stats,
       }
   }
 
-  fn serialize(&self) -> Vec<u8> {
-    let payload = match self {
-      Request::BeRead { substring, req_id } => {
-        let mut payload: Vec<u8> = PayloadHeader::new(*req_id).serialize();
-        payload.extend_from_slice(substring.as_bytes());
-        payload
+  fn encode_with(&self, buf: &mut Vec<u8>, layout: Layout) {
+    let start = buf.len();
+    MessageHeader::new(self.kind(), 0).encode_into(buf, layout);
+    PayloadHeader::new(self.req_id()).encode_into(buf, layout);
+    match self {
+      Request::Hello { version, features, .. } => {
+        buf.extend_from_slice(&version.to_be_bytes());
+        buf.extend_from_slice(&features.bits().to_be_bytes());
+      },
+      Request::Cancel { .. } | Request::Stats { .. } => {},
+      Request::BeRead { substring, results, limit, .. } => {
+        buf.push(results.value());
+        buf.extend_from_slice(&limit.to_be_bytes());
+        buf.extend_from_slice(substring);
+      },
+      Request::PrefixCount { prefix, .. } => {
+        buf.extend_from_slice(prefix);
       },
-      Request::LcRead { req_id, id } => {
-        let mut payload: Vec<u8> = PayloadHeader::new(*req_id).serialize();
-        payload.extend_from_slice(&id.to_be_bytes());
-        payload
+      Request::RegexCount { pattern, .. } | Request::GlobCount { pattern, .. } => {
+        buf.extend_from_slice(pattern.as_bytes());
       },
-      Request::LcWrite { req_id, id, username } => {
-        let mut payload: Vec<u8> = PayloadHeader::new(*req_id).serialize();
-        assert!(payload.len() == 8);
-        payload.extend_from_slice(&id.to_be_bytes());
-        assert!(payload.len() == 16);
-        payload.extend_from_slice(username.as_bytes());
-        payload
+      Request::RangeScan { start, end, limit, .. } => {
+        buf.extend_from_slice(&start.to_be_bytes());
+        buf.extend_from_slice(&end.to_be_bytes());
+        buf.extend_from_slice(&limit.to_be_bytes());
+      },
+      Request::LcRead { id, .. } | Request::LcDelete { id, .. } => {
+        put_int(buf, *id, layout);
+      },
+      Request::LcCompareAndSwap { id, expected, username, .. } => {
+        // the expected value is length prefixed, the new value takes the rest of the payload
+        put_int(buf, *id, layout);
+        buf.extend_from_slice(&(expected.len() as u64).to_be_bytes());
+        buf.extend_from_slice(expected);
+        buf.extend_from_slice(username);
+      },
+      Request::MultiGet { ids, .. } => {
+        buf.extend_from_slice(&(ids.len() as u64).to_be_bytes());
+        for id in ids {
+          buf.extend_from_slice(&id.to_be_bytes());
+        }
+      },
+      Request::MultiPut { entries, .. } => {
+        serialize_entries(buf, entries);
+      },
+      Request::LcWrite { id, username, .. } | Request::LcInsertIfAbsent { id, username, .. } => {
+        put_int(buf, *id, layout);
+        buf.extend_from_slice(username);
       }
-    };
-    let mut packet = MessageHeader::new(self.kind(), payload.len()).serialize();
-    packet.extend_from_slice(&payload);
-    packet
+    }
+    patch_payload_len(buf, start, layout);
   }
 
   fn deserialize(packet: &[u8]) -> Result<Self, ParseError> {
-    let header = MessageHeader::deserialize(packet)?;
-    
-    // Check for payload length
-    check_length(packet.len(), header.len() + header.payload_len)?;
-    let payload = &packet[header.len()..(header.len() + header.payload_len)];
-    let payload_header = PayloadHeader::deserialize(payload)?;
-    
-    let rest_payload = &payload[payload_header.len()..];
-    match header.kind {
-        RequestType::BeRead => {
-          check_length(rest_payload.len(), 1)?;
-          let str = String::from_utf8_lossy(rest_payload).to_string();
-          Ok(Request::BeRead { req_id: payload_header.req_id, substring: str })
-        },
-        RequestType::LcRead => {
-          let id = u64::from_be_bytes(rest_payload.try_into().unwrap()); // byte check already done
-          Ok(Request::LcRead { req_id: payload_header.req_id, id })
-        },
-        RequestType::LcWrite => {
-          check_length(rest_payload.len(), LEN_LENGTH)?;
-          let id = u64::from_be_bytes(rest_payload[0..LEN_LENGTH].try_into().unwrap());
-          
-          let username = if rest_payload.len() == LEN_LENGTH {
-            "".to_string()
-          } else {
-            let uname_slice = &rest_payload[LEN_LENGTH..];
-            String::from_utf8_lossy(uname_slice).to_string()
-          };
-          
-          Ok(Request::LcWrite { req_id: payload_header.req_id, id, username })
-        }
+    RequestView::decode(packet).map(RequestView::into_owned)
+  }
+}
+
+// Why the server could not answer a request, carried by an Error response
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
+pub enum ErrorCode {
+  Malformed,
+  UnknownType,
+  KeyOutOfRange,
+  Overloaded,
+  Internal,
+  TooLarge,
+}
+
+impl ErrorCode {
+  pub fn value(&self) -> u8 {
+    match self {
+      ErrorCode::Malformed => 1,
+      ErrorCode::UnknownType => 2,
+      ErrorCode::KeyOutOfRange => 3,
+      ErrorCode::Overloaded => 4,
+      ErrorCode::Internal => 5,
+      ErrorCode::TooLarge => 6,
+    }
+  }
+
+  pub fn from_value(value: u8) -> Result<Self, ParseError> {
+    match value {
+      1 => Ok(ErrorCode::Malformed),
+      2 => Ok(ErrorCode::UnknownType),
+      3 => Ok(ErrorCode::KeyOutOfRange),
+      4 => Ok(ErrorCode::Overloaded),
+      5 => Ok(ErrorCode::Internal),
+      6 => Ok(ErrorCode::TooLarge),
+      _ => Err(ParseError::InvalidErrorCode(value))
+    }
+  }
+
+  pub fn iterator() -> impl Iterator<Item = ErrorCode> {
+    [ErrorCode::Malformed, ErrorCode::UnknownType, ErrorCode::KeyOutOfRange, ErrorCode::Overloaded, ErrorCode::Internal, ErrorCode::TooLarge].iter().copied()
+  }
+}
+
+impl From<&ParseError> for ErrorCode {
+  fn from(value: &ParseError) -> Self {
+    match value {
+      ParseError::InvalidMessageType(_) => ErrorCode::UnknownType,
+      ParseError::FrameTooLarge { .. } => ErrorCode::TooLarge,
+      _ => ErrorCode::Malformed,
     }
   }
 }
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
 pub enum ResponseType {
+  HelloAck,
+  HelloReject,
+  Error,
+  Chunk,
+  Drop,
   BeRead,
   LcRead,
-  LcWrite
+  LcWrite,
+  LcDelete,
+  LcInsertIfAbsent,
+  LcCompareAndSwap,
+  MultiGet,
+  MultiPut,
+  RangeScan,
+  PrefixCount,
+  RegexCount,
+  GlobCount,
+  Cancelled,
+  Stats
 }
 
 impl MessageType for ResponseType {
   fn value(&self) -> u8 {
       match &self {
+          ResponseType::HelloAck => HELLO_BYTE,
+          ResponseType::HelloReject => HELLO_REJECT_BYTE,
+          ResponseType::Error => ERROR_BYTE,
+          ResponseType::Chunk => CHUNK_BYTE,
+          ResponseType::Drop => DROP_BYTE,
           ResponseType::BeRead => BE_BYTE,
           ResponseType::LcRead => LC_READ_BYTE,
           ResponseType::LcWrite => LC_WRITE_BYTE,
+          ResponseType::LcDelete => LC_DELETE_BYTE,
+          ResponseType::LcInsertIfAbsent => LC_INSERT_IF_ABSENT_BYTE,
+          ResponseType::LcCompareAndSwap => LC_CAS_BYTE,
+          ResponseType::MultiGet => MULTI_GET_BYTE,
+          ResponseType::MultiPut => MULTI_PUT_BYTE,
+          ResponseType::RangeScan => RANGE_SCAN_BYTE,
+          ResponseType::PrefixCount => PREFIX_COUNT_BYTE,
+          ResponseType::RegexCount => REGEX_COUNT_BYTE,
+          ResponseType::GlobCount => GLOB_COUNT_BYTE,
+          ResponseType::Cancelled => CANCEL_BYTE,
+          ResponseType::Stats => STATS_BYTE,
       }
   }
   
   fn from_value(value: u8) -> Result<Self, ParseError> where Self: std::marker::Sized {
       match value {
+        HELLO_BYTE => Ok(ResponseType::HelloAck),
+        HELLO_REJECT_BYTE => Ok(ResponseType::HelloReject),
+        ERROR_BYTE => Ok(ResponseType::Error),
+        CHUNK_BYTE => Ok(ResponseType::Chunk),
+        DROP_BYTE => Ok(ResponseType::Drop),
         BE_BYTE => Ok(ResponseType::BeRead),
         LC_READ_BYTE => Ok(ResponseType::LcRead),
         LC_WRITE_BYTE => Ok(ResponseType::LcWrite),
+        LC_DELETE_BYTE => Ok(ResponseType::LcDelete),
+        LC_INSERT_IF_ABSENT_BYTE => Ok(ResponseType::LcInsertIfAbsent),
+        LC_CAS_BYTE => Ok(ResponseType::LcCompareAndSwap),
+        MULTI_GET_BYTE => Ok(ResponseType::MultiGet),
+        MULTI_PUT_BYTE => Ok(ResponseType::MultiPut),
+        RANGE_SCAN_BYTE => Ok(ResponseType::RangeScan),
+        PREFIX_COUNT_BYTE => Ok(ResponseType::PrefixCount),
+        REGEX_COUNT_BYTE => Ok(ResponseType::RegexCount),
+        GLOB_COUNT_BYTE => Ok(ResponseType::GlobCount),
+        CANCEL_BYTE => Ok(ResponseType::Cancelled),
+        STATS_BYTE => Ok(ResponseType::Stats),
         _ => Err(ParseError::InvalidMessageType(value))
       }
   }
   
   fn expected_len(&self) -> Option<usize> {
       match &self {
-        ResponseType::BeRead => Some(2*size_of::<u64>()),
+        ResponseType::HelloAck => Some(HELLO_LEN),
+        ResponseType::HelloReject => None,
+        ResponseType::Error => None,
+        ResponseType::Chunk => None,
+        ResponseType::Drop => Some(2*size_of::<u64>()),
+        ResponseType::BeRead => None,
         ResponseType::LcRead => None,
         ResponseType::LcWrite => None,
+        ResponseType::LcDelete => None,
+        ResponseType::LcInsertIfAbsent => None,
+        ResponseType::LcCompareAndSwap => None,
+        ResponseType::MultiGet => None,
+        ResponseType::MultiPut => None,
+        ResponseType::RangeScan => None,
+        ResponseType::PrefixCount => Some(2*size_of::<u64>()),
+        ResponseType::RegexCount => Some(2*size_of::<u64>()),
+        ResponseType::GlobCount => Some(2*size_of::<u64>()),
+        ResponseType::Cancelled => Some(size_of::<u64>()),
+        ResponseType::Stats => None,
       }
   }
 
   fn iterator() -> impl Iterator<Item = ResponseType> {
-    [ResponseType::BeRead, ResponseType::LcRead, ResponseType::LcWrite].iter().copied()
+    [ResponseType::HelloAck, ResponseType::HelloReject, ResponseType::Error, ResponseType::Chunk, ResponseType::Drop, ResponseType::BeRead,
+      ResponseType::LcRead, ResponseType::LcWrite, ResponseType::LcDelete, ResponseType::LcInsertIfAbsent, ResponseType::LcCompareAndSwap, ResponseType::MultiGet, ResponseType::MultiPut,
+      ResponseType::RangeScan, ResponseType::PrefixCount, ResponseType::RegexCount, ResponseType::GlobCount, ResponseType::Cancelled,
+      ResponseType::Stats].iter().copied()
   }
 }
 
 impl ResponseType {
   pub fn from_request(req: RequestType) -> ResponseType {
     match req {
+        RequestType::Hello => ResponseType::HelloAck,
         RequestType::BeRead => ResponseType::BeRead,
         RequestType::LcRead => ResponseType::LcRead,
         RequestType::LcWrite => ResponseType::LcWrite,
+        RequestType::LcDelete => ResponseType::LcDelete,
+        RequestType::LcInsertIfAbsent => ResponseType::LcInsertIfAbsent,
+        RequestType::LcCompareAndSwap => ResponseType::LcCompareAndSwap,
+        RequestType::MultiGet => ResponseType::MultiGet,
+        RequestType::MultiPut => ResponseType::MultiPut,
+        RequestType::RangeScan => ResponseType::RangeScan,
+        RequestType::PrefixCount => ResponseType::PrefixCount,
+        RequestType::RegexCount => ResponseType::RegexCount,
+        RequestType::GlobCount => ResponseType::GlobCount,
+        RequestType::Cancel => ResponseType::Cancelled,
+        RequestType::Stats => ResponseType::Stats,
     }
   }
 }
 
 #[derive(Clone, Debug)]
 pub enum Response {
+  HelloAck {
+    req_id: u64,
+    version: u16,
+    features: Features // negotiated
+  },
+  HelloReject {
+    req_id: u64,
+    version: u16, // spoken by the server
+    reason: String
+  },
+  Error {
+    req_id: u64,
+    code: ErrorCode,
+    message: String
+  },
+  // part of a streamed result, the stream ends with the request's own response carrying the last entries
+  Chunk {
+    req_id: u64,
+    entries: Vec<(u64, Vec<u8>)>
+  },
+  // answer to a request whose deadline passed before it was executed
+  Drop {
+    req_id: u64,
+    queued_micros: u64 // how long it waited, from its send time to being dropped
+  },
   BeRead {
     req_id: u64,
-    freq: u64
+    freq: u64,
+    // matches up to the request's limit, each only filled in if the request asked for it
+    keys: Vec<u64>,
+    usernames: Vec<Vec<u8>>
   },
   LcRead {
     req_id: u64,
-    username: Option<String>
+    username: Option<Vec<u8>>
   },
   LcWrite {
     req_id: u64,
-    username: Option<String>
+    username: Option<Vec<u8>>
+  },
+  LcDelete {
+    req_id: u64,
+    username: Option<Vec<u8>> // removed value
+  },
+  LcInsertIfAbsent {
+    req_id: u64,
+    username: Option<Vec<u8>> // value already present, None if the insert happened
+  },
+  LcCompareAndSwap {
+    req_id: u64,
+    swapped: bool,
+    username: Option<Vec<u8>> // value found before the swap
+  },
+  MultiGet {
+    req_id: u64,
+    usernames: Vec<Option<Vec<u8>>> // one per requested id, in request order
+  },
+  MultiPut {
+    req_id: u64,
+    usernames: Vec<Option<Vec<u8>>> // replaced values, in request order
+  },
+  RangeScan {
+    req_id: u64,
+    entries: Vec<(u64, Vec<u8>)>, // present keys in ascending order
+    cursor: Option<u64> // key to continue the scan from, None once the range is exhausted
+  },
+  PrefixCount {
+    req_id: u64,
+    freq: u64
+  },
+  RegexCount {
+    req_id: u64,
+    freq: u64
+  },
+  GlobCount {
+    req_id: u64,
+    freq: u64
+  },
+  // answer to a request aborted by a Cancel, before or while it was executed
+  Cancelled {
+    req_id: u64
+  },
+  Stats {
+    req_id: u64,
+    counters: Vec<(String, u64)> // named counters and gauges, as the server keeps them
+  }
+}
+
+impl Response {
+  pub fn req_id(&self) -> u64 {
+    match self {
+      Response::HelloAck { req_id, .. } | Response::HelloReject { req_id, .. } | Response::Error { req_id, .. } | Response::Chunk { req_id, .. } | Response::Drop { req_id, .. }
+        | Response::BeRead { req_id, .. } | Response::LcRead { req_id, .. } | Response::LcWrite { req_id, .. }
+        | Response::LcDelete { req_id, .. } | Response::LcInsertIfAbsent { req_id, .. } | Response::LcCompareAndSwap { req_id, .. }
+        | Response::MultiGet { req_id, .. } | Response::MultiPut { req_id, .. } | Response::RangeScan { req_id, .. }
+        | Response::PrefixCount { req_id, .. } | Response::RegexCount { req_id, .. } | Response::GlobCount { req_id, .. } | Response::Cancelled { req_id }
+        | Response::Stats { req_id, .. } => *req_id
+    }
+  }
+
+  // answer to a frame that could not be parsed as a request
+  pub fn parse_error(frame: &[u8], layout: Layout, err: &ParseError) -> Response {
+    Response::Error { req_id: frame_req_id(frame, layout).unwrap_or(0), code: ErrorCode::from(err), message: err.to_string() }
   }
 }
 
@@ -325,78 +1070,299 @@ impl Message for Response {
 
   fn kind(&self) -> ResponseType {
       match &self {
+        Response::HelloAck { .. } => ResponseType::HelloAck,
+        Response::HelloReject { .. } => ResponseType::HelloReject,
+        Response::Error { .. } => ResponseType::Error,
+        Response::Chunk { .. } => ResponseType::Chunk,
+        Response::Drop { .. } => ResponseType::Drop,
         Response::BeRead { .. } => ResponseType::BeRead,
         Response::LcRead { .. } => ResponseType::LcRead,
         Response::LcWrite { .. } => ResponseType::LcWrite,
+        Response::LcDelete { .. } => ResponseType::LcDelete,
+        Response::LcInsertIfAbsent { .. } => ResponseType::LcInsertIfAbsent,
+        Response::LcCompareAndSwap { .. } => ResponseType::LcCompareAndSwap,
+        Response::MultiGet { .. } => ResponseType::MultiGet,
+        Response::MultiPut { .. } => ResponseType::MultiPut,
+        Response::RangeScan { .. } => ResponseType::RangeScan,
+        Response::PrefixCount { .. } => ResponseType::PrefixCount,
+        Response::RegexCount { .. } => ResponseType::RegexCount,
+        Response::GlobCount { .. } => ResponseType::GlobCount,
+        Response::Cancelled { .. } => ResponseType::Cancelled,
+        Response::Stats { .. } => ResponseType::Stats,
       }
   }
 
-  fn serialize(&self) -> Vec<u8> {
-    let payload = match self {
-      Response::BeRead { req_id, freq } => {
-        let mut payload = PayloadHeader::new(*req_id).serialize();
-        payload.extend_from_slice(&freq.to_be_bytes());
-
-        payload
+  fn encode_with(&self, buf: &mut Vec<u8>, layout: Layout) {
+    let start = buf.len();
+    MessageHeader::new(self.kind(), 0).encode_into(buf, layout);
+    PayloadHeader::new(self.req_id()).encode_into(buf, layout);
+    match self {
+      Response::HelloAck { version, features, .. } => {
+        buf.extend_from_slice(&version.to_be_bytes());
+        buf.extend_from_slice(&features.bits().to_be_bytes());
+      },
+      Response::HelloReject { version, reason, .. } => {
+        buf.extend_from_slice(&version.to_be_bytes());
+        buf.extend_from_slice(reason.as_bytes());
+      },
+      Response::Error { code, message, .. } => {
+        buf.push(code.value());
+        buf.extend_from_slice(message.as_bytes());
+      },
+      Response::BeRead { freq, keys, usernames, .. } => {
+        buf.extend_from_slice(&freq.to_be_bytes());
+        buf.extend_from_slice(&(keys.len() as u64).to_be_bytes());
+        for key in keys {
+          buf.extend_from_slice(&key.to_be_bytes());
+        }
+        buf.extend_from_slice(&(usernames.len() as u64).to_be_bytes());
+        for username in usernames {
+          buf.extend_from_slice(&(username.len() as u64).to_be_bytes());
+          buf.extend_from_slice(username);
+        }
+      },
+      Response::PrefixCount { freq, .. } | Response::RegexCount { freq, .. } | Response::GlobCount { freq, .. } => {
+        buf.extend_from_slice(&freq.to_be_bytes());
       },
-      Response::LcRead { req_id, username } | Response::LcWrite { req_id, username } => {
-        let mut payload = PayloadHeader::new(*req_id).serialize();
-        match username {
+      Response::Drop { queued_micros, .. } => {
+        buf.extend_from_slice(&queued_micros.to_be_bytes());
+      },
+      Response::Cancelled { .. } => {},
+      Response::Stats { counters, .. } => {
+        buf.extend_from_slice(&(counters.len() as u64).to_be_bytes());
+        for (name, value) in counters {
+          buf.extend_from_slice(&(name.len() as u64).to_be_bytes());
+          buf.extend_from_slice(name.as_bytes());
+          buf.extend_from_slice(&value.to_be_bytes());
+        }
+      },
+      Response::LcRead { username, .. } | Response::LcWrite { username, .. }
+        | Response::LcDelete { username, .. } | Response::LcInsertIfAbsent { username, .. } => {
+        serialize_username(buf, username);
+      },
+      Response::LcCompareAndSwap { swapped, username, .. } => {
+        buf.push(*swapped as u8);
+        serialize_username(buf, username);
+      },
+      Response::MultiGet { usernames, .. } | Response::MultiPut { usernames, .. } => {
+        buf.extend_from_slice(&(usernames.len() as u64).to_be_bytes());
+        for username in usernames {
+          match username {
             Some(username) => {
-              payload.push(SOME_BYTE);
-              payload.extend_from_slice(username.as_bytes());
-            },
-            None => {
-              payload.push(NONE_BYTE);
+              buf.push(SOME_BYTE);
+              buf.extend_from_slice(&(username.len() as u64).to_be_bytes());
+              buf.extend_from_slice(username);
             },
+            None => buf.push(NONE_BYTE),
+          }
+        }
+      },
+      Response::RangeScan { entries, cursor, .. } => {
+        match cursor {
+          Some(cursor) => {
+            buf.push(SOME_BYTE);
+            buf.extend_from_slice(&cursor.to_be_bytes());
+          },
+          None => buf.push(NONE_BYTE),
         }
-        payload
+        serialize_entries(buf, entries);
+      },
+      Response::Chunk { entries, .. } => {
+        serialize_entries(buf, entries);
       }
-    };
-    let mut packet = MessageHeader::new(self.kind(), payload.len()).serialize();
-    packet.extend_from_slice(&payload);
-    packet
+    }
+    patch_payload_len(buf, start, layout);
   }
 
   fn deserialize(packet: &[u8]) -> Result<Self, ParseError> {
-    let header: MessageHeader<ResponseType> = MessageHeader::deserialize(packet)?;
-    
-    // Check for payload length
-    check_length(packet.len(), header.len() + header.payload_len)?;
-    let payload = &packet[header.len()..(header.len() + header.payload_len)];
-    let payload_header = PayloadHeader::deserialize(payload)?;
-    
-    let rest_payload = &payload[payload_header.len()..];
-    let kind = header.kind;
-    match kind {
-      ResponseType::BeRead => {
-          let freq = u64::from_be_bytes(rest_payload.try_into().unwrap()); // byte check already done
-          Ok(Response::BeRead { req_id: payload_header.req_id, freq })
-        },
-        ResponseType::LcRead | ResponseType::LcWrite => {
-          check_length(rest_payload.len(), 1)?;
-          let res = match payload[0] {
-            NONE_BYTE => None,
-            SOME_BYTE => {
-              let username = if rest_payload.len() == 1 {
-                "".to_string()
-              } else {
-                let uname_slice = &rest_payload[1..];
-                String::from_utf8_lossy(uname_slice).to_string()
-              };
-              Some(username)
-            },
-            _ => {return Err(ParseError::UnexpectedOptionType(payload[0]));}
-          };
+    ResponseView::decode(packet).map(ResponseView::into_owned)
+  }
+}
 
-          match kind {
-            ResponseType::LcRead => Ok(Response::LcRead { req_id: payload_header.req_id, username: res }),
-            ResponseType::LcWrite => Ok(Response::LcWrite { req_id: payload_header.req_id, username: res }),
-            _ => panic!("this should be impossible")
-          }
-        },
+// req_id of a frame, even one whose type or body could not be parsed
+pub fn frame_req_id(frame: &[u8], layout: Layout) -> Option<u64> {
+  payload_header(frame, layout).map(|header| header.req_id)
+}
+
+// the request a Cancel frame names, None for any other frame
+pub fn frame_cancels(frame: &[u8], layout: Layout) -> Option<u64> {
+  let kind = *frame.first()?;
+  if kind & KIND_MASK != CANCEL_BYTE {
+    return None;
+  }
+  frame_req_id(frame, layout)
+}
+
+// timing and tags of a frame, read before the rest of it is decoded
+pub fn frame_stamp(frame: &[u8], layout: Layout) -> Option<Stamp> {
+  payload_header(frame, layout).map(|header| header.stamp)
+}
+
+fn payload_header(frame: &[u8], layout: Layout) -> Option<PayloadHeader> {
+  let (kind, _, header_len) = frame_header(frame, layout).ok()??;
+  PayloadHeader::deserialize_with(&frame[header_len..], layout, kind & !KIND_MASK).ok()
+}
+
+// give the frame starting at `start` a PayloadHeader carrying `stamp`, and flag the fields it adds
+fn stamp_frame<T: MessageType>(buf: &mut Vec<u8>, start: usize, layout: Layout, stamp: Stamp) {
+  let header = MessageHeader::<T>::deserialize(&buf[start..], layout).expect("frame was just encoded");
+  let payload_start = start + header.len(layout);
+  let mut payload_header = PayloadHeader::deserialize_with(&buf[payload_start..], layout, header.flags).expect("frame was just encoded");
+  let body_start = payload_start + payload_header.len(layout);
+  payload_header.stamp = stamp;
+  // only the headers are re-encoded, the body is moved once to make room for them
+  let mut payload_head = Vec::new();
+  payload_header.encode_into(&mut payload_head, layout);
+  let payload_len = payload_head.len() + (buf.len() - body_start);
+  let mut headers = Vec::new();
+  MessageHeader { kind: header.kind, payload_len, flags: stamp.flags() }.encode_into(&mut headers, layout);
+  headers.extend_from_slice(&payload_head);
+  buf.splice(start..body_start, headers);
+}
+
+// append `timing` to the response frame just encoded, before its checksum if there is one
+pub fn append_server_timing(buf: &mut Vec<u8>, timing: ServerTiming) {
+  timing.encode_into(buf);
+}
+
+// the timing trailer of a whole response frame, as handed out by a decoder expecting one
+pub fn frame_server_timing(frame: &[u8], layout: Layout) -> Option<ServerTiming> {
+  let (_, payload_len, header_len) = frame_header(frame, layout).ok()??;
+  let trailer = frame.get(header_len.checked_add(usize::try_from(payload_len).ok()?)?..)?;
+  if trailer.len() != SERVER_TIMING_LEN {
+    return None;
+  }
+  let mut values = trailer.chunks_exact(size_of::<u64>()).map(|value| u64::from_be_bytes(value.try_into().unwrap()));
+  Some(ServerTiming { received_micros: values.next()?, queued_micros: values.next()?, exec_micros: values.next()?, yields: values.next()? })
+}
+
+// append the CRC32C of the frame starting at `start` as its trailer, once it is otherwise complete
+pub fn append_checksum(buf: &mut Vec<u8>, start: usize) {
+  let checksum = crc32c::crc32c(&buf[start..]);
+  buf.extend_from_slice(&checksum.to_be_bytes());
+}
+
+// the frame without its trailer, if the trailer matches its bytes
+fn verify_checksum(frame: &[u8]) -> Result<&[u8], ParseError> {
+  let (frame, trailer) = frame.split_at(frame.len() - CHECKSUM_LEN);
+  let expected = u32::from_be_bytes(trailer.try_into().unwrap());
+  let actual = crc32c::crc32c(frame);
+  if expected != actual {
+    return Err(ParseError::ChecksumMismatch { kind: frame[0], expected, actual });
+  }
+  Ok(frame)
+}
+
+// type byte, payload length and header length of the frame `bytes` starts with, None until the whole
+// header is there. The type is not checked, so an unknown one can still be skipped
+fn frame_header(bytes: &[u8], layout: Layout) -> Result<Option<(u8, u64, usize)>, ParseError> {
+  let Some((kind, rest)) = bytes.split_first() else {
+    return Ok(None);
+  };
+  match layout {
+    Layout::Fixed => {
+      let Some(len) = rest.get(..LEN_LENGTH) else {
+        return Ok(None);
+      };
+      Ok(Some((*kind, u64::from_be_bytes(len.try_into().unwrap()), 1 + LEN_LENGTH)))
+    },
+    Layout::Compact => Ok(read_varint(rest)?.map(|(payload_len, len)| (*kind, payload_len, 1 + len))),
+  }
+}
+
+// a req_id, key or length in the given layout
+fn put_int(buf: &mut Vec<u8>, value: u64, layout: Layout) {
+  match layout {
+    Layout::Fixed => buf.extend_from_slice(&value.to_be_bytes()),
+    Layout::Compact => {
+      let (bytes, len) = encode_varint(value);
+      buf.extend_from_slice(&bytes[..len]);
+    },
+  }
+}
+
+// LEB128: seven bits per byte starting with the lowest, the high bit set on every byte but the last
+fn encode_varint(mut value: u64) -> ([u8; MAX_VARINT_LEN], usize) {
+  let mut bytes = [0; MAX_VARINT_LEN];
+  let mut len = 0;
+  while value >= 0x80 {
+    bytes[len] = value as u8 | 0x80;
+    value >>= 7;
+    len += 1;
+  }
+  bytes[len] = value as u8;
+  (bytes, len + 1)
+}
+
+fn varint_len(value: u64) -> usize {
+  (u64::BITS - value.leading_zeros()).max(1).div_ceil(7) as usize
+}
+
+fn int_len(value: u64, layout: Layout) -> usize {
+  match layout {
+    Layout::Fixed => LEN_LENGTH,
+    Layout::Compact => varint_len(value),
+  }
+}
+
+// the value and the bytes it took, None if `bytes` ends before the varint does. Headers are skipped by the
+// length of their canonical encoding, so a varint padded with trailing zero groups is malformed
+fn read_varint(bytes: &[u8]) -> Result<Option<(u64, usize)>, ParseError> {
+  let mut value = 0;
+  for (i, byte) in bytes.iter().enumerate() {
+    // the tenth byte only has room for the top bit of a u64
+    if i == MAX_VARINT_LEN - 1 && *byte > 1 {
+      return Err(ParseError::MalformedPacket("varint does not fit in 64 bits".to_string()));
+    }
+    value |= u64::from(byte & 0x7f) << (7 * i);
+    if byte & 0x80 == 0 {
+      if i > 0 && *byte == 0 {
+        return Err(ParseError::MalformedPacket(format!("varint of {value} padded to {} bytes", i + 1)));
+      }
+      return Ok(Some((value, i + 1)));
     }
   }
+  Ok(None)
+}
+
+// option tag followed by the username bytes, used by every response that returns a stored value
+fn serialize_username(payload: &mut Vec<u8>, username: &Option<Vec<u8>>) {
+  match username {
+      Some(username) => {
+        payload.push(SOME_BYTE);
+        payload.extend_from_slice(username);
+      },
+      None => {
+        payload.push(NONE_BYTE);
+      },
+  }
+}
+
+// fill in the payload length of the frame starting at `start`, which is only known once the body is written.
+// A compact header was written with a one byte length, a longer one moves the payload along
+fn patch_payload_len(buf: &mut Vec<u8>, start: usize, layout: Layout) {
+  match layout {
+    Layout::Fixed => {
+      let payload_start = start + MessageHeader::<RequestType>::expected_len();
+      let payload_len = (buf.len() - payload_start) as u64;
+      buf[(start + 1)..payload_start].copy_from_slice(&payload_len.to_be_bytes());
+    },
+    Layout::Compact => {
+      let payload_len = (buf.len() - (start + 2)) as u64;
+      let (bytes, len) = encode_varint(payload_len);
+      buf.splice((start + 1)..(start + 2), bytes[..len].iter().copied());
+    },
+  }
+}
+
+// count followed by (id, length prefixed username) pairs
+fn serialize_entries(payload: &mut Vec<u8>, entries: &[(u64, Vec<u8>)]) {
+  payload.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+  for (id, username) in entries {
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(&(username.len() as u64).to_be_bytes());
+    payload.extend_from_slice(username);
+  }
 }
 
 fn check_length(len: usize, exp: usize) -> Result<(), ParseError> {
@@ -0,0 +1,133 @@
+use crate::{COMPRESSED_FLAG, KIND_MASK, LEN_LENGTH, ParseError};
+use super::{FrameLimits, Header, Layout, MessageHeader, MessageType, PayloadHeader, check_length, frame_header, int_len, patch_payload_len, put_int, read_varint};
+
+// A compressed frame keeps its req_id readable, so an error can still be matched to the request. After it
+// comes the length of the body once inflated, in the frame's layout, and then the body as one LZ4 block
+
+// replace the body of the frame starting at `start` by its compressed form, unless the body is too short
+// to be worth it or does not shrink
+pub(super) fn compress_frame<T: MessageType>(buf: &mut Vec<u8>, start: usize, layout: Layout, threshold: usize) {
+  let header = MessageHeader::<T>::deserialize(&buf[start..], layout).expect("frame was just encoded");
+  let payload_start = start + header.len(layout);
+  let payload_header = PayloadHeader::deserialize_with(&buf[payload_start..], layout, header.flags).expect("frame was just encoded");
+  let body_start = payload_start + payload_header.len(layout);
+  let body_len = buf.len() - body_start;
+  if body_len <= threshold {
+    return;
+  }
+  let block = lz4_flex::block::compress(&buf[body_start..]);
+  if int_len(body_len as u64, layout) + block.len() >= body_len {
+    return;
+  }
+  buf.truncate(start);
+  MessageHeader { kind: header.kind, payload_len: 0, flags: header.flags | COMPRESSED_FLAG }.encode_into(buf, layout);
+  payload_header.encode_into(buf, layout);
+  put_int(buf, body_len as u64, layout);
+  buf.extend_from_slice(&block);
+  patch_payload_len(buf, start, layout);
+}
+
+// write the whole compressed `frame` into `out` as it was before compression. The inflated payload is held
+// to the limit of the frame's type before anything is allocated for it
+pub(super) fn inflate_frame(frame: &[u8], layout: Layout, limits: &FrameLimits, out: &mut Vec<u8>) -> Result<(), ParseError> {
+  let (kind, _, header_len) = frame_header(frame, layout)?.ok_or(ParseError::PacketTooShort)?;
+  let kind = kind & !COMPRESSED_FLAG;
+  let payload = &frame[header_len..];
+  let payload_header = PayloadHeader::deserialize_with(payload, layout, kind & !KIND_MASK)?;
+  let rest = &payload[payload_header.len(layout)..];
+  let (body_len, len_len) = match layout {
+    Layout::Fixed => {
+      check_length(rest.len(), LEN_LENGTH)?;
+      (u64::from_be_bytes(rest[..LEN_LENGTH].try_into().unwrap()), LEN_LENGTH)
+    },
+    Layout::Compact => read_varint(rest)?.ok_or(ParseError::PacketTooShort)?,
+  };
+  let payload_len = (payload_header.len(layout) as u64).saturating_add(body_len);
+  let max_payload_len = limits.max_payload_len(kind & KIND_MASK);
+  if payload_len > max_payload_len as u64 {
+    return Err(ParseError::FrameTooLarge { kind, payload_len, max_payload_len });
+  }
+
+  out.clear();
+  out.push(kind);
+  put_int(out, 0, layout);
+  payload_header.encode_into(out, layout);
+  let body_start = out.len();
+  out.resize(body_start + body_len as usize, 0);
+  let inflated = lz4_flex::block::decompress_into(&rest[len_len..], &mut out[body_start..])
+    .map_err(|e| ParseError::MalformedPacket(format!("compressed body: {e}")))?;
+  if inflated != body_len as usize {
+    return Err(ParseError::MalformedPacket(format!("compressed body inflated to {inflated} bytes instead of {body_len}")));
+  }
+  patch_payload_len(out, 0, layout);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::packet::{Message, Request, RequestType, Stamp};
+
+  // a username that compresses well, `len` bytes long
+  fn repetitive(len: usize) -> Vec<u8> {
+    b"alice".iter().copied().cycle().take(len).collect()
+  }
+
+  fn encode(req: &Request, layout: Layout, threshold: Option<usize>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    req.encode_framed(&mut buf, layout, Stamp::default(), threshold);
+    buf
+  }
+
+  #[test]
+  fn compressed_frames_inflate_to_the_original() {
+    let req = Request::LcWrite { req_id: 7, id: 42, username: repetitive(4096) };
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let plain = encode(&req, layout, None);
+      let compressed = encode(&req, layout, Some(1024));
+      assert_ne!(compressed[0] & COMPRESSED_FLAG, 0);
+      assert!(compressed.len() < plain.len());
+      let mut inflated = Vec::new();
+      inflate_frame(&compressed, layout, &FrameLimits::requests(), &mut inflated).unwrap();
+      assert_eq!(inflated, plain);
+    }
+  }
+
+  // bytes of the frame after its PayloadHeader
+  fn body_len(frame: &[u8], layout: Layout) -> usize {
+    let (kind, payload_len, header_len) = frame_header(frame, layout).unwrap().unwrap();
+    let payload_header = PayloadHeader::deserialize_with(&frame[header_len..], layout, kind & !KIND_MASK).unwrap();
+    payload_len as usize - payload_header.len(layout)
+  }
+
+  #[test]
+  fn bodies_up_to_the_threshold_are_left_alone() {
+    let req = Request::LcWrite { req_id: 7, id: 42, username: repetitive(2000) };
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let plain = encode(&req, layout, None);
+      let body_len = body_len(&plain, layout);
+      assert_eq!(encode(&req, layout, Some(body_len)), plain);
+      assert_ne!(encode(&req, layout, Some(body_len - 1))[0] & COMPRESSED_FLAG, 0);
+    }
+  }
+
+  #[test]
+  fn bodies_that_do_not_shrink_are_left_alone() {
+    // every byte value once, which LZ4 finds nothing to repeat in
+    let req = Request::LcWrite { req_id: 7, id: 42, username: (0..=255).collect() };
+    let plain = encode(&req, Layout::Fixed, None);
+    assert_eq!(encode(&req, Layout::Fixed, Some(0)), plain);
+  }
+
+  #[test]
+  fn bodies_inflating_past_the_limit_are_refused() {
+    let req = Request::LcWrite { req_id: 7, id: 42, username: repetitive(4096) };
+    let compressed = encode(&req, Layout::Fixed, Some(1024));
+    let limits = FrameLimits::requests().limit(RequestType::LcWrite, 4096);
+    let mut inflated = Vec::new();
+    let err = inflate_frame(&compressed, Layout::Fixed, &limits, &mut inflated).unwrap_err();
+    assert!(matches!(err, ParseError::FrameTooLarge { max_payload_len: 4096, .. }), "{err:?}");
+    // nothing was inflated
+    assert!(inflated.is_empty());
+  }
+}
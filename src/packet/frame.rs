@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+
+use crate::{CHECKSUM_LEN, COMPRESSED_FLAG, KIND_MASK, MAX_BATCH_LEN, MAX_PATTERN_LEN, MAX_PAYLOAD_LEN, MAX_VALUE_LEN, ParseError, SERVER_TIMING_LEN};
+use super::{Layout, MessageType, compression::inflate_frame, RequestType, RequestView, ResponseType, ResponseView, Utf8Mode, frame_header, verify_checksum};
+
+// Largest payload accepted for each message type. Limits are checked as soon as a frame's header is
+// buffered, so a peer cannot make the decoder wait for more bytes than its message may carry
+#[derive(Clone, Debug)]
+pub struct FrameLimits {
+  per_type: HashMap<u8, usize>,
+  default: usize, // for types without a limit of their own, unknown ones included
+}
+
+impl FrameLimits {
+  pub fn new(default: usize) -> Self {
+    FrameLimits { per_type: HashMap::new(), default }
+  }
+
+  pub fn limit(mut self, kind: impl MessageType, max_payload_len: usize) -> Self {
+    self.per_type.insert(kind.value(), max_payload_len);
+    self
+  }
+
+  pub fn max_payload_len(&self, kind: u8) -> usize {
+    self.per_type.get(&kind).copied().unwrap_or(self.default)
+  }
+
+  // what a server accepts: a request carries at most a value or a pattern, unless it is a batch
+  pub fn requests() -> Self {
+    FrameLimits::new(MAX_VALUE_LEN)
+      .limit(RequestType::BeRead, MAX_PATTERN_LEN)
+      .limit(RequestType::PrefixCount, MAX_PATTERN_LEN)
+      .limit(RequestType::RegexCount, MAX_PATTERN_LEN)
+      .limit(RequestType::GlobCount, MAX_PATTERN_LEN)
+      .limit(RequestType::LcCompareAndSwap, 2 * MAX_VALUE_LEN)
+      .limit(RequestType::MultiGet, MAX_BATCH_LEN)
+      .limit(RequestType::MultiPut, MAX_BATCH_LEN)
+  }
+
+  // what a client accepts: batches and scans return many values, anything else a few
+  pub fn responses() -> Self {
+    FrameLimits::new(MAX_BATCH_LEN)
+      .limit(ResponseType::Chunk, MAX_PAYLOAD_LEN)
+      .limit(ResponseType::BeRead, MAX_PAYLOAD_LEN)
+      .limit(ResponseType::MultiGet, MAX_PAYLOAD_LEN)
+      .limit(ResponseType::MultiPut, MAX_PAYLOAD_LEN)
+      .limit(ResponseType::RangeScan, MAX_PAYLOAD_LEN)
+  }
+}
+
+impl Default for FrameLimits {
+  fn default() -> Self {
+    FrameLimits::new(MAX_PAYLOAD_LEN)
+  }
+}
+
+// Frames moved in one direction, to weigh the bytes compression saves on the wire against its cost
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+  pub frames: u64,
+  pub compressed: u64,
+  pub bytes: u64, // before compression, without checksum trailers
+  pub wire_bytes: u64,
+}
+
+impl FrameStats {
+  pub fn record(&mut self, frame_len: usize, wire_len: usize, compressed: bool) {
+    self.frames += 1;
+    if compressed {
+      self.compressed += 1;
+    }
+    self.bytes += frame_len as u64;
+    self.wire_bytes += wire_len as u64;
+  }
+
+  pub fn merge(&mut self, other: &FrameStats) {
+    self.frames += other.frames;
+    self.compressed += other.compressed;
+    self.bytes += other.bytes;
+    self.wire_bytes += other.wire_bytes;
+  }
+}
+
+// a frame and the bytes buffered after it
+pub type FrameAndPending<'a> = (&'a [u8], &'a [u8]);
+
+// Splits a byte stream into frames: chunks of any size are pushed in as they are read and every whole
+// frame they complete comes out, possibly several per chunk. Frames are handed out as slices of the
+// buffer, which is only compacted when more bytes are pushed, except compressed ones, which are handed
+// out inflated from a second buffer. Once checksums are on, every frame is verified against its trailer
+// and handed out without it. A server timing trailer is handed out with its frame, after the payload
+#[derive(Default)]
+pub struct FrameDecoder {
+  buf: Vec<u8>,
+  start: usize, // first byte of the next frame, everything before it was handed out
+  unfilled: usize, // bytes at the end of the buffer handed out to be read into, not pushed yet
+  limits: FrameLimits,
+  layout: Layout,
+  checksums: bool,
+  timing_trailers: bool,
+  utf8: Utf8Mode, // for text in the requests and responses decoded here
+  inflated: Vec<u8>, // the last compressed frame handed out, reused for the next one
+  stats: FrameStats,
+}
+
+impl FrameDecoder {
+  pub fn new(limits: FrameLimits) -> Self {
+    FrameDecoder { buf: Vec::new(), start: 0, unfilled: 0, limits, layout: Layout::Fixed, checksums: false, timing_trailers: false, utf8: Utf8Mode::Lossy, inflated: Vec::new(), stats: FrameStats::default() }
+  }
+
+  // frames after the handshake are read in the negotiated layout
+  pub fn set_layout(&mut self, layout: Layout) {
+    self.layout = layout;
+  }
+
+  // likewise, frames after the handshake carry a checksum trailer if both sides agreed to it
+  pub fn set_checksums(&mut self, checksums: bool) {
+    self.checksums = checksums;
+  }
+
+  // and responses after it carry a server timing trailer if both sides agreed to it
+  pub fn set_timing_trailers(&mut self, timing_trailers: bool) {
+    self.timing_trailers = timing_trailers;
+  }
+
+  // how next_request and next_response decode text that is not valid UTF-8. Unlike the settings above it
+  // is not negotiated, so clearing the decoder keeps it
+  pub fn set_utf8(&mut self, utf8: Utf8Mode) {
+    self.utf8 = utf8;
+  }
+
+  pub fn push(&mut self, bytes: &[u8]) {
+    self.compact();
+    self.buf.extend_from_slice(bytes);
+  }
+
+  // `len` bytes at the end of the buffer for a read to write into instead of pushing a copy of what it read.
+  // Only the ones `filled` is then told about are pushed, a read that never completes pushes none
+  pub fn unfilled(&mut self, len: usize) -> &mut [u8] {
+    self.compact();
+    let end = self.buf.len();
+    self.buf.resize(end + len, 0);
+    self.unfilled = len;
+    &mut self.buf[end..]
+  }
+
+  pub fn filled(&mut self, bytes_read: usize) {
+    assert!(bytes_read <= self.unfilled, "{bytes_read} bytes read into {} unfilled", self.unfilled);
+    self.buf.truncate(self.buf.len() - self.unfilled + bytes_read);
+    self.unfilled = 0;
+  }
+
+  // drops the frames handed out and whatever was left unfilled
+  fn compact(&mut self) {
+    self.buf.truncate(self.buf.len() - self.unfilled);
+    self.unfilled = 0;
+    if self.start > 0 {
+      self.buf.drain(..self.start);
+      self.start = 0;
+    }
+  }
+
+  fn end(&self) -> usize {
+    self.buf.len() - self.unfilled
+  }
+
+  // frames handed out so far, clearing the decoder does not reset them
+  pub fn stats(&self) -> FrameStats {
+    self.stats
+  }
+
+  // drop everything buffered, e.g. after reconnecting, when the next frame is a handshake again
+  pub fn clear(&mut self) {
+    self.buf.clear();
+    self.start = 0;
+    self.unfilled = 0;
+    self.layout = Layout::Fixed;
+    self.checksums = false;
+    self.timing_trailers = false;
+  }
+
+  // bytes buffered past the frames handed out, starting with the next frame's header
+  pub fn pending(&self) -> &[u8] {
+    &self.buf[self.start..self.end()]
+  }
+
+  pub fn has_frame(&self) -> Result<bool, ParseError> {
+    Ok(self.frame_len()?.is_some())
+  }
+
+  // the next whole frame, header included and inflated if it was compressed. A frame that fails its checksum,
+  // does not inflate, or inflates past its type's limit is an error and is left at the front of the buffer
+  pub fn next_frame(&mut self) -> Result<Option<&[u8]>, ParseError> {
+    Ok(self.next_frame_and_pending()?.map(|(frame, _)| frame))
+  }
+
+  // the next whole frame like next_frame, along with the bytes buffered after it
+  pub fn next_frame_and_pending(&mut self) -> Result<Option<FrameAndPending<'_>>, ParseError> {
+    let Some(wire_len) = self.frame_len()? else {
+      return Ok(None);
+    };
+    let start = self.start;
+    let mut frame = &self.buf[start..(start + wire_len)];
+    if self.checksums {
+      frame = verify_checksum(frame)?;
+    }
+    let compressed = frame[0] & COMPRESSED_FLAG != 0;
+    if compressed {
+      // the trailer is not compressed, so it is put back after the inflated frame
+      let (frame, trailer) = frame.split_at(frame.len() - if self.timing_trailers { SERVER_TIMING_LEN } else { 0 });
+      inflate_frame(frame, self.layout, &self.limits, &mut self.inflated)?;
+      self.inflated.extend_from_slice(trailer);
+    }
+    let frame_len = frame.len();
+    self.start += wire_len;
+    let end = self.end();
+    if !compressed {
+      self.stats.record(frame_len, wire_len, false);
+      return Ok(Some((&self.buf[start..(start + frame_len)], &self.buf[self.start..end])));
+    }
+    self.stats.record(self.inflated.len(), wire_len, true);
+    Ok(Some((&self.inflated, &self.buf[self.start..end])))
+  }
+
+  // the next whole frame decoded as a request, a frame that does not parse is consumed all the same
+  pub fn next_request(&mut self) -> Result<Option<RequestView<'_>>, ParseError> {
+    let (layout, utf8) = (self.layout, self.utf8);
+    self.next_frame()?.map(|frame| RequestView::decode_with(frame, layout, utf8)).transpose()
+  }
+
+  pub fn next_response(&mut self) -> Result<Option<ResponseView<'_>>, ParseError> {
+    let (layout, utf8) = (self.layout, self.utf8);
+    self.next_frame()?.map(|frame| ResponseView::decode_with(frame, layout, utf8)).transpose()
+  }
+
+  // length of the next frame once all of it is buffered. A frame announcing more than its type's limit
+  // is an error before its body arrives, and the stream cannot be resynchronized after it
+  fn frame_len(&self) -> Result<Option<usize>, ParseError> {
+    let rest = self.pending();
+    let Some((kind, payload_len, header_len)) = frame_header(rest, self.layout)? else {
+      return Ok(None);
+    };
+    let kind = kind & KIND_MASK;
+    let max_payload_len = self.limits.max_payload_len(kind);
+    if payload_len > max_payload_len as u64 {
+      return Err(ParseError::FrameTooLarge { kind, payload_len, max_payload_len });
+    }
+    let frame_len = header_len + payload_len as usize + if self.checksums { CHECKSUM_LEN } else { 0 }
+      + if self.timing_trailers { SERVER_TIMING_LEN } else { 0 };
+    Ok((rest.len() >= frame_len).then_some(frame_len))
+  }
+}
+
+// the whole frames at the front of `bytes`, as they are on the wire, stopping at the first one that is
+// incomplete or has an unreadable header
+pub fn whole_frames(mut bytes: &[u8], layout: Layout, checksums: bool) -> impl Iterator<Item = &[u8]> {
+  std::iter::from_fn(move || {
+    let (_, payload_len, header_len) = frame_header(bytes, layout).ok()??;
+    let wire_len = header_len.checked_add(usize::try_from(payload_len).ok()?)? + if checksums { CHECKSUM_LEN } else { 0 };
+    let frame = bytes.get(..wire_len)?;
+    bytes = &bytes[wire_len..];
+    Some(frame)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{LEN_LENGTH, packet::{Features, Message, Priority, Request, Stamp, Tags, Timing, append_checksum, frame_stamp}};
+
+  const FIXED_HEADER_LEN: usize = 1 + LEN_LENGTH;
+
+  fn requests() -> Vec<Request> {
+    vec![
+      Request::LcWrite { req_id: 1, id: 7, username: b"alice".to_vec() },
+      Request::MultiGet { req_id: 2, ids: vec![1, 2, 300] },
+      Request::LcCompareAndSwap { req_id: 3, id: 9, expected: b"bob".to_vec(), username: b"carol".to_vec() },
+      Request::LcRead { req_id: u64::MAX, id: u64::MAX },
+    ]
+  }
+
+  // the requests back to back, as they arrive on a connection
+  fn stream(reqs: &[Request], layout: Layout) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for req in reqs {
+      req.encode_with(&mut buf, layout);
+    }
+    buf
+  }
+
+  fn describe(reqs: &[Request]) -> Vec<String> {
+    reqs.iter().map(|req| format!("{req:?}")).collect()
+  }
+
+  fn drain(decoder: &mut FrameDecoder, decoded: &mut Vec<String>) {
+    while let Some(req) = decoder.next_request().unwrap() {
+      decoded.push(format!("{:?}", req.into_owned()));
+    }
+  }
+
+  // pushes `bytes` in chunks of the given lengths, cycling through them, taking frames out after every chunk
+  fn decode_in_chunks(bytes: &[u8], chunk_lens: &[usize], layout: Layout) -> Vec<String> {
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.set_layout(layout);
+    let mut decoded = Vec::new();
+    let mut rest = bytes;
+    for len in chunk_lens.iter().cycle() {
+      if rest.is_empty() {
+        break;
+      }
+      let (chunk, after) = rest.split_at((*len).min(rest.len()));
+      decoder.push(chunk);
+      drain(&mut decoder, &mut decoded);
+      rest = after;
+    }
+    assert!(decoder.pending().is_empty());
+    decoded
+  }
+
+  #[test]
+  fn frames_pushed_one_byte_at_a_time() {
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let reqs = requests();
+      assert_eq!(decode_in_chunks(&stream(&reqs, layout), &[1], layout), describe(&reqs));
+    }
+  }
+
+  #[test]
+  fn frames_split_across_chunk_boundaries() {
+    let reqs = requests();
+    let bytes = stream(&reqs, Layout::Fixed);
+    for chunk_len in 2..bytes.len() {
+      assert_eq!(decode_in_chunks(&bytes, &[chunk_len], Layout::Fixed), describe(&reqs), "chunks of {chunk_len} bytes");
+    }
+    assert_eq!(decode_in_chunks(&bytes, &[3, 1, 17, 2, 40, 5], Layout::Fixed), describe(&reqs));
+  }
+
+  #[test]
+  fn several_frames_in_one_chunk() {
+    let reqs = requests();
+    let mut bytes = stream(&reqs, Layout::Fixed);
+    // and the start of one more, which stays buffered
+    let mut next = Vec::new();
+    Request::LcRead { req_id: 5, id: 1 }.encode_into(&mut next);
+    bytes.extend_from_slice(&next[..3]);
+
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.push(&bytes);
+    let mut decoded = Vec::new();
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded, describe(&reqs));
+    assert_eq!(decoder.pending(), &next[..3]);
+    assert_eq!(decoder.stats().frames, reqs.len() as u64);
+  }
+
+  #[test]
+  fn frame_over_its_limit_is_rejected_before_its_body_arrives() {
+    let mut bytes = Vec::new();
+    Request::LcWrite { req_id: 1, id: 7, username: vec![b'a'; 64] }.encode_into(&mut bytes);
+    let mut decoder = FrameDecoder::new(FrameLimits::new(1024).limit(RequestType::LcWrite, 32));
+    // the header alone announces too much
+    decoder.push(&bytes[..FIXED_HEADER_LEN]);
+    assert!(matches!(decoder.next_frame(), Err(ParseError::FrameTooLarge { max_payload_len: 32, .. })));
+    // and the frame is left at the front of the buffer
+    assert_eq!(decoder.pending(), &bytes[..FIXED_HEADER_LEN]);
+
+    // one within it is let through
+    let mut bytes = Vec::new();
+    Request::LcWrite { req_id: 1, id: 7, username: vec![b'a'; 8] }.encode_into(&mut bytes);
+    let mut decoder = FrameDecoder::new(FrameLimits::new(1024).limit(RequestType::LcWrite, 32));
+    decoder.push(&bytes);
+    assert!(decoder.next_frame().unwrap().is_some());
+  }
+
+  #[test]
+  fn stamped_frames_keep_their_body() {
+    let stamp = Stamp {
+      timing: Some(Timing { sent_micros: 1 << 40, deadline_micros: Some((1 << 40) + 500) }),
+      tags: Some(Tags { priority: Priority::High, tenant: 300 }),
+    };
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let reqs = requests();
+      let mut bytes = Vec::new();
+      for req in &reqs {
+        req.encode_framed(&mut bytes, layout, stamp, None);
+      }
+      let mut decoder = FrameDecoder::new(FrameLimits::requests());
+      decoder.set_layout(layout);
+      decoder.push(&bytes);
+      let mut decoded = Vec::new();
+      while let Some(frame) = decoder.next_frame().unwrap() {
+        assert_eq!(frame_stamp(frame, layout), Some(stamp));
+        decoded.push(format!("{:?}", RequestView::decode_with(frame, layout, Utf8Mode::Strict).unwrap().into_owned()));
+      }
+      assert_eq!(decoded, describe(&reqs));
+    }
+  }
+
+  #[test]
+  fn padded_varints_are_rejected() {
+    let kind = RequestType::LcRead.value();
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.set_layout(Layout::Compact);
+    // req_id 5 and key 1, then req_id 5 padded to two bytes
+    decoder.push(&[kind, 2, 5, 1, kind, 3, 0x85, 0x00, 1]);
+    assert!(matches!(decoder.next_request(), Ok(Some(RequestView::LcRead { req_id: 5, id: 1 }))));
+    assert!(matches!(decoder.next_request(), Err(ParseError::MalformedPacket(_))));
+  }
+
+  #[test]
+  fn corrupted_frames_fail_their_checksum() {
+    let reqs = requests();
+    let mut bytes = Vec::new();
+    let mut starts = Vec::new();
+    for req in &reqs {
+      starts.push(bytes.len());
+      req.encode_with(&mut bytes, Layout::Compact);
+      append_checksum(&mut bytes, starts[starts.len() - 1]);
+    }
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.set_layout(Layout::Compact);
+    decoder.set_checksums(true);
+    decoder.push(&bytes);
+    let mut decoded = Vec::new();
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded, describe(&reqs));
+
+    // a bit flipped in the body of the second frame
+    bytes[starts[2] - CHECKSUM_LEN - 1] ^= 0x10;
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.set_layout(Layout::Compact);
+    decoder.set_checksums(true);
+    decoder.push(&bytes);
+    assert!(decoder.next_request().unwrap().is_some());
+    let kind = RequestType::MultiGet.value();
+    assert!(matches!(decoder.next_request(), Err(ParseError::ChecksumMismatch { kind: given, .. }) if given == kind));
+  }
+
+  #[test]
+  fn text_is_decoded_in_the_mode_set() {
+    let mut bytes = Vec::new();
+    Request::RegexCount { req_id: 1, pattern: "ab".to_string() }.encode_into(&mut bytes);
+    *bytes.last_mut().unwrap() = 0xff;
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.push(&bytes);
+    decoder.push(&bytes);
+    assert!(matches!(decoder.next_request(), Ok(Some(RequestView::RegexCount { pattern, .. })) if pattern == "a\u{fffd}"));
+    decoder.set_utf8(Utf8Mode::Strict);
+    assert!(matches!(decoder.next_request(), Err(ParseError::InvalidUtf8(_))));
+  }
+
+  #[test]
+  fn clear_drops_a_partial_frame_and_the_negotiated_layout() {
+    let reqs = requests();
+    let compact = stream(&reqs, Layout::Compact);
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.set_layout(Layout::Compact);
+    decoder.push(&compact[..compact.len() - 1]);
+    let mut decoded = Vec::new();
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded, describe(&reqs[..reqs.len() - 1]));
+
+    decoder.clear();
+    assert!(decoder.pending().is_empty());
+    // after a reconnect the handshake is in the fixed layout again
+    decoder.push(&stream(&reqs, Layout::Fixed));
+    let mut decoded = Vec::new();
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded, describe(&reqs));
+  }
+
+  #[test]
+  fn set_layout_applies_to_the_frames_after_it() {
+    let hello = Request::Hello { req_id: 0, version: 1, features: Features::NONE };
+    let reqs = requests();
+    let mut bytes = stream(std::slice::from_ref(&hello), Layout::Fixed);
+    bytes.extend_from_slice(&stream(&reqs, Layout::Compact));
+
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    decoder.push(&bytes);
+    assert_eq!(format!("{:?}", decoder.next_request().unwrap().unwrap().into_owned()), format!("{hello:?}"));
+    decoder.set_layout(Layout::Compact);
+    let mut decoded = Vec::new();
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded, describe(&reqs));
+  }
+
+  #[test]
+  fn reads_into_the_unfilled_tail() {
+    let reqs = requests();
+    let bytes = stream(&reqs, Layout::Fixed);
+    let mut decoder = FrameDecoder::new(FrameLimits::requests());
+    let mut decoded = Vec::new();
+    for chunk in bytes.chunks(5) {
+      let tail = decoder.unfilled(16);
+      tail[..chunk.len()].copy_from_slice(chunk);
+      decoder.filled(chunk.len());
+      drain(&mut decoder, &mut decoded);
+    }
+    // a read that never completed leaves nothing behind
+    decoder.unfilled(16);
+    assert!(decoder.pending().is_empty());
+    decoder.push(&bytes);
+    drain(&mut decoder, &mut decoded);
+    assert_eq!(decoded.len(), 2 * reqs.len());
+    assert_eq!(decoded[reqs.len()..], describe(&reqs));
+  }
+}
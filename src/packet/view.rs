@@ -0,0 +1,679 @@
+use std::{borrow::Cow, fmt, marker::PhantomData};
+
+use crate::{LEN_LENGTH, NONE_BYTE, ParseError, SOME_BYTE};
+use super::{BeResults, ErrorCode, Features, Header, Layout, MessageHeader, MessageType, PayloadHeader, Request, RequestType, Response, ResponseType, Utf8Mode, check_length, read_varint};
+
+// Requests and responses decoded in place: values, text and lists point into the receive buffer, only text
+// that is not valid UTF-8 is copied and a list is only walked once to check it, so decoding a frame does not allocate
+#[derive(Clone, Debug)]
+pub enum RequestView<'a> {
+  Hello {
+    req_id: u64,
+    version: u16,
+    features: Features
+  },
+  BeRead {
+    req_id: u64,
+    substring: &'a [u8],
+    results: BeResults,
+    limit: u64
+  },
+  LcRead {
+    req_id: u64,
+    id: u64
+  },
+  LcWrite {
+    req_id: u64,
+    id: u64,
+    username: &'a [u8]
+  },
+  LcDelete {
+    req_id: u64,
+    id: u64
+  },
+  LcInsertIfAbsent {
+    req_id: u64,
+    id: u64,
+    username: &'a [u8]
+  },
+  LcCompareAndSwap {
+    req_id: u64,
+    id: u64,
+    expected: &'a [u8],
+    username: &'a [u8]
+  },
+  MultiGet {
+    req_id: u64,
+    ids: ListView<'a, u64>
+  },
+  MultiPut {
+    req_id: u64,
+    entries: ListView<'a, (u64, &'a [u8])>
+  },
+  RangeScan {
+    req_id: u64,
+    start: u64,
+    end: u64,
+    limit: u64
+  },
+  PrefixCount {
+    req_id: u64,
+    prefix: &'a [u8]
+  },
+  RegexCount {
+    req_id: u64,
+    pattern: Cow<'a, str>
+  },
+  GlobCount {
+    req_id: u64,
+    pattern: Cow<'a, str>
+  },
+  Cancel {
+    req_id: u64
+  },
+  Stats {
+    req_id: u64
+  }
+}
+
+impl<'a> RequestView<'a> {
+  pub fn decode(packet: &'a [u8]) -> Result<Self, ParseError> {
+    RequestView::decode_with(packet, Layout::Fixed, Utf8Mode::Lossy)
+  }
+
+  pub fn decode_with(packet: &'a [u8], layout: Layout, utf8: Utf8Mode) -> Result<Self, ParseError> {
+    let (kind, req_id, mut rest) = split_frame::<RequestType>(packet, layout)?;
+    match kind {
+      RequestType::Hello => {
+        let (version, features) = take_hello(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(RequestView::Hello { req_id, version, features })
+      },
+      RequestType::Cancel | RequestType::Stats => {
+        check_consumed(rest)?;
+        match kind {
+          RequestType::Cancel => Ok(RequestView::Cancel { req_id }),
+          _ => Ok(RequestView::Stats { req_id }),
+        }
+      },
+      RequestType::BeRead => {
+        let results = BeResults::from_value(take(&mut rest, 1)?[0])?;
+        let limit = take_u64(&mut rest)?;
+        check_length(rest.len(), 1)?;
+        // matched against the stored bytes as they are, so not read as text
+        Ok(RequestView::BeRead { req_id, substring: rest, results, limit })
+      },
+      RequestType::PrefixCount => {
+        check_length(rest.len(), 1)?;
+        Ok(RequestView::PrefixCount { req_id, prefix: rest })
+      },
+      RequestType::RegexCount | RequestType::GlobCount => {
+        check_length(rest.len(), 1)?;
+        let str = text(rest, utf8)?;
+        match kind {
+          RequestType::RegexCount => Ok(RequestView::RegexCount { req_id, pattern: str }),
+          _ => Ok(RequestView::GlobCount { req_id, pattern: str }),
+        }
+      },
+      RequestType::RangeScan => {
+        let start = take_u64(&mut rest)?;
+        let end = take_u64(&mut rest)?;
+        let limit = take_u64(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(RequestView::RangeScan { req_id, start, end, limit })
+      },
+      RequestType::LcRead | RequestType::LcDelete => {
+        let id = take_int(&mut rest, layout)?;
+        check_consumed(rest)?;
+        match kind {
+          RequestType::LcRead => Ok(RequestView::LcRead { req_id, id }),
+          _ => Ok(RequestView::LcDelete { req_id, id }),
+        }
+      },
+      RequestType::LcCompareAndSwap => {
+        // the expected value is length prefixed, the new value takes the rest of the payload
+        let id = take_int(&mut rest, layout)?;
+        let expected = take_bytes(&mut rest)?;
+        Ok(RequestView::LcCompareAndSwap { req_id, id, expected, username: rest })
+      },
+      RequestType::MultiGet => {
+        let ids = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(RequestView::MultiGet { req_id, ids })
+      },
+      RequestType::MultiPut => {
+        let entries = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(RequestView::MultiPut { req_id, entries })
+      },
+      RequestType::LcWrite | RequestType::LcInsertIfAbsent => {
+        let id = take_int(&mut rest, layout)?;
+        let username = rest;
+        match kind {
+          RequestType::LcWrite => Ok(RequestView::LcWrite { req_id, id, username }),
+          _ => Ok(RequestView::LcInsertIfAbsent { req_id, id, username }),
+        }
+      }
+    }
+  }
+
+  pub fn req_id(&self) -> u64 {
+    match self {
+      RequestView::Hello { req_id, .. } | RequestView::BeRead { req_id, .. } | RequestView::LcRead { req_id, .. } | RequestView::LcWrite { req_id, .. }
+        | RequestView::LcDelete { req_id, .. } | RequestView::LcInsertIfAbsent { req_id, .. } | RequestView::LcCompareAndSwap { req_id, .. }
+        | RequestView::MultiGet { req_id, .. } | RequestView::MultiPut { req_id, .. } | RequestView::RangeScan { req_id, .. }
+        | RequestView::PrefixCount { req_id, .. } | RequestView::RegexCount { req_id, .. } | RequestView::GlobCount { req_id, .. } | RequestView::Cancel { req_id }
+        | RequestView::Stats { req_id } => *req_id
+    }
+  }
+
+  pub fn kind(&self) -> RequestType {
+    match self {
+      RequestView::Hello { .. } => RequestType::Hello,
+      RequestView::BeRead { .. } => RequestType::BeRead,
+      RequestView::LcRead { .. } => RequestType::LcRead,
+      RequestView::LcWrite { .. } => RequestType::LcWrite,
+      RequestView::LcDelete { .. } => RequestType::LcDelete,
+      RequestView::LcInsertIfAbsent { .. } => RequestType::LcInsertIfAbsent,
+      RequestView::LcCompareAndSwap { .. } => RequestType::LcCompareAndSwap,
+      RequestView::MultiGet { .. } => RequestType::MultiGet,
+      RequestView::MultiPut { .. } => RequestType::MultiPut,
+      RequestView::RangeScan { .. } => RequestType::RangeScan,
+      RequestView::PrefixCount { .. } => RequestType::PrefixCount,
+      RequestView::RegexCount { .. } => RequestType::RegexCount,
+      RequestView::GlobCount { .. } => RequestType::GlobCount,
+      RequestView::Cancel { .. } => RequestType::Cancel,
+      RequestView::Stats { .. } => RequestType::Stats,
+    }
+  }
+
+  pub fn into_owned(self) -> Request {
+    match self {
+      RequestView::Hello { req_id, version, features } => Request::Hello { req_id, version, features },
+      RequestView::BeRead { req_id, substring, results, limit } => Request::BeRead { req_id, substring: substring.to_vec(), results, limit },
+      RequestView::LcRead { req_id, id } => Request::LcRead { req_id, id },
+      RequestView::LcWrite { req_id, id, username } => Request::LcWrite { req_id, id, username: username.to_vec() },
+      RequestView::LcDelete { req_id, id } => Request::LcDelete { req_id, id },
+      RequestView::LcInsertIfAbsent { req_id, id, username } => Request::LcInsertIfAbsent { req_id, id, username: username.to_vec() },
+      RequestView::LcCompareAndSwap { req_id, id, expected, username } => {
+        Request::LcCompareAndSwap { req_id, id, expected: expected.to_vec(), username: username.to_vec() }
+      },
+      RequestView::MultiGet { req_id, ids } => Request::MultiGet { req_id, ids: ids.iter().collect() },
+      RequestView::MultiPut { req_id, entries } => Request::MultiPut { req_id, entries: owned_entries(entries) },
+      RequestView::RangeScan { req_id, start, end, limit } => Request::RangeScan { req_id, start, end, limit },
+      RequestView::PrefixCount { req_id, prefix } => Request::PrefixCount { req_id, prefix: prefix.to_vec() },
+      RequestView::RegexCount { req_id, pattern } => Request::RegexCount { req_id, pattern: pattern.into_owned() },
+      RequestView::GlobCount { req_id, pattern } => Request::GlobCount { req_id, pattern: pattern.into_owned() },
+      RequestView::Cancel { req_id } => Request::Cancel { req_id },
+      RequestView::Stats { req_id } => Request::Stats { req_id },
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub enum ResponseView<'a> {
+  HelloAck {
+    req_id: u64,
+    version: u16,
+    features: Features
+  },
+  HelloReject {
+    req_id: u64,
+    version: u16,
+    reason: Cow<'a, str>
+  },
+  Error {
+    req_id: u64,
+    code: ErrorCode,
+    message: Cow<'a, str>
+  },
+  Chunk {
+    req_id: u64,
+    entries: ListView<'a, (u64, &'a [u8])>
+  },
+  Drop {
+    req_id: u64,
+    queued_micros: u64
+  },
+  BeRead {
+    req_id: u64,
+    freq: u64,
+    keys: ListView<'a, u64>,
+    usernames: ListView<'a, &'a [u8]>
+  },
+  LcRead {
+    req_id: u64,
+    username: Option<&'a [u8]>
+  },
+  LcWrite {
+    req_id: u64,
+    username: Option<&'a [u8]>
+  },
+  LcDelete {
+    req_id: u64,
+    username: Option<&'a [u8]>
+  },
+  LcInsertIfAbsent {
+    req_id: u64,
+    username: Option<&'a [u8]>
+  },
+  LcCompareAndSwap {
+    req_id: u64,
+    swapped: bool,
+    username: Option<&'a [u8]>
+  },
+  MultiGet {
+    req_id: u64,
+    usernames: ListView<'a, Option<&'a [u8]>>
+  },
+  MultiPut {
+    req_id: u64,
+    usernames: ListView<'a, Option<&'a [u8]>>
+  },
+  RangeScan {
+    req_id: u64,
+    entries: ListView<'a, (u64, &'a [u8])>,
+    cursor: Option<u64>
+  },
+  PrefixCount {
+    req_id: u64,
+    freq: u64
+  },
+  RegexCount {
+    req_id: u64,
+    freq: u64
+  },
+  GlobCount {
+    req_id: u64,
+    freq: u64
+  },
+  Cancelled {
+    req_id: u64
+  },
+  Stats {
+    req_id: u64,
+    counters: ListView<'a, (&'a [u8], u64)>
+  }
+}
+
+impl<'a> ResponseView<'a> {
+  pub fn decode(packet: &'a [u8]) -> Result<Self, ParseError> {
+    ResponseView::decode_with(packet, Layout::Fixed, Utf8Mode::Lossy)
+  }
+
+  pub fn decode_with(packet: &'a [u8], layout: Layout, utf8: Utf8Mode) -> Result<Self, ParseError> {
+    let (kind, req_id, mut rest) = split_frame::<ResponseType>(packet, layout)?;
+    match kind {
+      ResponseType::HelloAck => {
+        let (version, features) = take_hello(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::HelloAck { req_id, version, features })
+      },
+      ResponseType::HelloReject => {
+        let version = u16::from_be_bytes(take(&mut rest, size_of::<u16>())?.try_into().unwrap());
+        Ok(ResponseView::HelloReject { req_id, version, reason: text(rest, utf8)? })
+      },
+      ResponseType::Error => {
+        let code = ErrorCode::from_value(take(&mut rest, 1)?[0])?;
+        Ok(ResponseView::Error { req_id, code, message: text(rest, utf8)? })
+      },
+      ResponseType::BeRead => {
+        let freq = take_u64(&mut rest)?;
+        let keys = ListView::take(&mut rest)?;
+        let usernames = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::BeRead { req_id, freq, keys, usernames })
+      },
+      ResponseType::PrefixCount | ResponseType::RegexCount | ResponseType::GlobCount => {
+        let freq = take_u64(&mut rest)?;
+        check_consumed(rest)?;
+        match kind {
+          ResponseType::PrefixCount => Ok(ResponseView::PrefixCount { req_id, freq }),
+          ResponseType::RegexCount => Ok(ResponseView::RegexCount { req_id, freq }),
+          _ => Ok(ResponseView::GlobCount { req_id, freq }),
+        }
+      },
+      ResponseType::RangeScan => {
+        let tag = take(&mut rest, 1)?[0];
+        let cursor = match tag {
+          NONE_BYTE => None,
+          SOME_BYTE => Some(take_u64(&mut rest)?),
+          _ => return Err(ParseError::UnexpectedOptionType(tag)),
+        };
+        let entries = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::RangeScan { req_id, entries, cursor })
+      },
+      ResponseType::Chunk => {
+        let entries = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::Chunk { req_id, entries })
+      },
+      ResponseType::Drop => {
+        let queued_micros = take_u64(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::Drop { req_id, queued_micros })
+      },
+      ResponseType::Cancelled => {
+        check_consumed(rest)?;
+        Ok(ResponseView::Cancelled { req_id })
+      },
+      ResponseType::Stats => {
+        let counters = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        Ok(ResponseView::Stats { req_id, counters })
+      },
+      ResponseType::LcRead | ResponseType::LcWrite | ResponseType::LcDelete | ResponseType::LcInsertIfAbsent => {
+        let username = deserialize_username(rest)?;
+        match kind {
+          ResponseType::LcRead => Ok(ResponseView::LcRead { req_id, username }),
+          ResponseType::LcWrite => Ok(ResponseView::LcWrite { req_id, username }),
+          ResponseType::LcDelete => Ok(ResponseView::LcDelete { req_id, username }),
+          _ => Ok(ResponseView::LcInsertIfAbsent { req_id, username }),
+        }
+      },
+      ResponseType::LcCompareAndSwap => {
+        let swapped = match take(&mut rest, 1)?[0] {
+          0 => false,
+          1 => true,
+          byte => return Err(ParseError::MalformedPacket(format!("swapped flag {byte} is not a bool"))),
+        };
+        let username = deserialize_username(rest)?;
+        Ok(ResponseView::LcCompareAndSwap { req_id, swapped, username })
+      },
+      ResponseType::MultiGet | ResponseType::MultiPut => {
+        let usernames = ListView::take(&mut rest)?;
+        check_consumed(rest)?;
+        match kind {
+          ResponseType::MultiGet => Ok(ResponseView::MultiGet { req_id, usernames }),
+          _ => Ok(ResponseView::MultiPut { req_id, usernames }),
+        }
+      },
+    }
+  }
+
+  pub fn req_id(&self) -> u64 {
+    match self {
+      ResponseView::HelloAck { req_id, .. } | ResponseView::HelloReject { req_id, .. } | ResponseView::Error { req_id, .. } | ResponseView::Chunk { req_id, .. }
+        | ResponseView::Drop { req_id, .. } | ResponseView::BeRead { req_id, .. } | ResponseView::LcRead { req_id, .. } | ResponseView::LcWrite { req_id, .. }
+        | ResponseView::LcDelete { req_id, .. } | ResponseView::LcInsertIfAbsent { req_id, .. } | ResponseView::LcCompareAndSwap { req_id, .. }
+        | ResponseView::MultiGet { req_id, .. } | ResponseView::MultiPut { req_id, .. } | ResponseView::RangeScan { req_id, .. }
+        | ResponseView::PrefixCount { req_id, .. } | ResponseView::RegexCount { req_id, .. } | ResponseView::GlobCount { req_id, .. } | ResponseView::Cancelled { req_id }
+        | ResponseView::Stats { req_id, .. } => *req_id
+    }
+  }
+
+  pub fn kind(&self) -> ResponseType {
+    match self {
+      ResponseView::HelloAck { .. } => ResponseType::HelloAck,
+      ResponseView::HelloReject { .. } => ResponseType::HelloReject,
+      ResponseView::Error { .. } => ResponseType::Error,
+      ResponseView::Chunk { .. } => ResponseType::Chunk,
+      ResponseView::Drop { .. } => ResponseType::Drop,
+      ResponseView::BeRead { .. } => ResponseType::BeRead,
+      ResponseView::LcRead { .. } => ResponseType::LcRead,
+      ResponseView::LcWrite { .. } => ResponseType::LcWrite,
+      ResponseView::LcDelete { .. } => ResponseType::LcDelete,
+      ResponseView::LcInsertIfAbsent { .. } => ResponseType::LcInsertIfAbsent,
+      ResponseView::LcCompareAndSwap { .. } => ResponseType::LcCompareAndSwap,
+      ResponseView::MultiGet { .. } => ResponseType::MultiGet,
+      ResponseView::MultiPut { .. } => ResponseType::MultiPut,
+      ResponseView::RangeScan { .. } => ResponseType::RangeScan,
+      ResponseView::PrefixCount { .. } => ResponseType::PrefixCount,
+      ResponseView::RegexCount { .. } => ResponseType::RegexCount,
+      ResponseView::GlobCount { .. } => ResponseType::GlobCount,
+      ResponseView::Cancelled { .. } => ResponseType::Cancelled,
+      ResponseView::Stats { .. } => ResponseType::Stats,
+    }
+  }
+
+  pub fn into_owned(self) -> Response {
+    match self {
+      ResponseView::HelloAck { req_id, version, features } => Response::HelloAck { req_id, version, features },
+      ResponseView::HelloReject { req_id, version, reason } => Response::HelloReject { req_id, version, reason: reason.into_owned() },
+      ResponseView::Error { req_id, code, message } => Response::Error { req_id, code, message: message.into_owned() },
+      ResponseView::Chunk { req_id, entries } => Response::Chunk { req_id, entries: owned_entries(entries) },
+      ResponseView::Drop { req_id, queued_micros } => Response::Drop { req_id, queued_micros },
+      ResponseView::BeRead { req_id, freq, keys, usernames } => Response::BeRead {
+        req_id,
+        freq,
+        keys: keys.iter().collect(),
+        usernames: usernames.iter().map(<[u8]>::to_vec).collect()
+      },
+      ResponseView::LcRead { req_id, username } => Response::LcRead { req_id, username: username.map(<[u8]>::to_vec) },
+      ResponseView::LcWrite { req_id, username } => Response::LcWrite { req_id, username: username.map(<[u8]>::to_vec) },
+      ResponseView::LcDelete { req_id, username } => Response::LcDelete { req_id, username: username.map(<[u8]>::to_vec) },
+      ResponseView::LcInsertIfAbsent { req_id, username } => Response::LcInsertIfAbsent { req_id, username: username.map(<[u8]>::to_vec) },
+      ResponseView::LcCompareAndSwap { req_id, swapped, username } => {
+        Response::LcCompareAndSwap { req_id, swapped, username: username.map(<[u8]>::to_vec) }
+      },
+      ResponseView::MultiGet { req_id, usernames } => Response::MultiGet { req_id, usernames: owned_usernames(usernames) },
+      ResponseView::MultiPut { req_id, usernames } => Response::MultiPut { req_id, usernames: owned_usernames(usernames) },
+      ResponseView::RangeScan { req_id, entries, cursor } => Response::RangeScan { req_id, entries: owned_entries(entries), cursor },
+      ResponseView::PrefixCount { req_id, freq } => Response::PrefixCount { req_id, freq },
+      ResponseView::RegexCount { req_id, freq } => Response::RegexCount { req_id, freq },
+      ResponseView::GlobCount { req_id, freq } => Response::GlobCount { req_id, freq },
+      ResponseView::Cancelled { req_id } => Response::Cancelled { req_id },
+      ResponseView::Stats { req_id, counters } => Response::Stats {
+        req_id,
+        counters: counters.iter().map(|(name, value)| (String::from_utf8_lossy(name).into_owned(), value)).collect()
+      },
+    }
+  }
+}
+
+// A value that can be split off the front of a body, the building block of a list
+pub trait WireItem<'a>: Sized {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError>;
+}
+
+impl<'a> WireItem<'a> for u64 {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    take_u64(rest)
+  }
+}
+
+impl<'a> WireItem<'a> for &'a [u8] {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    take_bytes(rest)
+  }
+}
+
+impl<'a> WireItem<'a> for (u64, &'a [u8]) {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    Ok((take_u64(rest)?, take_bytes(rest)?))
+  }
+}
+
+impl<'a> WireItem<'a> for (&'a [u8], u64) {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    Ok((take_bytes(rest)?, take_u64(rest)?))
+  }
+}
+
+impl<'a> WireItem<'a> for Option<&'a [u8]> {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    let tag = take(rest, 1)?[0];
+    match tag {
+      NONE_BYTE => Ok(None),
+      SOME_BYTE => Ok(Some(take_bytes(rest)?)),
+      _ => Err(ParseError::UnexpectedOptionType(tag)),
+    }
+  }
+}
+
+// A count followed by that many items, checked when it is taken and decoded again on every iteration
+pub struct ListView<'a, T> {
+  bytes: &'a [u8],
+  len: usize,
+  item: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: WireItem<'a> + 'a> ListView<'a, T> {
+  fn take(rest: &mut &'a [u8]) -> Result<Self, ParseError> {
+    let count = take_u64(rest)?;
+    let start = *rest;
+    // every item takes at least a byte, so a bogus count runs out of payload instead of looping for long
+    for _ in 0..count {
+      T::take(rest)?;
+    }
+    Ok(ListView { bytes: &start[..(start.len() - rest.len())], len: count as usize, item: PhantomData })
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = T> + 'a {
+    let mut rest = self.bytes;
+    (0..self.len).map(move |_| T::take(&mut rest).expect("list was checked when it was taken"))
+  }
+}
+
+impl<T> Clone for ListView<'_, T> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<T> Copy for ListView<'_, T> {}
+
+impl<'a, T: WireItem<'a> + fmt::Debug + 'a> fmt::Debug for ListView<'a, T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list().entries(self.iter()).finish()
+  }
+}
+
+fn owned_entries(entries: ListView<'_, (u64, &[u8])>) -> Vec<(u64, Vec<u8>)> {
+  entries.iter().map(|(id, username)| (id, username.to_vec())).collect()
+}
+
+fn owned_usernames(usernames: ListView<'_, Option<&[u8]>>) -> Vec<Option<Vec<u8>>> {
+  usernames.iter().map(|username| username.map(<[u8]>::to_vec)).collect()
+}
+
+// type, req_id and body of a frame, after checking the lengths its header announces
+fn split_frame<T: MessageType>(packet: &[u8], layout: Layout) -> Result<(T, u64, &[u8]), ParseError> {
+  let header = MessageHeader::<T>::deserialize(packet, layout)?;
+  if header.compressed() {
+    return Err(ParseError::MalformedPacket("compressed frame was not inflated before decoding".to_string()));
+  }
+  let header_len = header.len(layout);
+  // the length comes off the wire, a frame that was never bounded by a FrameDecoder can announce anything
+  let frame_len = header_len.checked_add(header.payload_len)
+    .ok_or_else(|| ParseError::MalformedPacket(format!("payload len {} overflows the frame length", header.payload_len)))?;
+  check_length(packet.len(), frame_len)?;
+  let payload = &packet[header_len..frame_len];
+  let payload_header = PayloadHeader::deserialize_with(payload, layout, header.flags)?;
+  Ok((header.kind, payload_header.req_id, &payload[payload_header.len(layout)..]))
+}
+
+// version and features of a Hello or HelloAck
+fn take_hello(rest: &mut &[u8]) -> Result<(u16, Features), ParseError> {
+  let version = u16::from_be_bytes(take(rest, size_of::<u16>())?.try_into().unwrap());
+  let features = u32::from_be_bytes(take(rest, size_of::<u32>())?.try_into().unwrap());
+  Ok((version, Features::from_bits(features)))
+}
+
+// option tag followed by the rest of the body as the username
+fn deserialize_username(rest_payload: &[u8]) -> Result<Option<&[u8]>, ParseError> {
+  check_length(rest_payload.len(), 1)?;
+  match rest_payload[0] {
+    NONE_BYTE => Ok(None),
+    SOME_BYTE => Ok(Some(&rest_payload[1..])),
+    _ => Err(ParseError::UnexpectedOptionType(rest_payload[0])),
+  }
+}
+
+// split `len` bytes off the front of a variable length body
+pub(super) fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], ParseError> {
+  check_length(rest.len(), len)?;
+  let (taken, remaining) = rest.split_at(len);
+  *rest = remaining;
+  Ok(taken)
+}
+
+fn take_u64(rest: &mut &[u8]) -> Result<u64, ParseError> {
+  Ok(u64::from_be_bytes(take(rest, LEN_LENGTH)?.try_into().unwrap()))
+}
+
+// a key in the given layout
+pub(super) fn take_int(rest: &mut &[u8], layout: Layout) -> Result<u64, ParseError> {
+  match layout {
+    Layout::Fixed => take_u64(rest),
+    Layout::Compact => {
+      let (value, len) = read_varint(rest)?.ok_or(ParseError::PacketTooShort)?;
+      *rest = &rest[len..];
+      Ok(value)
+    },
+  }
+}
+
+// u64 length followed by that many bytes
+fn take_bytes<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8], ParseError> {
+  let len = usize::try_from(take_u64(rest)?).map_err(|_| ParseError::PacketTooShort)?;
+  take(rest, len)
+}
+
+// a text field, with invalid UTF-8 replaced or rejected
+fn text(bytes: &[u8], utf8: Utf8Mode) -> Result<Cow<'_, str>, ParseError> {
+  match utf8 {
+    Utf8Mode::Lossy => Ok(String::from_utf8_lossy(bytes)),
+    Utf8Mode::Strict => Ok(Cow::Borrowed(std::str::from_utf8(bytes)?)),
+  }
+}
+
+fn check_consumed(rest: &[u8]) -> Result<(), ParseError> {
+  if !rest.is_empty() {
+    return Err(ParseError::MalformedPacket(format!("{} bytes left after the last entry", rest.len())));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::packet::Message;
+
+  // not valid UTF-8: a lone continuation byte, an overlong NUL and a byte that never occurs in UTF-8
+  const NOT_UTF8: &[u8] = &[b'a', 0x80, 0xc0, 0x80, 0xff, b'b'];
+
+  #[test]
+  fn text_is_rejected_or_replaced_by_mode() {
+    assert!(matches!(text(NOT_UTF8, Utf8Mode::Strict), Err(ParseError::InvalidUtf8(_))));
+    assert_eq!(text(NOT_UTF8, Utf8Mode::Lossy).unwrap(), "a\u{fffd}\u{fffd}\u{fffd}\u{fffd}b");
+    // valid text is borrowed either way
+    for utf8 in [Utf8Mode::Strict, Utf8Mode::Lossy] {
+      assert!(matches!(text("añb".as_bytes(), utf8), Ok(Cow::Borrowed("añb"))));
+    }
+  }
+
+  #[test]
+  fn error_messages_are_decoded_by_mode() {
+    let mut bytes = Vec::new();
+    Response::Error { req_id: 1, code: ErrorCode::Malformed, message: "ab".to_string() }.encode_with(&mut bytes, Layout::Fixed);
+    *bytes.last_mut().unwrap() = 0xff;
+    assert!(matches!(ResponseView::decode_with(&bytes, Layout::Fixed, Utf8Mode::Strict), Err(ParseError::InvalidUtf8(_))));
+    let res = ResponseView::decode_with(&bytes, Layout::Fixed, Utf8Mode::Lossy).unwrap();
+    assert!(matches!(res, ResponseView::Error { message, .. } if message == "a\u{fffd}"));
+  }
+
+  #[test]
+  fn values_are_bytes_not_text() {
+    for layout in [Layout::Fixed, Layout::Compact] {
+      let mut bytes = Vec::new();
+      Request::LcWrite { req_id: 1, id: 7, username: NOT_UTF8.to_vec() }.encode_with(&mut bytes, layout);
+      let req = RequestView::decode_with(&bytes, layout, Utf8Mode::Strict).unwrap().into_owned();
+      assert!(matches!(req, Request::LcWrite { username, .. } if username == NOT_UTF8));
+
+      let mut bytes = Vec::new();
+      Response::LcRead { req_id: 2, username: Some(NOT_UTF8.to_vec()) }.encode_with(&mut bytes, layout);
+      let res = ResponseView::decode_with(&bytes, layout, Utf8Mode::Strict).unwrap().into_owned();
+      assert!(matches!(res, Response::LcRead { username: Some(username), .. } if username == NOT_UTF8));
+    }
+  }
+}
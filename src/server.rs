@@ -1,18 +1,151 @@
-use std::{net::SocketAddr, sync::{Arc, mpsc::SyncSender}};
+use std::{net::SocketAddr, pin::pin, sync::{Arc, atomic::{AtomicU64, Ordering}, mpsc::SyncSender}, time::Instant};
 use smol::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
-use crate::{AspenRsError, BUF_LEN, LEN_LENGTH, NetworkError, packet::{Message, MessageType, Request, RequestType, Response}, store::Store};
+use crate::{AspenRsError, BUF_LEN, CAPACITY, COMPRESSION_THRESHOLD, KIND_MASK, NetworkError, PROTOCOL_VERSION, ParseError, READ_AHEAD_LEN, STREAM_CHUNK_LEN, packet::{BeResults, ErrorCode, Features, FrameDecoder, FrameLimits, Layout, Message, MessageType, Priority, RequestType, RequestView, Response, ServerTiming, Stamp, Utf8Mode, append_checksum, append_server_timing, frame_cancels, frame_req_id, frame_stamp, now_micros, whole_frames}, store::Store};
 
 
 use async_channel::unbounded;
+use globset::Glob;
+use regex::bytes::Regex;
 use async_executor::Executor;
 use easy_parallel::Parallel;
 use futures_lite::future;
 
+mod metrics;
+use metrics::LatencyBuckets;
+
+// capabilities offered to clients during the handshake
+const SERVER_FEATURES: Features = Features::PIPELINING.union(Features::BATCH_OPS).union(Features::STREAMING)
+  .union(Features::COMPACT).union(Features::COMPRESSION).union(Features::CHECKSUMS).union(Features::DEADLINES)
+  .union(Features::DROP_RESPONSES).union(Features::TAGS).union(Features::CANCEL)
+  .union(Features::SERVER_TIMING).union(Features::STATS);
+
 pub struct DefaultSmolServer;
 
+// How every connection of a server decodes its requests and encodes its responses
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+  pub limits: FrameLimits,
+  pub utf8: Utf8Mode,
+  pub compress_over: usize, // response bodies longer than this are compressed for clients that offer it
+  pub metrics_port: Option<usize>, // local port serving GET /metrics in the Prometheus text format
+}
+
+impl Default for ServerOptions {
+  fn default() -> Self {
+    ServerOptions { limits: FrameLimits::requests(), utf8: Utf8Mode::Lossy, compress_over: COMPRESSION_THRESHOLD, metrics_port: None }
+  }
+}
+
+// shared by every worker of a server
+#[derive(Default)]
+struct ServerStats {
+  started_micros: u64,
+  threads: usize, // running the executor
+  busy_nanos: AtomicU64, // spent by the executor threads polling workers
+  accepted_connections: AtomicU64,
+  open_connections: AtomicU64,
+  rejected_connections: AtomicU64, // closed for a frame over the size limit, failing its checksum or not inflating
+  requests: [AtomicU64; KIND_MASK as usize + 1], // received, by type byte
+  running_requests: AtomicU64, // being executed
+  running_scans: AtomicU64, // of the requests being executed, those scanning the store
+  queued_bytes: AtomicU64, // received and not yet served, summed over connections
+  timed_requests: AtomicU64, // carried a send time
+  queued_micros: AtomicU64, // summed over timed requests, from their send time to being decoded
+  max_queued_micros: AtomicU64,
+  expired_requests: AtomicU64, // dropped with their deadline passed instead of being executed
+  tagged_requests: [AtomicU64; 3], // by priority value
+  outranked_requests: AtomicU64, // waited for requests of a higher priority before executing
+  cancelled_requests: AtomicU64, // answered with Cancelled, before or while they were executed
+  queueing: [LatencyBuckets; KIND_MASK as usize + 1], // of executed requests by type byte, from their last bytes being read to being served
+  execution: [LatencyBuckets; KIND_MASK as usize + 1], // likewise from being served to being answered
+}
+
+impl ServerStats {
+  fn record_queueing(&self, queued_micros: u64) {
+    self.timed_requests.fetch_add(1, Ordering::Relaxed);
+    self.queued_micros.fetch_add(queued_micros, Ordering::Relaxed);
+    self.max_queued_micros.fetch_max(queued_micros, Ordering::Relaxed);
+  }
+
+  fn record_latencies(&self, kind: u8, timing: ServerTiming) {
+    self.queueing[kind as usize].record(timing.queued_micros);
+    self.execution[kind as usize].record(timing.exec_micros);
+  }
+
+  // every counter and gauge by name, as a Stats request is answered. The connection asking is left out: it
+  // is not counted as accepted or open, nor are the requests it sent, its running Stats request and its bytes
+  fn snapshot(&self, asking: &ConnectionCounts) -> Vec<(String, u64)> {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut counters: Vec<(String, u64)> = [
+      ("uptime_micros", now_micros().saturating_sub(self.started_micros)),
+      ("executor_threads", self.threads as u64),
+      ("busy_nanos", load(&self.busy_nanos)),
+      ("accepted_connections", load(&self.accepted_connections).saturating_sub(1)),
+      ("open_connections", load(&self.open_connections).saturating_sub(1)),
+      ("rejected_connections", load(&self.rejected_connections)),
+      ("running_requests", load(&self.running_requests).saturating_sub(1)),
+      ("running_scans", load(&self.running_scans)),
+      ("queued_bytes", load(&self.queued_bytes).saturating_sub(asking.queued_bytes)),
+      ("timed_requests", load(&self.timed_requests)),
+      ("queued_micros", load(&self.queued_micros)),
+      ("max_queued_micros", load(&self.max_queued_micros)),
+      ("expired_requests", load(&self.expired_requests)),
+      ("cancelled_requests", load(&self.cancelled_requests)),
+      ("outranked_requests", load(&self.outranked_requests)),
+    ].into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+    for kind in RequestType::iterator() {
+      let kind_value = kind.value() as usize;
+      counters.push((format!("requests_{kind:?}"), load(&self.requests[kind_value]).saturating_sub(asking.requests[kind_value])));
+    }
+    for priority in Priority::iterator() {
+      let priority_value = priority.value() as usize;
+      counters.push((format!("tagged_requests_{priority:?}"), load(&self.tagged_requests[priority_value]).saturating_sub(asking.tagged_requests[priority_value])));
+    }
+    counters
+  }
+}
+
+// What one connection added to the stats, so a snapshot it asks for can leave it out
+#[derive(Default)]
+struct ConnectionCounts {
+  requests: [u64; KIND_MASK as usize + 1], // received, by type byte
+  tagged_requests: [u64; 3], // by priority value
+  queued_bytes: u64, // its share of the stats' gauge
+}
+
+// Counts a request as running until it is answered or its connection fails
+struct Running {
+  stats: Arc<ServerStats>,
+  scan: bool,
+}
+
+impl Running {
+  fn start(stats: &Arc<ServerStats>, scan: bool) -> Self {
+    stats.running_requests.fetch_add(1, Ordering::Relaxed);
+    if scan {
+      stats.running_scans.fetch_add(1, Ordering::Relaxed);
+    }
+    Running { stats: stats.clone(), scan }
+  }
+}
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    self.stats.running_requests.fetch_sub(1, Ordering::Relaxed);
+    if self.scan {
+      self.stats.running_scans.fetch_sub(1, Ordering::Relaxed);
+    }
+  }
+}
+
 impl DefaultSmolServer {
   pub fn init(num_threads: usize, port: usize, start_client: SyncSender<()>, database: Store) {
+    DefaultSmolServer::init_with(num_threads, port, start_client, database, ServerOptions::default());
+  }
+
+  pub fn init_with(num_threads: usize, port: usize, start_client: SyncSender<()>, database: Store, options: ServerOptions) {
     let safe_store = Arc::new(database);
+    let stats = Arc::new(ServerStats { started_micros: now_micros(), threads: num_threads, ..ServerStats::default() });
 
     let ex = Arc::new(Executor::new());
     let (signal, shutdown) = unbounded::<()>();
@@ -23,6 +156,11 @@ impl DefaultSmolServer {
         // Run the main future on the current thread.
         .finish(|| future::block_on(async {
           let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await.unwrap();
+          if let Some(metrics_port) = options.metrics_port {
+            let metrics_listener = TcpListener::bind(format!("127.0.0.1:{metrics_port}")).await.unwrap();
+            println!("Serving metrics at http://127.0.0.1:{metrics_port}/metrics");
+            ex.spawn(metrics::serve(metrics_listener, stats.clone(), safe_store.clone(), ex.clone())).detach();
+          }
           let mut i = true;
           let ex_clone = ex.clone();
           ex.run(async move {
@@ -31,9 +169,10 @@ impl DefaultSmolServer {
             loop {
               let store = safe_store.clone();
               let (stream, addr) = listener.accept().await.unwrap();
-              async fn worker(stream: TcpStream, addr: SocketAddr, store: Arc<Store>) {
-                match Worker::new(stream, addr, store.clone()).run().await {
-                    Ok(_) | Err(AspenRsError::NetworkError(NetworkError::ConnectionReset)) => {},
+              async fn worker(stream: TcpStream, addr: SocketAddr, store: Arc<Store>, stats: Arc<ServerStats>, options: ServerOptions) {
+                let busy_nanos = stats.clone();
+                match count_busy(Worker::new(stream, addr, store, stats, options).run(), &busy_nanos.busy_nanos).await {
+                    Ok(_) | Err(AspenRsError::NetworkError(NetworkError::ConnectionReset | NetworkError::ConnectionClosed)) => {},
                     Err(e) => eprintln!("{e}"),
                 }
               }
@@ -41,7 +180,7 @@ impl DefaultSmolServer {
                 println!("Server accepted first connection at addr {:?}. Now spawning workers...", addr);
                 i = false;
               }
-              ex_clone.spawn(worker(stream, addr, store)).detach();
+              ex_clone.spawn(worker(stream, addr, store, stats.clone(), options.clone())).detach();
             }
           }).await;
           drop(signal);
@@ -51,87 +190,604 @@ impl DefaultSmolServer {
 
 struct Worker {
   stream: TcpStream,
-  _addr: SocketAddr,
+  addr: SocketAddr,
   store: Arc<Store>,
+  stats: Arc<ServerStats>,
+  features: Features,
+  layout: Layout,
+  utf8: Utf8Mode,
+  compress_over: usize,
+  compression: Option<usize>, // threshold in effect once the client agreed to compression
+  checksums: bool,
+  server_timing: bool, // the client agreed to a timing trailer on every response
+  // when the decoder was last pushed bytes. It is only read from once it holds no whole frame, so those bytes
+  // completed the frames it holds, except for those read ahead while a scan ran, which are dated by the last read
+  received_micros: u64,
+  timing: ServerTiming, // of the request being served
+  priority: Priority, // likewise, Normal unless it was tagged
+  counts: ConnectionCounts,
+  decoder: FrameDecoder,
+  read_ahead: Vec<u8>, // read while a task ran, pushed to the decoder once it is answered
+  write_buf: Vec<u8>, // reused for every response
 }
 
 impl Worker {
-  fn new(stream: TcpStream, addr: SocketAddr, store: Arc<Store>) -> Self {
+  fn new(stream: TcpStream, addr: SocketAddr, store: Arc<Store>, stats: Arc<ServerStats>, options: ServerOptions) -> Self {
+    stats.accepted_connections.fetch_add(1, Ordering::Relaxed);
+    stats.open_connections.fetch_add(1, Ordering::Relaxed);
     Worker {
       stream,
-      _addr: addr, 
-      store
+      addr,
+      store,
+      stats,
+      features: Features::NONE,
+      layout: Layout::Fixed,
+      utf8: options.utf8,
+      compress_over: options.compress_over,
+      compression: None,
+      checksums: false,
+      server_timing: false,
+      received_micros: 0,
+      timing: ServerTiming::default(),
+      priority: Priority::default(),
+      counts: ConnectionCounts::default(),
+      decoder: FrameDecoder::new(options.limits),
+      read_ahead: Vec::new(),
+      write_buf: Vec::new(),
     }
   }
 
   async fn run(mut self) -> Result<(), AspenRsError> {
-    loop {
-      let req = self.receive_request().await?;
-      let res = self.execute_task(req).await;
-      self.send_response(res).await?;
+    if !self.handshake().await? {
+      return Ok(());
     }
-  }
-  
-  async fn receive_request(&mut self) -> Result<Request, AspenRsError> {
-    let mut read_buf: Vec<u8> = Vec::new();
-    let mut buf = vec![0u8; BUF_LEN];
-    let mut req_type: Option<RequestType> = None;
-    let mut expected_len: Option<usize> = None;
-
     loop {
-      let bytes_read = self.stream.read(&mut buf).await.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))?;
-      if bytes_read > 0 {
-        read_buf.extend_from_slice(&buf[0..bytes_read]);
-      } else {
-        continue;
+      if !self.receive_frame().await? {
+        return Ok(());
       }
-
-      if req_type.is_none() {
-        req_type = Some(RequestType::from_value(read_buf[0])?);
+      // the request borrows from the decoder's buffer, which is moved out while the request is served
+      let mut decoder = std::mem::take(&mut self.decoder);
+      let (frame, pending) = match decoder.next_frame_and_pending() {
+        Ok(frame) => frame.expect("a whole frame was received"),
+        Err(e) => {
+          self.decoder = decoder;
+          self.reject(&e).await;
+          return Ok(());
+        },
+      };
+      self.timing = ServerTiming { received_micros: self.received_micros, ..ServerTiming::default() };
+      let kind = frame[0] & KIND_MASK;
+      self.stats.requests[kind as usize].fetch_add(1, Ordering::Relaxed);
+      self.counts.requests[kind as usize] += 1;
+      self.publish_queued(pending.len());
+      let Stamp { timing, tags } = frame_stamp(frame, self.layout).unwrap_or_default();
+      let tags = tags.filter(|_| self.features.contains(Features::TAGS));
+      if let Some(tags) = tags {
+        self.stats.tagged_requests[tags.priority.value() as usize].fetch_add(1, Ordering::Relaxed);
+        self.counts.tagged_requests[tags.priority.value() as usize] += 1;
+      }
+      // connections share the executor threads and the store, so requests of a higher priority go first.
+      // The wait counts as queueing, and may expire the request. Requests that touch neither go straight on,
+      // so the server still answers Stats while it is busy
+      self.priority = tags.map(|tags| tags.priority).unwrap_or_default();
+      let store = self.store.clone();
+      let admitted = match RequestType::from_value(kind) {
+        Ok(RequestType::Hello | RequestType::Cancel | RequestType::Stats) => None,
+        _ => Some(store.admission.admit(self.priority).await),
+      };
+      if admitted.as_ref().is_some_and(|(_, outranked)| *outranked) {
+        self.stats.outranked_requests.fetch_add(1, Ordering::Relaxed);
       }
-      
-      if expected_len.is_none() {
-        if read_buf.len() < (1 + LEN_LENGTH) {
-          continue;
+      // a request past its deadline is not worth executing, if the client agreed to be told it was dropped
+      let now = now_micros();
+      self.timing.queued_micros = now.saturating_sub(self.received_micros);
+      if let Some(timing) = timing {
+        self.stats.record_queueing(timing.queued_micros(now));
+      }
+      let expired = timing.filter(|timing| self.features.contains(Features::DEADLINES | Features::DROP_RESPONSES) && timing.expired(now));
+      // likewise a request whose Cancel is already buffered behind it
+      let cancelling = self.features.contains(Features::CANCEL);
+      let cancelled = frame_req_id(frame, self.layout)
+        .filter(|req_id| cancelling && frame_cancels(frame, self.layout).is_none() && self.cancelled_in(pending, *req_id));
+      let res = if let Some(timing) = expired {
+        self.stats.expired_requests.fetch_add(1, Ordering::Relaxed);
+        Some(Response::Drop { req_id: frame_req_id(frame, self.layout).unwrap_or(0), queued_micros: timing.queued_micros(now) })
+      } else if let Some(req_id) = cancelled {
+        self.stats.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+        Some(Response::Cancelled { req_id })
+      } else {
+        // a request that cannot be parsed is answered with an error instead of closing the connection
+        let req = RequestView::decode_with(frame, self.layout, self.utf8);
+        let _running = Running::start(&self.stats, req.as_ref().is_ok_and(is_scan));
+        match req {
+          Ok(RequestView::RangeScan { req_id, start, end, limit }) if self.features.contains(Features::STREAMING) => {
+            Some(self.stream_range_scan(req_id, start, end, limit).await?)
+          },
+          // the request it names was answered before it got here, so there is nothing left to abort
+          Ok(RequestView::Cancel { .. }) if cancelling => None,
+          Ok(req) if cancelling && is_scan(&req) => Some(self.execute_cancellable(req, pending).await),
+          Ok(req) => {
+            let mut yields = 0;
+            let res = count_yields(self.execute_task(req), &mut yields).await;
+            self.timing.yields = yields;
+            Some(res)
+          },
+          Err(e) => Some(Response::parse_error(frame, self.layout, &e)),
         }
+      };
+      if let Some(res) = res {
+        self.send_response(&res).await?;
+        // expired and cancelled requests were not executed, so they would only skew the histograms
+        if expired.is_none() && cancelled.is_none() {
+          self.stats.record_latencies(kind, self.timing);
+        }
+      }
+      decoder.push(&self.read_ahead);
+      self.read_ahead.clear();
+      self.decoder = decoder;
+    }
+  }
+
+  // make `bytes` this connection's share of the queued bytes gauge
+  fn publish_queued(&mut self, bytes: usize) {
+    let bytes = bytes as u64;
+    if bytes > self.counts.queued_bytes {
+      self.stats.queued_bytes.fetch_add(bytes - self.counts.queued_bytes, Ordering::Relaxed);
+    } else {
+      self.stats.queued_bytes.fetch_sub(self.counts.queued_bytes - bytes, Ordering::Relaxed);
+    }
+    self.counts.queued_bytes = bytes;
+  }
+
+  // whether a Cancel naming `req_id` is among the whole frames in `pending`
+  fn cancelled_in(&self, pending: &[u8], req_id: u64) -> bool {
+    whole_frames(pending, self.layout, self.checksums).any(|frame| frame_cancels(frame, self.layout) == Some(req_id))
+  }
 
-        let len_arr: [u8; 8] = read_buf[1..(1+LEN_LENGTH)].try_into().unwrap();
-        expected_len = Some(usize::from_be_bytes(len_arr));
+  // runs a scan while reading on from the client, so a Cancel arriving for it drops the scan at its next yield
+  // point. What was read is kept in read_ahead for the decoder
+  async fn execute_cancellable(&mut self, req: RequestView<'_>, pending: &[u8]) -> Response {
+    let req_id = req.req_id();
+    let mut ahead = std::mem::take(&mut self.read_ahead);
+    ahead.extend_from_slice(pending);
+    let mut watcher = CancelWatcher { stream: self.stream.clone(), req_id, layout: self.layout, checksums: self.checksums, ahead, scanned: 0, read_micros: None };
+    let mut yields = 0;
+    let res = future::or(async { Some(count_yields(self.execute_task(req), &mut yields).await) }, async { watcher.cancelled().await; None }).await;
+    self.timing.yields = yields;
+    let mut ahead = watcher.ahead;
+    ahead.drain(..pending.len());
+    self.read_ahead = ahead;
+    if let Some(read_micros) = watcher.read_micros {
+      self.received_micros = read_micros;
+    }
+    res.unwrap_or_else(|| {
+      self.stats.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+      Response::Cancelled { req_id }
+    })
+  }
+
+  // sends the scanned values in chunks as they are read, returning the response that ends the stream
+  async fn stream_range_scan(&mut self, req_id: u64, start: u64, end: u64, limit: u64) -> Result<Response, AspenRsError> {
+    let (start, end, limit) = clip_range(start, end, limit);
+    let mut entries = Vec::new();
+    let mut sent = 0;
+    let mut key = start;
+    while key < end && sent + entries.len() < limit {
+      key = count_yields(self.store.range_scan_chunk(key, end, limit - sent, self.priority, &mut entries), &mut self.timing.yields).await;
+      if entries.len() >= STREAM_CHUNK_LEN {
+        sent += entries.len();
+        self.send_response(&Response::Chunk { req_id, entries: to_wire_entries(entries) }).await?;
+        entries = Vec::new();
       }
+    }
+    Ok(Response::RangeScan {
+      req_id,
+      entries: to_wire_entries(entries),
+      cursor: (key < end).then_some(key as u64),
+    })
+  }
+  
+  // the first message on a connection must be a Hello with a version this server speaks
+  async fn handshake(&mut self) -> Result<bool, AspenRsError> {
+    if !self.receive_frame().await? {
+      return Ok(false);
+    }
+    let frame = self.decoder.next_frame()?.expect("a whole frame was received");
+    self.stats.requests[(frame[0] & KIND_MASK) as usize].fetch_add(1, Ordering::Relaxed);
+    self.counts.requests[(frame[0] & KIND_MASK) as usize] += 1;
+    let res = match RequestView::decode_with(frame, self.layout, self.utf8) {
+      Ok(RequestView::Hello { req_id, version, features }) if version == PROTOCOL_VERSION => {
+        self.features = features.intersection(SERVER_FEATURES);
+        Response::HelloAck { req_id, version, features: self.features }
+      },
+      Ok(RequestView::Hello { req_id, version, .. }) => {
+        Response::HelloReject { req_id, version: PROTOCOL_VERSION, reason: format!("unsupported protocol version {version}") }
+      },
+      Ok(req) => {
+        Response::HelloReject { req_id: req.req_id(), version: PROTOCOL_VERSION, reason: format!("expected Hello but got {:?}", req.kind()) }
+      },
+      Err(e) => Response::parse_error(frame, self.layout, &e),
+    };
+    let accepted = matches!(res, Response::HelloAck { .. });
+    self.publish_queued(self.decoder.pending().len());
+    self.send_response(&res).await?;
+    // the answer to Hello is still in the fixed layout, everything after it in the negotiated one
+    self.layout = Layout::negotiated(self.features);
+    self.decoder.set_layout(self.layout);
+    self.compression = self.features.contains(Features::COMPRESSION).then_some(self.compress_over);
+    self.checksums = self.features.contains(Features::CHECKSUMS);
+    self.decoder.set_checksums(self.checksums);
+    self.server_timing = self.features.contains(Features::SERVER_TIMING);
+    Ok(accepted)
+  }
 
-      let total_exp_len = 1 + LEN_LENGTH + expected_len.expect("Should not be None based on previous checks");
-      
-      if read_buf.len() < total_exp_len {
-        continue
-      } else if read_buf.len() == total_exp_len {
-        return Request::deserialize(&read_buf).map_err(AspenRsError::ParseError);
-      } else {
-        return Err(AspenRsError::ParseError(crate::ParseError::UnexpectedLength { payload_len: read_buf.len(), exp_len: total_exp_len }));
+  // read until the decoder holds a whole frame, requests may arrive back to back. Returns false once the
+  // connection was rejected for an oversized frame
+  async fn receive_frame(&mut self) -> Result<bool, AspenRsError> {
+    loop {
+      match self.decoder.has_frame() {
+        Ok(true) => return Ok(true),
+        Ok(false) => {},
+        Err(e @ ParseError::FrameTooLarge { .. }) => {
+          self.reject(&e).await;
+          return Ok(false);
+        },
+        Err(e) => return Err(AspenRsError::ParseError(e)),
+      }
+      let read = self.stream.read(self.decoder.unfilled(BUF_LEN)).await;
+      let bytes_read = read.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))?;
+      self.decoder.filled(bytes_read);
+      if bytes_read == 0 {
+        return Err(AspenRsError::NetworkError(NetworkError::ConnectionClosed));
       }
+      self.received_micros = now_micros();
+      self.publish_queued(self.decoder.pending().len());
     }
   }
 
-  async fn execute_task(&mut self, req: Request) -> Response {
+  // the rest of the stream cannot be framed, trusted or inflated, so the peer is told why and the connection is closed.
+  // A frame that failed its checksum gets no answer, as the req_id it would be matched by may be corrupt too
+  async fn reject(&mut self, err: &ParseError) {
+    let rejected = self.stats.rejected_connections.fetch_add(1, Ordering::Relaxed) + 1;
+    eprintln!("Rejected connection from {} ({rejected} so far): {err}", self.addr);
+    if matches!(err, ParseError::ChecksumMismatch { .. }) {
+      return;
+    }
+    let res = Response::parse_error(self.decoder.pending(), self.layout, err);
+    // the connection is closed either way
+    let _ = self.send_response(&res).await;
+  }
+
+  async fn execute_task(&self, req: RequestView<'_>) -> Response {
     match req {
-        Request::BeRead { req_id, substring } => {
-            let freq: u64 = self.store.be_task(substring).await as u64;
-            Response::BeRead { req_id, freq }
+        RequestView::Hello { req_id, .. } => {
+            // features are fixed by the first Hello, later ones are only acknowledged
+            Response::HelloAck { req_id, version: PROTOCOL_VERSION, features: self.features }
+          },
+        RequestView::BeRead { req_id, substring, results, limit } => {
+            let limit = if results == BeResults::Count { 0 } else { limit as usize };
+            let (freq, matches) = self.store.be_task(substring, limit, self.priority).await;
+            let (keys, usernames): (Vec<u64>, Vec<Vec<u8>>) = matches.into_iter().map(|(key, username)| (key as u64, username)).unzip();
+            Response::BeRead {
+              req_id,
+              freq: freq as u64,
+              keys: if results.keys() { keys } else { Vec::new() },
+              usernames: if results.usernames() { usernames } else { Vec::new() }
+            }
+          },
+        RequestView::PrefixCount { req_id, prefix } => {
+            let freq: u64 = self.store.prefix_count_task(prefix, self.priority).await as u64;
+            Response::PrefixCount { req_id, freq }
+          },
+        RequestView::RegexCount { req_id, pattern } => {
+            let regex = match Regex::new(&pattern) {
+              Ok(regex) => regex,
+              Err(e) => return Response::Error { req_id, code: ErrorCode::Malformed, message: format!("invalid regex: {e}") },
+            };
+            let freq: u64 = self.store.regex_count_task(regex, self.priority).await as u64;
+            Response::RegexCount { req_id, freq }
           },
-        Request::LcRead { req_id, id } => {
-            let id = id.try_into().unwrap();
+        RequestView::GlobCount { req_id, pattern } => {
+            let glob = match Glob::new(&pattern) {
+              Ok(glob) => glob,
+              Err(e) => return Response::Error { req_id, code: ErrorCode::Malformed, message: format!("invalid glob: {e}") },
+            };
+            // values are bytes, which the regex a glob translates to can match
+            let regex = match Regex::new(glob.regex()) {
+              Ok(regex) => regex,
+              Err(e) => return Response::Error { req_id, code: ErrorCode::Internal, message: format!("glob translated to an invalid regex: {e}") },
+            };
+            let freq: u64 = self.store.regex_count_task(regex, self.priority).await as u64;
+            Response::GlobCount { req_id, freq }
+          },
+        RequestView::RangeScan { req_id, start, end, limit } => {
+            let (start, end, limit) = clip_range(start, end, limit);
+            let (entries, cursor) = self.store.range_scan_task(start, end, limit, self.priority).await;
+            Response::RangeScan {
+              req_id,
+              entries: to_wire_entries(entries),
+              cursor: cursor.map(|cursor| cursor as u64),
+            }
+          },
+        RequestView::LcRead { req_id, id } => {
+            let Some(id) = key_in_range(id) else {
+              return key_out_of_range(req_id, id);
+            };
             let username = self.store.lc_read_task(id).await;
             Response::LcRead { req_id, username }
           },
-        Request::LcWrite { req_id, id, username } => {
-            let id = id.try_into().unwrap();
-            let username = self.store.lc_write_task(id, username).await;
+        RequestView::LcWrite { req_id, id, username } => {
+            let Some(id) = key_in_range(id) else {
+              return key_out_of_range(req_id, id);
+            };
+            let username = self.store.lc_write_task(id, username.to_vec()).await;
             Response::LcWrite { req_id, username }
         },
+        RequestView::LcDelete { req_id, id } => {
+            let Some(id) = key_in_range(id) else {
+              return key_out_of_range(req_id, id);
+            };
+            let username = self.store.lc_delete_task(id).await;
+            Response::LcDelete { req_id, username }
+        },
+        RequestView::LcInsertIfAbsent { req_id, id, username } => {
+            let Some(id) = key_in_range(id) else {
+              return key_out_of_range(req_id, id);
+            };
+            let username = self.store.lc_insert_if_absent_task(id, username.to_vec()).await;
+            Response::LcInsertIfAbsent { req_id, username }
+        },
+        RequestView::LcCompareAndSwap { req_id, id, expected, username } => {
+            let Some(id) = key_in_range(id) else {
+              return key_out_of_range(req_id, id);
+            };
+            let (swapped, username) = self.store.lc_compare_and_swap_task(id, expected, username.to_vec()).await;
+            Response::LcCompareAndSwap { req_id, swapped, username }
+        },
+        RequestView::MultiGet { req_id, .. } | RequestView::MultiPut { req_id, .. } if !self.features.contains(Features::BATCH_OPS) => {
+            Response::Error { req_id, code: ErrorCode::UnknownType, message: "batch operations were not negotiated".to_string() }
+        },
+        RequestView::Cancel { req_id } => {
+            Response::Error { req_id, code: ErrorCode::UnknownType, message: "cancellation was not negotiated".to_string() }
+        },
+        RequestView::Stats { req_id } if !self.features.contains(Features::STATS) => {
+            Response::Error { req_id, code: ErrorCode::UnknownType, message: "server statistics were not negotiated".to_string() }
+        },
+        RequestView::Stats { req_id } => {
+            Response::Stats { req_id, counters: self.stats.snapshot(&self.counts) }
+        },
+        RequestView::MultiGet { req_id, ids } => {
+            let mut keys = Vec::with_capacity(ids.len());
+            for id in ids.iter() {
+              let Some(key) = key_in_range(id) else {
+                return key_out_of_range(req_id, id);
+              };
+              keys.push(key);
+            }
+            let usernames = self.store.multi_get_task(&keys).await;
+            Response::MultiGet { req_id, usernames }
+        },
+        RequestView::MultiPut { req_id, entries } => {
+            let mut keyed = Vec::with_capacity(entries.len());
+            for (id, username) in entries.iter() {
+              let Some(key) = key_in_range(id) else {
+                return key_out_of_range(req_id, id);
+              };
+              keyed.push((key, username.to_vec()));
+            }
+            let usernames = self.store.multi_put_task(keyed).await;
+            Response::MultiPut { req_id, usernames }
+        },
     }
   }
 
-  async fn send_response(&mut self, res: Response) -> Result<(), AspenRsError> {
-    let response = res.serialize();
-    self.stream.write_all(&response).await.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))
+  async fn send_response(&mut self, res: &Response) -> Result<(), AspenRsError> {
+    self.write_buf.clear();
+    res.encode_framed(&mut self.write_buf, self.layout, Stamp::default(), self.compression);
+    // a streamed response's chunks each carry the execution time so far
+    self.timing.exec_micros = now_micros().saturating_sub(self.timing.received_micros + self.timing.queued_micros);
+    if self.server_timing {
+      append_server_timing(&mut self.write_buf, self.timing);
+    }
+    if self.checksums {
+      append_checksum(&mut self.write_buf, 0);
+    }
+    self.stream.write_all(&self.write_buf).await.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))
+  }
+}
+
+impl Drop for Worker {
+  fn drop(&mut self) {
+    self.publish_queued(0);
+    self.stats.open_connections.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+// Reads on from a client while one of its scans runs, looking for a Cancel naming it
+struct CancelWatcher {
+  stream: TcpStream, // a handle to the worker's own
+  req_id: u64,
+  layout: Layout,
+  checksums: bool,
+  ahead: Vec<u8>, // the bytes buffered behind the scan's frame when it started, then everything read since
+  scanned: usize, // bytes of the whole frames already looked at
+  read_micros: Option<u64>, // when it last read anything
+}
+
+impl CancelWatcher {
+  // resolves once the Cancel arrives. It never does once the connection fails, which the worker finds out
+  // after the scan, or once READ_AHEAD_LEN bytes are buffered
+  async fn cancelled(&mut self) {
+    let mut buf = [0u8; BUF_LEN];
+    loop {
+      for frame in whole_frames(&self.ahead[self.scanned..], self.layout, self.checksums) {
+        if frame_cancels(frame, self.layout) == Some(self.req_id) {
+          return;
+        }
+        self.scanned += frame.len();
+      }
+      if self.ahead.len() >= READ_AHEAD_LEN {
+        return future::pending().await;
+      }
+      match self.stream.read(&mut buf).await {
+        Ok(0) | Err(_) => return future::pending().await,
+        Ok(bytes_read) => {
+          self.ahead.extend_from_slice(&buf[0..bytes_read]);
+          self.read_micros = Some(now_micros());
+        },
+      }
+    }
+  }
+}
+
+// runs `task` to completion, counting the times it yielded, to the executor or to wait on a lock
+async fn count_yields<F: Future>(task: F, yields: &mut u64) -> F::Output {
+  let mut task = pin!(task);
+  future::poll_fn(|cx| {
+    let poll = task.as_mut().poll(cx);
+    if poll.is_pending() {
+      *yields += 1;
+    }
+    poll
+  }).await
+}
+
+// runs `task` to completion, adding the time spent polling it, which one of the executor threads spent on it,
+// to `busy_nanos`
+async fn count_busy<F: Future>(task: F, busy_nanos: &AtomicU64) -> F::Output {
+  let mut task = pin!(task);
+  future::poll_fn(|cx| {
+    let start = Instant::now();
+    let poll = task.as_mut().poll(cx);
+    busy_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    poll
+  }).await
+}
+
+// requests that run long enough to be worth cancelling, the others are answered before a Cancel could arrive.
+// Streamed scans are only cancelled before they start
+fn is_scan(req: &RequestView) -> bool {
+  matches!(req, RequestView::BeRead { .. } | RequestView::PrefixCount { .. } | RequestView::RegexCount { .. }
+    | RequestView::GlobCount { .. } | RequestView::RangeScan { .. })
+}
+
+// nothing is stored at or above CAPACITY, so scans are clipped instead of rejected
+fn clip_range(start: u64, end: u64, limit: u64) -> (usize, usize, usize) {
+  let start = start.min(CAPACITY as u64) as usize;
+  let end = end.min(CAPACITY as u64) as usize;
+  (start, end, usize::try_from(limit).unwrap_or(usize::MAX))
+}
+
+fn to_wire_entries(entries: Vec<(usize, Vec<u8>)>) -> Vec<(u64, Vec<u8>)> {
+  entries.into_iter().map(|(id, username)| (id as u64, username)).collect()
+}
+
+fn key_in_range(id: u64) -> Option<usize> {
+  usize::try_from(id).ok().filter(|id| *id < CAPACITY)
+}
+
+fn key_out_of_range(req_id: u64, id: u64) -> Response {
+  Response::Error { req_id, code: ErrorCode::KeyOutOfRange, message: format!("key {id} is not below {CAPACITY}") }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use super::*;
+  use crate::packet::Request;
+
+  const ALICE: &[u8] = b"alice";
+
+  // the responses of a worker to `reqs`, sent one at a time on a connection that offered `features`
+  fn serve(features: Features, reqs: &[Request]) -> Vec<Response> {
+    future::block_on(async {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let server_addr = listener.local_addr().unwrap();
+      let store = Arc::new(Store::from_map(HashMap::from([(0, ALICE.to_vec())])));
+      let server = async {
+        let (stream, addr) = listener.accept().await.unwrap();
+        // runs until the client closes the connection
+        let _ = Worker::new(stream, addr, store, Arc::new(ServerStats::default()), ServerOptions::default()).run().await;
+      };
+      let client = async {
+        let mut stream = TcpStream::connect(server_addr).await.unwrap();
+        let mut decoder = FrameDecoder::new(FrameLimits::responses());
+        let hello = Request::Hello { req_id: 0, version: PROTOCOL_VERSION, features };
+        let mut responses = Vec::new();
+        for req in std::iter::once(&hello).chain(reqs) {
+          stream.write_all(&req.serialize()).await.unwrap();
+          let mut buf = [0; BUF_LEN];
+          while !decoder.has_frame().unwrap() {
+            let bytes_read = stream.read(&mut buf).await.unwrap();
+            assert!(bytes_read > 0, "connection closed after {} responses", responses.len());
+            decoder.push(&buf[..bytes_read]);
+          }
+          responses.push(decoder.next_response().unwrap().unwrap().into_owned());
+        }
+        assert!(matches!(responses[0], Response::HelloAck { .. }));
+        responses.split_off(1)
+      };
+      future::zip(server, client).await.1
+    })
+  }
+
+  fn error(res: &Response) -> (u64, ErrorCode) {
+    match res {
+      Response::Error { req_id, code, .. } => (*req_id, *code),
+      res => panic!("expected an error but got {res:?}"),
+    }
+  }
+
+  // the request sent after the refused ones, which the connection still has to answer
+  fn read_alice(req_id: u64) -> Request {
+    Request::LcRead { req_id, id: 0 }
+  }
+
+  fn assert_read_alice(res: &Response) {
+    assert!(matches!(res, Response::LcRead { username: Some(username), .. } if username == ALICE), "{res:?}");
+  }
+
+  #[test]
+  fn keys_out_of_range_are_refused() {
+    let reqs = [
+      Request::LcRead { req_id: 1, id: CAPACITY as u64 },
+      Request::LcWrite { req_id: 2, id: u64::MAX, username: ALICE.to_vec() },
+      Request::MultiGet { req_id: 3, ids: vec![0, CAPACITY as u64] },
+      read_alice(4),
+    ];
+    let res = serve(Features::BATCH_OPS, &reqs);
+    assert_eq!(error(&res[0]), (1, ErrorCode::KeyOutOfRange));
+    assert_eq!(error(&res[1]), (2, ErrorCode::KeyOutOfRange));
+    assert_eq!(error(&res[2]), (3, ErrorCode::KeyOutOfRange));
+    assert_read_alice(&res[3]);
+  }
+
+  #[test]
+  fn invalid_patterns_are_refused() {
+    let reqs = [
+      Request::RegexCount { req_id: 1, pattern: "(".to_string() },
+      Request::GlobCount { req_id: 2, pattern: "[a".to_string() },
+      read_alice(3),
+    ];
+    let res = serve(Features::NONE, &reqs);
+    assert_eq!(error(&res[0]), (1, ErrorCode::Malformed));
+    assert_eq!(error(&res[1]), (2, ErrorCode::Malformed));
+    assert_read_alice(&res[2]);
+  }
+
+  #[test]
+  fn requests_of_features_not_negotiated_are_refused() {
+    let reqs = [
+      Request::MultiGet { req_id: 1, ids: vec![0] },
+      Request::MultiPut { req_id: 2, entries: vec![(0, ALICE.to_vec())] },
+      Request::Cancel { req_id: 3 },
+      Request::Stats { req_id: 4 },
+      read_alice(5),
+    ];
+    let res = serve(Features::NONE, &reqs);
+    for (i, res) in res[..4].iter().enumerate() {
+      assert_eq!(error(res), (i as u64 + 1, ErrorCode::UnknownType));
+    }
+    assert_read_alice(&res[4]);
   }
 }
@@ -0,0 +1,140 @@
+use std::{fmt::{Display, Write}, io, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
+use async_executor::Executor;
+use smol::{Timer, future::FutureExt, io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+use crate::{AspenRsError, BUF_LEN, MAX_HTTP_REQUEST_LEN, METRICS_ACCEPT_BACKOFF_MILLIS, METRICS_BUCKETS_MICROS, METRICS_READ_TIMEOUT_MILLIS, NetworkError, packet::{MessageType, Priority, RequestType, now_micros}, store::Store};
+use super::ServerStats;
+
+// Latencies of one request type in µs, bucketed by METRICS_BUCKETS_MICROS
+#[derive(Default)]
+pub(super) struct LatencyBuckets {
+  counts: [AtomicU64; METRICS_BUCKETS_MICROS.len() + 1], // the last one past every bound
+  sum_micros: AtomicU64,
+}
+
+impl LatencyBuckets {
+  pub(super) fn record(&self, micros: u64) {
+    let bucket = METRICS_BUCKETS_MICROS.iter().position(|bound| micros <= *bound).unwrap_or(METRICS_BUCKETS_MICROS.len());
+    self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+  }
+
+  // appends the series of a Prometheus histogram, cumulative as it expects, for the type given by `labels`
+  fn render(&self, metrics: &mut String, name: &str, labels: &str) {
+    let mut count = 0;
+    for (bound, bucket) in METRICS_BUCKETS_MICROS.iter().zip(&self.counts) {
+      count += bucket.load(Ordering::Relaxed);
+      let _ = writeln!(metrics, "{name}_bucket{{{labels},le=\"{}\"}} {count}", *bound as f64 / 1e6);
+    }
+    count += self.counts[METRICS_BUCKETS_MICROS.len()].load(Ordering::Relaxed);
+    let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(metrics, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}\n{name}_sum{{{labels}}} {sum}\n{name}_count{{{labels}}} {count}");
+  }
+
+  fn is_empty(&self) -> bool {
+    self.counts.iter().all(|bucket| bucket.load(Ordering::Relaxed) == 0)
+  }
+}
+
+// answers GET /metrics on `listener` until the server stops, each scrape in a task of its own so a slow one
+// holds up no other
+pub(super) async fn serve(listener: TcpListener, stats: Arc<ServerStats>, store: Arc<Store>, ex: Arc<Executor<'static>>) {
+  loop {
+    let stream = match listener.accept().await {
+      Ok((stream, _)) => stream,
+      // such as running out of file descriptors, which another attempt right away would not fix
+      Err(e) => {
+        eprintln!("metrics accept failed: {e}");
+        Timer::after(Duration::from_millis(METRICS_ACCEPT_BACKOFF_MILLIS)).await;
+        continue;
+      },
+    };
+    let (stats, store) = (stats.clone(), store.clone());
+    ex.spawn(async move {
+      if let Err(e) = respond(stream, &stats, &store).await {
+        eprintln!("metrics request failed: {e}");
+      }
+    }).detach();
+  }
+}
+
+async fn respond(mut stream: TcpStream, stats: &ServerStats, store: &Store) -> Result<(), AspenRsError> {
+  // only the request line matters, the headers are read so closing the connection does not reset it
+  let mut request = Vec::new();
+  let mut buf = [0u8; BUF_LEN];
+  while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_HTTP_REQUEST_LEN {
+    let timeout = async {
+      Timer::after(Duration::from_millis(METRICS_READ_TIMEOUT_MILLIS)).await;
+      Err(io::Error::from(io::ErrorKind::TimedOut))
+    };
+    let bytes_read = stream.read(&mut buf).or(timeout).await.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))?;
+    if bytes_read == 0 {
+      return Ok(());
+    }
+    request.extend_from_slice(&buf[0..bytes_read]);
+  }
+  let request_line = String::from_utf8_lossy(request.split(|byte| *byte == b'\r').next().unwrap_or_default()).into_owned();
+  let (status, body) = match request_line.split(' ').take(2).collect::<Vec<_>>()[..] {
+    ["GET", "/metrics"] => ("200 OK", render(stats, store).await),
+    ["GET", _] => ("404 Not Found", "only /metrics is served\n".to_string()),
+    _ => ("405 Method Not Allowed", "only GET is served\n".to_string()),
+  };
+  let response = format!("HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+  stream.write_all(response.as_bytes()).await.map_err(|e| AspenRsError::NetworkError(NetworkError::from(e)))
+}
+
+// every metric in the Prometheus text format, written into one buffer
+async fn render(stats: &ServerStats, store: &Store) -> String {
+  let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+  let mut metrics = String::new();
+  write_metric(&mut metrics, "aspenrs_uptime_seconds", "gauge", "Time since the server started.", now_micros().saturating_sub(stats.started_micros) as f64 / 1e6);
+  write_metric(&mut metrics, "aspenrs_executor_threads", "gauge", "Threads running the executor.", stats.threads);
+  write_metric(&mut metrics, "aspenrs_executor_busy_seconds_total", "counter", "Time the executor threads spent polling connections.", load(&stats.busy_nanos) as f64 / 1e9);
+  write_metric(&mut metrics, "aspenrs_connections_accepted_total", "counter", "Connections accepted.", load(&stats.accepted_connections));
+  write_metric(&mut metrics, "aspenrs_connections_open", "gauge", "Connections currently open.", load(&stats.open_connections));
+  write_metric(&mut metrics, "aspenrs_connections_rejected_total", "counter", "Connections closed for a frame that could not be framed, trusted or inflated.",
+    load(&stats.rejected_connections));
+  write_metric(&mut metrics, "aspenrs_requests_running", "gauge", "Requests being executed.", load(&stats.running_requests));
+  write_metric(&mut metrics, "aspenrs_scans_running", "gauge", "Requests being executed that scan the store.", load(&stats.running_scans));
+  write_metric(&mut metrics, "aspenrs_queued_bytes", "gauge", "Bytes received and not yet served.", load(&stats.queued_bytes));
+  write_metric(&mut metrics, "aspenrs_requests_expired_total", "counter", "Requests dropped with their deadline passed.", load(&stats.expired_requests));
+  write_metric(&mut metrics, "aspenrs_requests_cancelled_total", "counter", "Requests answered with Cancelled.", load(&stats.cancelled_requests));
+  write_metric(&mut metrics, "aspenrs_requests_outranked_total", "counter", "Requests that waited for requests of a higher priority before executing.",
+    load(&stats.outranked_requests));
+
+  metrics.push_str("# HELP aspenrs_requests_total Requests received.\n# TYPE aspenrs_requests_total counter\n");
+  for kind in RequestType::iterator() {
+    let _ = writeln!(metrics, "aspenrs_requests_total{{type=\"{kind:?}\"}} {}", load(&stats.requests[kind.value() as usize]));
+  }
+  metrics.push_str("# HELP aspenrs_requests_tagged_total Tagged requests received.\n# TYPE aspenrs_requests_tagged_total counter\n");
+  for priority in Priority::iterator() {
+    let _ = writeln!(metrics, "aspenrs_requests_tagged_total{{priority=\"{priority:?}\"}} {}", load(&stats.tagged_requests[priority.value() as usize]));
+  }
+
+  // types that were never executed would only add empty series
+  for (name, help, histograms) in [
+    ("aspenrs_request_queueing_seconds", "Time from a request's last bytes being read to it being served.", &stats.queueing),
+    ("aspenrs_request_execution_seconds", "Time from a request being served to it being answered.", &stats.execution),
+  ] {
+    let _ = writeln!(metrics, "# HELP {name} {help}\n# TYPE {name} histogram");
+    for kind in RequestType::iterator().filter(|kind| !histograms[kind.value() as usize].is_empty()) {
+      histograms[kind.value() as usize].render(&mut metrics, name, &format!("type=\"{kind:?}\""));
+    }
+  }
+
+  write_metric(&mut metrics, "aspenrs_store_keys", "gauge", "Keys in the store.", store.key_count().await);
+  let waits = &store.lock_waits;
+  metrics.push_str("# HELP aspenrs_store_lock_acquisitions_total Acquisitions of the store's lock by requests.\n# TYPE aspenrs_store_lock_acquisitions_total counter\n");
+  let _ = writeln!(metrics, "aspenrs_store_lock_acquisitions_total{{mode=\"read\"}} {}\naspenrs_store_lock_acquisitions_total{{mode=\"write\"}} {}",
+    load(&waits.reads), load(&waits.writes));
+  metrics.push_str("# HELP aspenrs_store_lock_wait_seconds_total Time requests spent waiting for the store's lock.\n# TYPE aspenrs_store_lock_wait_seconds_total counter\n");
+  let _ = writeln!(metrics, "aspenrs_store_lock_wait_seconds_total{{mode=\"read\"}} {}\naspenrs_store_lock_wait_seconds_total{{mode=\"write\"}} {}",
+    load(&waits.read_wait_nanos) as f64 / 1e9, load(&waits.write_wait_nanos) as f64 / 1e9);
+  metrics
+}
+
+// a metric with a single series, appended to `metrics`
+fn write_metric(metrics: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+  let _ = writeln!(metrics, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+}
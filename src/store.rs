@@ -1,51 +1,314 @@
-use std::{collections::HashMap, fs::File};
-use smol::{future::yield_now, lock::RwLock};
+use std::{collections::{HashMap, hash_map::Entry}, fs::File, pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::Poll, time::Instant};
+use event_listener::{Event, EventListener};
+use futures_lite::future;
+use memchr::memmem;
+use regex::bytes::Regex;
+use smol::{future::yield_now, lock::{RwLock, RwLockReadGuard, RwLockWriteGuard}};
 
-use crate::{CAPACITY, YIELD_FREQ};
+use crate::{CAPACITY, MAX_OUTRANKED_WAIT, YIELD_FREQ, packet::Priority};
 
+// Values are opaque bytes, only the searches treat them as text
 pub struct Store {
-  pub store: RwLock<HashMap<usize, String>>
+  pub store: RwLock<HashMap<usize, Vec<u8>>>,
+  pub lock_waits: LockWaits,
+  pub admission: Admission,
+}
+
+// Acquisitions of the store's lock by the tasks, and the time they spent waiting for it
+#[derive(Default)]
+pub struct LockWaits {
+  pub reads: AtomicU64,
+  pub read_wait_nanos: AtomicU64,
+  pub writes: AtomicU64,
+  pub write_wait_nanos: AtomicU64,
+}
+
+// Orders the tasks of every connection by priority: a task starts only once no task of a higher priority
+// is waiting to start or running, and a running scan stops at its next yield until that is so again. Higher
+// priorities get the executor threads and the lock first. So that a steady stream of them cannot starve the
+// lower ones, a task goes on anyway once MAX_OUTRANKED_WAIT others finished while it waited
+#[derive(Default)]
+pub struct Admission {
+  active: [AtomicU64; 3], // admitted or waiting to be, by priority value
+  finished: AtomicU64, // tasks done so far, which waiting tasks count off
+  released: Event, // a task finished, which may let lower priorities go on
+}
+
+// Holds a task's place until it is done
+pub struct Admitted<'a> {
+  admission: &'a Admission,
+  priority: Priority,
+}
+
+impl Admission {
+  // resolves once a task of `priority` may start, and whether it had to wait for that
+  pub async fn admit(&self, priority: Priority) -> (Admitted<'_>, bool) {
+    self.active[priority.value() as usize].fetch_add(1, Ordering::SeqCst);
+    let admitted = Admitted { admission: self, priority };
+    let waited = self.outranked(priority);
+    self.ready(priority).await;
+    (admitted, waited)
+  }
+
+  // resolves once no task of a higher priority than `priority` is active, or enough tasks finished meanwhile
+  async fn ready(&self, priority: Priority) {
+    let mut listener: Option<EventListener> = None;
+    let since = self.finished.load(Ordering::SeqCst);
+    future::poll_fn(|cx| {
+      loop {
+        if !self.outranked(priority) || self.finished.load(Ordering::SeqCst) - since >= MAX_OUTRANKED_WAIT {
+          return Poll::Ready(());
+        }
+        match &mut listener {
+          // checked again after listening, so a release in between is not missed
+          None => listener = Some(self.released.listen()),
+          Some(released) => match Pin::new(released).poll(cx) {
+            Poll::Ready(()) => listener = None,
+            Poll::Pending => return Poll::Pending,
+          },
+        }
+      }
+    }).await
+  }
+
+  fn outranked(&self, priority: Priority) -> bool {
+    Priority::iterator()
+      .filter(|other| *other > priority)
+      .any(|other| self.active[other.value() as usize].load(Ordering::SeqCst) > 0)
+  }
+}
+
+impl Drop for Admitted<'_> {
+  // every release counts towards the waiting tasks' bound, not only the last one of its priority
+  fn drop(&mut self) {
+    self.admission.active[self.priority.value() as usize].fetch_sub(1, Ordering::SeqCst);
+    self.admission.finished.fetch_add(1, Ordering::SeqCst);
+    self.admission.released.notify(usize::MAX);
+  }
 }
 
 impl Store {
+  pub fn from_map(map: HashMap<usize, Vec<u8>>) -> Self {
+    Store { store: RwLock::new(map), lock_waits: LockWaits::default(), admission: Admission::default() }
+  }
+
   pub fn new() -> (Self, usize) {
     let file = File::open("bench/usernames.txt").unwrap();
     let mut rdr = csv::Reader::from_reader(file);
-    let mut map: HashMap<usize, String> = HashMap::with_capacity(CAPACITY);
-    for (i, result) in rdr.records().flatten().enumerate() {
-      map.insert(i, result.iter().collect());
+    let mut map: HashMap<usize, Vec<u8>> = HashMap::with_capacity(CAPACITY);
+    for (i, result) in rdr.byte_records().flatten().enumerate() {
+      map.insert(i, result.as_slice().to_vec());
     }
     let len = map.len();
     
-    (Store {
-      store: RwLock::new(map)
-    }, len)
+    (Store::from_map(map), len)
   }
 
-  pub async fn lc_read_task(&self, key: usize) -> Option<String> {
-    self.store.read().await.get(&key).cloned()
+  // number of stored keys, taking the lock without counting it as a task's
+  pub async fn key_count(&self) -> usize {
+    self.store.read().await.len()
   }
 
-  pub async fn lc_write_task(&self, key: usize, value: String) -> Option<String> {
-    self.store.write().await.insert(key, value)
+  // the lock as the tasks take it, read or write, with the wait counted in lock_waits
+  async fn read(&self) -> RwLockReadGuard<'_, HashMap<usize, Vec<u8>>> {
+    let start = Instant::now();
+    let guard = self.store.read().await;
+    self.lock_waits.reads.fetch_add(1, Ordering::Relaxed);
+    self.lock_waits.read_wait_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    guard
   }
 
-  pub async fn be_task(&self, substring: String) -> usize {
+  async fn write(&self) -> RwLockWriteGuard<'_, HashMap<usize, Vec<u8>>> {
+    let start = Instant::now();
+    let guard = self.store.write().await;
+    self.lock_waits.writes.fetch_add(1, Ordering::Relaxed);
+    self.lock_waits.write_wait_nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    guard
+  }
+
+  pub async fn lc_read_task(&self, key: usize) -> Option<Vec<u8>> {
+    self.read().await.get(&key).cloned()
+  }
+
+  pub async fn lc_write_task(&self, key: usize, value: Vec<u8>) -> Option<Vec<u8>> {
+    self.write().await.insert(key, value)
+  }
+
+  pub async fn lc_delete_task(&self, key: usize) -> Option<Vec<u8>> {
+    self.write().await.remove(&key)
+  }
+
+  // returns the value already stored, in which case nothing is written
+  pub async fn lc_insert_if_absent_task(&self, key: usize, value: Vec<u8>) -> Option<Vec<u8>> {
+    match self.write().await.entry(key) {
+      Entry::Occupied(entry) => Some(entry.get().clone()),
+      Entry::Vacant(entry) => {
+        entry.insert(value);
+        None
+      },
+    }
+  }
+
+  // swaps in `value` only if the stored value equals `expected`, returning whether it did and the value found
+  pub async fn lc_compare_and_swap_task(&self, key: usize, expected: &[u8], value: Vec<u8>) -> (bool, Option<Vec<u8>>) {
+    let mut store = self.write().await;
+    match store.get_mut(&key) {
+      Some(current) if *current == expected => (true, Some(std::mem::replace(current, value))),
+      current => (false, current.cloned()),
+    }
+  }
+
+  // every key is read under the same lock acquisition
+  pub async fn multi_get_task(&self, keys: &[usize]) -> Vec<Option<Vec<u8>>> {
+    let store = self.read().await;
+    keys.iter().map(|key| store.get(key).cloned()).collect()
+  }
+
+  // entries are applied in order under the same lock acquisition, returning the replaced values
+  pub async fn multi_put_task(&self, entries: Vec<(usize, Vec<u8>)>) -> Vec<Option<Vec<u8>>> {
+    let mut store = self.write().await;
+    entries.into_iter().map(|(key, value)| store.insert(key, value)).collect()
+  }
+
+  // values of the present keys in start..end, stopping after `limit` of them with the key to resume from
+  pub async fn range_scan_task(&self, start: usize, end: usize, limit: usize, priority: Priority) -> (Vec<(usize, Vec<u8>)>, Option<usize>) {
+    let mut entries = Vec::new();
+    let mut key = start;
+    while key < end {
+      if entries.len() >= limit {
+        return (entries, Some(key));
+      }
+      key = self.range_scan_chunk(key, end, limit, priority, &mut entries).await;
+    }
+    (entries, None)
+  }
+
+  // scans the keys from `key` until `entries` holds `limit` values or a lock's worth of keys was read,
+  // returning the key to continue from. The lock is released in between so long scans do not starve writers
+  pub async fn range_scan_chunk(&self, mut key: usize, end: usize, limit: usize, priority: Priority, entries: &mut Vec<(usize, Vec<u8>)>) -> usize {
+    let chunk_end = end.min(key.saturating_add(1 << yield_shift(priority)));
+    let s = self.read().await;
+    while key < chunk_end && entries.len() < limit {
+      if let Some(username) = s.get(&key) {
+        entries.push((key, username.clone()));
+      }
+      key += 1;
+    }
+    drop(s);
+    self.scan_yield(priority).await;
+    key
+  }
+
+  // number of usernames containing `substring`, along with up to `limit` of the matching entries
+  pub async fn be_task(&self, substring: &[u8], limit: usize, priority: Priority) -> (usize, Vec<(usize, Vec<u8>)>) {
     let mut freq: usize = 0;
+    let mut matches = Vec::new();
+    let yield_mask = (1 << yield_shift(priority)) - 1;
 
-    let s = self.store.read().await;
+    let finder = memmem::Finder::new(substring);
+    let s = self.read().await;
+    let e = s.clone();
+    drop(s);
+
+    for (i, (key, username)) in e.into_iter().enumerate() {
+      if finder.find(&username).is_some() {
+        freq += 1;
+        if matches.len() < limit {
+          matches.push((key, username));
+        }
+      }
+
+      if (i & yield_mask) == 0 {
+        self.scan_yield(priority).await;
+      }
+    }
+    (freq, matches)
+  }
+
+  pub async fn prefix_count_task(&self, prefix: &[u8], priority: Priority) -> usize {
+    self.count_matches(|username| username.starts_with(prefix), priority).await
+  }
+
+  // also counts glob matches, a glob is matched as the regex it translates to
+  pub async fn regex_count_task(&self, regex: Regex, priority: Priority) -> usize {
+    self.count_matches(|username| regex.is_match(username), priority).await
+  }
+
+  // hands the thread over between values, holding no lock, and stays off it while higher priorities run
+  async fn scan_yield(&self, priority: Priority) {
+    yield_now().await;
+    self.admission.ready(priority).await;
+  }
+
+  async fn count_matches(&self, matches: impl Fn(&[u8]) -> bool, priority: Priority) -> usize {
+    let mut freq: usize = 0;
+    let yield_mask = (1 << yield_shift(priority)) - 1;
+
+    let s = self.read().await;
     let e = s.clone();
     drop(s);
 
     for (i, username) in e.values().enumerate(){
-      if username.contains(&substring) {
+      if matches(username) {
         freq += 1;
       }
 
-      if (i & ((1 << YIELD_FREQ) - 1)) == 0 {
-        yield_now().await;
+      if (i & yield_mask) == 0 {
+        self.scan_yield(priority).await;
       }
     }
     freq
   }
-}
\ No newline at end of file
+}
+
+// scans yield every 2^n values: a High one keeps its executor thread longer, a Low one hands it over sooner
+fn yield_shift(priority: Priority) -> usize {
+  match priority {
+    Priority::Low => YIELD_FREQ - 2,
+    Priority::Normal => YIELD_FREQ,
+    Priority::High => YIELD_FREQ + 2,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lower_priorities_wait_for_higher_ones() {
+    let admission = Admission::default();
+    future::block_on(async {
+      let high = admission.admit(Priority::High).await.0;
+      let mut normal = Box::pin(admission.admit(Priority::Normal));
+      assert!(future::poll_once(&mut normal).await.is_none());
+      // a High task arriving meanwhile is not held back by the waiting Normal one
+      let (other_high, outranked) = admission.admit(Priority::High).await;
+      assert!(!outranked);
+      assert!(future::poll_once(&mut normal).await.is_none());
+      drop(high);
+      drop(other_high);
+      let (_normal, outranked) = future::poll_once(&mut normal).await.expect("no High task is left");
+      assert!(outranked);
+    });
+  }
+
+  #[test]
+  fn a_steady_stream_of_higher_priorities_does_not_starve_lower_ones() {
+    let admission = Admission::default();
+    future::block_on(async {
+      let mut high = admission.admit(Priority::High).await.0;
+      let mut low = Box::pin(admission.admit(Priority::Low));
+      // the next High task always arrives before the last one finishes, so one is active throughout
+      for _ in 1..MAX_OUTRANKED_WAIT {
+        assert!(future::poll_once(&mut low).await.is_none());
+        let next = admission.admit(Priority::High).await.0;
+        drop(std::mem::replace(&mut high, next));
+      }
+      assert!(future::poll_once(&mut low).await.is_none());
+      let next = admission.admit(Priority::High).await.0;
+      drop(std::mem::replace(&mut high, next));
+      let (_low, outranked) = future::poll_once(&mut low).await.expect("the wait is bounded");
+      assert!(outranked);
+    });
+  }
+}